@@ -0,0 +1,275 @@
+use crate::identity::PublicKey;
+use base64::{Engine as _, engine::general_purpose::STANDARD};
+use chrono::{DateTime, SubsecRound, Utc};
+use serde::{Deserialize, Serialize};
+use zeroize::Zeroize;
+
+/// Chat payload carried inside an encrypted Mles message.
+///
+/// Messages are encoded as tagged JSON objects, e.g.
+/// `{"type":"text","sender":"alice","timestamp":"2025-01-01T12:00:00Z","body":"hi"}`.
+/// The decoder also accepts the legacy `"<timestamp> <uid>: <text>"` lines and
+/// `{"uid": ...}` join objects sent by earlier clients.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ChatMessage {
+    /// Chat line written by a user
+    Text {
+        sender: String,
+        timestamp: DateTime<Utc>,
+        body: String,
+    },
+    /// User joined the channel, announcing their identity key if they have one
+    Join {
+        sender: String,
+        timestamp: DateTime<Utc>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        public_key: Option<String>,
+    },
+    /// User left the channel
+    Leave {
+        sender: String,
+        timestamp: DateTime<Utc>,
+    },
+    /// Notice without a user sender
+    System {
+        timestamp: DateTime<Utc>,
+        body: String,
+    },
+}
+
+/// Join object sent by earlier clients
+#[derive(Deserialize)]
+struct LegacyJoin {
+    uid: String,
+}
+
+impl ChatMessage {
+    /// Creates a text message from `sender` timestamped now
+    pub fn text(sender: &str, body: &str) -> Self {
+        ChatMessage::Text {
+            sender: sender.to_string(),
+            timestamp: now(),
+            body: body.to_string(),
+        }
+    }
+
+    /// Creates a join notification for `sender` timestamped now
+    pub fn join(sender: &str) -> Self {
+        ChatMessage::Join {
+            sender: sender.to_string(),
+            timestamp: now(),
+            public_key: None,
+        }
+    }
+
+    /// Creates a join notification for `sender` announcing their identity key
+    pub fn join_with_key(sender: &str, public_key: &PublicKey) -> Self {
+        ChatMessage::Join {
+            sender: sender.to_string(),
+            timestamp: now(),
+            public_key: Some(STANDARD.encode(public_key)),
+        }
+    }
+
+    /// Creates a leave notification for `sender` timestamped now
+    pub fn leave(sender: &str) -> Self {
+        ChatMessage::Leave {
+            sender: sender.to_string(),
+            timestamp: now(),
+        }
+    }
+
+    /// Creates a system notice timestamped now
+    pub fn system(body: &str) -> Self {
+        ChatMessage::System {
+            timestamp: now(),
+            body: body.to_string(),
+        }
+    }
+
+    /// Returns the user the message is from, if any
+    pub fn sender(&self) -> Option<&str> {
+        match self {
+            ChatMessage::Text { sender, .. }
+            | ChatMessage::Join { sender, .. }
+            | ChatMessage::Leave { sender, .. } => Some(sender),
+            ChatMessage::System { .. } => None,
+        }
+    }
+
+    /// Returns the time the message was created
+    pub fn timestamp(&self) -> DateTime<Utc> {
+        match self {
+            ChatMessage::Text { timestamp, .. }
+            | ChatMessage::Join { timestamp, .. }
+            | ChatMessage::Leave { timestamp, .. }
+            | ChatMessage::System { timestamp, .. } => *timestamp,
+        }
+    }
+
+    /// Returns the identity key announced in a join message, if any
+    pub fn public_key(&self) -> Option<PublicKey> {
+        let ChatMessage::Join {
+            public_key: Some(public_key),
+            ..
+        } = self
+        else {
+            return None;
+        };
+        STANDARD.decode(public_key).ok()?.try_into().ok()
+    }
+
+    /// Encodes the message into the plaintext that gets encrypted
+    pub fn encode(&self) -> String {
+        serde_json::to_string(self).expect("ChatMessage serialization cannot fail")
+    }
+
+    /// Encodes the message in the format of earlier clients, which only know
+    /// `"<timestamp> <uid>: <text>"` lines and `{"uid": ...}` joins. Returns
+    /// None for messages they cannot show.
+    pub fn encode_legacy(&self) -> Option<String> {
+        match self {
+            ChatMessage::Text {
+                sender,
+                timestamp,
+                body,
+            } => Some(format!(
+                "{} {}: {}",
+                timestamp.format("%Y-%m-%dT%H:%M:%SZ"),
+                sender,
+                body
+            )),
+            ChatMessage::Join { sender, .. } => {
+                Some(serde_json::json!({ "uid": sender }).to_string())
+            }
+            ChatMessage::Leave { .. } | ChatMessage::System { .. } => None,
+        }
+    }
+
+    /// Decodes a decrypted plaintext, returning None if it is not a chat message
+    pub fn decode(plaintext: &str) -> Option<Self> {
+        if plaintext.starts_with('{') {
+            if let Ok(message) = serde_json::from_str::<ChatMessage>(plaintext) {
+                return Some(message);
+            }
+            // Earlier clients announce themselves with a bare uid object
+            return serde_json::from_str::<LegacyJoin>(plaintext)
+                .ok()
+                .map(|join| ChatMessage::Join {
+                    sender: join.uid,
+                    timestamp: now(),
+                    public_key: None,
+                });
+        }
+
+        // Legacy "<timestamp> <uid>: <text>" line
+        let (timestamp, rest) = plaintext.split_once(' ')?;
+        let timestamp = DateTime::parse_from_rfc3339(timestamp).ok()?.to_utc();
+        let (sender, body) = rest.split_once(':')?;
+        Some(ChatMessage::Text {
+            sender: sender.to_string(),
+            timestamp,
+            body: body.strip_prefix(' ').unwrap_or(body).to_string(),
+        })
+    }
+}
+
+/// Overwrites the text of the message, e.g. before dropping chat history
+impl Zeroize for ChatMessage {
+    fn zeroize(&mut self) {
+        match self {
+            ChatMessage::Text { sender, body, .. } => {
+                sender.zeroize();
+                body.zeroize();
+            }
+            ChatMessage::Join {
+                sender, public_key, ..
+            } => {
+                sender.zeroize();
+                public_key.zeroize();
+            }
+            ChatMessage::Leave { sender, .. } => sender.zeroize(),
+            ChatMessage::System { body, .. } => body.zeroize(),
+        }
+    }
+}
+
+fn now() -> DateTime<Utc> {
+    Utc::now().trunc_subsecs(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ts(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().to_utc()
+    }
+
+    #[test]
+    fn test_text_wire_format() {
+        let message = ChatMessage::Text {
+            sender: "alice".to_string(),
+            timestamp: ts("2025-01-01T12:00:00Z"),
+            body: "hello".to_string(),
+        };
+        assert_eq!(
+            message.encode(),
+            r#"{"type":"text","sender":"alice","timestamp":"2025-01-01T12:00:00Z","body":"hello"}"#
+        );
+    }
+
+    #[test]
+    fn test_roundtrip_preserves_colons_and_spaces() {
+        let message = ChatMessage::Text {
+            sender: "ops:bot".to_string(),
+            timestamp: ts("2025-01-01T12:00:00Z"),
+            body: "  indented: text".to_string(),
+        };
+        assert_eq!(ChatMessage::decode(&message.encode()), Some(message));
+
+        let join = ChatMessage::join("ops:bot");
+        assert!(!join.encode().contains("public_key"));
+        assert_eq!(ChatMessage::decode(&join.encode()), Some(join));
+
+        let join = ChatMessage::join_with_key("ops:bot", &[7u8; 32]);
+        let decoded = ChatMessage::decode(&join.encode()).unwrap();
+        assert_eq!(decoded.public_key(), Some([7u8; 32]));
+    }
+
+    #[test]
+    fn test_decode_legacy_text() {
+        assert_eq!(
+            ChatMessage::decode("2025-01-01T12:00:00Z alice: hello there"),
+            Some(ChatMessage::Text {
+                sender: "alice".to_string(),
+                timestamp: ts("2025-01-01T12:00:00Z"),
+                body: "hello there".to_string(),
+            })
+        );
+        assert_eq!(ChatMessage::decode("not a chat line"), None);
+    }
+
+    #[test]
+    fn test_decode_legacy_join() {
+        let message = ChatMessage::decode(r#"{"uid":"bob","channel":"ops"}"#).unwrap();
+        assert!(matches!(message, ChatMessage::Join { ref sender, .. } if sender == "bob"));
+    }
+
+    #[test]
+    fn test_encode_legacy() {
+        let message = ChatMessage::Text {
+            sender: "alice".to_string(),
+            timestamp: ts("2025-01-01T12:00:00Z"),
+            body: "hello: there".to_string(),
+        };
+        let legacy = message.encode_legacy().unwrap();
+        assert_eq!(legacy, "2025-01-01T12:00:00Z alice: hello: there");
+        assert_eq!(ChatMessage::decode(&legacy), Some(message));
+
+        let join = ChatMessage::join("bob").encode_legacy().unwrap();
+        assert_eq!(join, r#"{"uid":"bob"}"#);
+        assert!(ChatMessage::leave("bob").encode_legacy().is_none());
+    }
+}
@@ -0,0 +1,900 @@
+use crate::chat::ChatMessage;
+use crate::dupdet::MessageTracker;
+use crate::envelope::ContentType;
+use crate::error::{CryptoError, Error, Result};
+use crate::handshake::AuthFrame;
+use crate::identity::{Identity, PublicKey};
+use crate::kdf::ChannelKey;
+use crate::keyring::{Keyring, RekeyAnnouncement};
+use crate::keyshare::{KeyGrant, KeyRequest};
+use crate::message;
+use crate::padding::Padding;
+use crate::ratchet::{ChainAnnouncement, Ratchet};
+use crate::replay::{ReplayError, ReplayGuard};
+use crate::transfer::{FileOffer, OutgoingFile};
+use crate::transport::{Frame, Transport, WsTransport};
+use futures_util::stream::{SplitSink, SplitStream};
+use futures_util::{SinkExt, Stream, StreamExt};
+use std::collections::HashMap;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use zeroize::Zeroizing;
+
+/// Connects to a Mles server over WebSocket and joins the channel named in `auth`.
+/// The returned transport is ready for sending and receiving channel messages.
+pub async fn connect_channel(server: &str, auth: &AuthFrame) -> Result<WsTransport> {
+    let mut transport = WsTransport::connect(server).await?;
+    join_channel(&mut transport, auth).await?;
+    Ok(transport)
+}
+
+/// Joins the channel named in `auth` on an already connected transport
+pub async fn join_channel<T: Transport>(transport: &mut T, auth: &AuthFrame) -> Result<()> {
+    transport
+        .send(Frame::Text(auth.to_json()))
+        .await
+        .map_err(|e| match e {
+            Error::Protocol(e) => Error::Handshake(e),
+            e => e,
+        })
+}
+
+/// Event yielded by [`MlesReceiver`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Incoming {
+    /// Accepted chat message, with the identity key that signed it if any
+    Message {
+        message: ChatMessage,
+        signer: Option<PublicKey>,
+    },
+    /// Accepted payload other than a chat message, see [`MlesSender::send_bytes`].
+    /// The sender is None for unbound legacy messages.
+    Payload {
+        sender: Option<String>,
+        content_type: ContentType,
+        payload: Vec<u8>,
+        signer: Option<PublicKey>,
+    },
+    /// Message dropped by the replay guard
+    Replayed { sender: String, reason: ReplayError },
+    /// Channel key changed to a new epoch announced by `sender`
+    Rekeyed { sender: String, epoch: u32 },
+    /// Newcomer asking for the channel key, see [`crate::keyshare`]
+    KeyRequest(KeyRequest),
+    /// Channel key sealed to a newcomer, see [`crate::keyshare`]
+    KeyGrant(KeyGrant),
+}
+
+/// End-to-end encrypted connection to a single Mles channel
+pub struct MlesClient<T: Transport = WsTransport> {
+    sender: MlesSender<T>,
+    receiver: MlesReceiver<T>,
+}
+
+impl MlesClient {
+    /// Connects to `server`, joins the channel with `auth` and uses
+    /// `encryption_key` (see [`message::derive_key`] and [`ChannelKey::derive`])
+    /// or the keys of a [`Keyring`] for all channel traffic
+    pub async fn connect(
+        server: &str,
+        auth: AuthFrame,
+        encryption_key: impl Into<Keyring>,
+    ) -> Result<Self> {
+        let transport = WsTransport::connect(server).await?;
+        Self::with_transport(transport, auth, encryption_key).await
+    }
+}
+
+impl<T: Transport> MlesClient<T> {
+    /// Joins the channel with `auth` over an already connected transport
+    pub async fn with_transport(
+        mut transport: T,
+        auth: AuthFrame,
+        encryption_key: impl Into<Keyring>,
+    ) -> Result<Self> {
+        let keyring: Keyring = encryption_key.into();
+        let id_key = keyring.current().duplicate();
+        let keyring = Arc::new(Mutex::new(keyring));
+        join_channel(&mut transport, &auth).await?;
+        let (write, read) = transport.split();
+        let tracker = Arc::new(Mutex::new(MessageTracker::new()));
+        let ratchet = Arc::new(Mutex::new(Ratchet::default()));
+
+        Ok(Self {
+            sender: MlesSender {
+                write,
+                name: auth.uid.clone(),
+                uid: auth.uid,
+                channel: auth.channel.clone(),
+                keyring: Arc::clone(&keyring),
+                identity: None,
+                padding: Padding::None,
+                legacy: false,
+                tracker: Arc::clone(&tracker),
+                ratchet: Arc::clone(&ratchet),
+            },
+            receiver: MlesReceiver {
+                read,
+                channel: auth.channel,
+                keyring,
+                id_key,
+                names: HashMap::new(),
+                tracker,
+                ratchet,
+                replay_guard: Some(ReplayGuard::default()),
+                error: None,
+            },
+        })
+    }
+
+    /// Returns the user ID this client authenticated with
+    pub fn uid(&self) -> &str {
+        &self.sender.uid
+    }
+
+    /// Returns the name chat messages are sent under
+    pub fn name(&self) -> &str {
+        &self.sender.name
+    }
+
+    /// Sends chat messages under `name` while the server and the message
+    /// envelopes only see the authenticated uid, which should be
+    /// [`message::pseudonym`] of `name` under the channel key
+    pub fn set_name(&mut self, name: &str) {
+        self.sender.name = name.to_string();
+    }
+
+    /// Replaces all channel keys with `key`, e.g. one opened from an
+    /// [`Incoming::KeyGrant`] after joining with a placeholder key
+    pub fn set_key(&mut self, key: ChannelKey) {
+        self.receiver.id_key = key.duplicate();
+        self.sender.keyring.lock().unwrap().replace(key);
+    }
+
+    /// Encrypts and sends a chat line, see [`MlesSender::send`]
+    pub async fn send(&mut self, text: &str) -> Result<Option<ChatMessage>> {
+        self.sender.send(text).await
+    }
+
+    /// Asks the channel for its key, see [`MlesSender::request_key`]
+    pub async fn request_key(&mut self, request: &KeyRequest) -> Result<()> {
+        self.sender.request_key(request).await
+    }
+
+    /// Waits for the next decrypted, non-duplicate message or replay report.
+    /// Returns None once the connection is closed.
+    pub async fn recv(&mut self) -> Option<Incoming> {
+        self.receiver.next().await
+    }
+
+    /// Replaces the replay guard, or disables replay checks with None.
+    /// A guard with the default window is installed on connect.
+    pub fn set_replay_guard(&mut self, replay_guard: Option<ReplayGuard>) {
+        self.receiver.replay_guard = replay_guard;
+    }
+
+    /// Signs outgoing messages with `identity`, or stops signing with None
+    pub fn set_identity(&mut self, identity: Option<Identity>) {
+        self.sender.identity = identity;
+    }
+
+    /// Pads outgoing messages with `padding` to hide their length.
+    /// Padded messages from other senders are always accepted.
+    pub fn set_padding(&mut self, padding: Padding) {
+        self.sender.padding = padding;
+    }
+
+    /// Sends chat lines and joins in the headerless format of earlier and
+    /// browser clients, see [`ChatMessage::encode_legacy`]. Legacy messages
+    /// are not signed, padded or bound to the sender.
+    pub fn set_legacy(&mut self, legacy: bool) {
+        self.sender.legacy = legacy;
+    }
+
+    /// Sends chat lines in sender-key ratchet mode, see [`crate::ratchet`].
+    /// Messages in ratchet mode from other senders are always accepted.
+    pub fn enable_ratchet(&mut self) {
+        self.sender.ratchet.lock().unwrap().enable();
+    }
+
+    /// Splits the client into halves that can be used from separate tasks.
+    /// Both halves share the same duplicate tracker, keyring and ratchet state.
+    pub fn split(self) -> (MlesSender<T>, MlesReceiver<T>) {
+        (self.sender, self.receiver)
+    }
+}
+
+/// Sending half of an [`MlesClient`]
+pub struct MlesSender<T: Transport = WsTransport> {
+    write: SplitSink<T, Frame>,
+    uid: String,
+    name: String,
+    channel: String,
+    keyring: Arc<Mutex<Keyring>>,
+    identity: Option<Identity>,
+    padding: Padding,
+    legacy: bool,
+    tracker: Arc<Mutex<MessageTracker>>,
+    ratchet: Arc<Mutex<Ratchet>>,
+}
+
+impl<T: Transport> MlesSender<T> {
+    /// Sends `text` as a chat line from this client's user.
+    /// Returns the message that was sent, or None if it was suppressed as a duplicate.
+    pub async fn send(&mut self, text: &str) -> Result<Option<ChatMessage>> {
+        let message = ChatMessage::text(&self.name, text);
+        Ok(self.send_message(&message).await?.then_some(message))
+    }
+
+    /// Encodes, encrypts and sends a chat message, signed if an identity is set.
+    /// Returns false if the message was suppressed as a duplicate, or cannot
+    /// be expressed in legacy mode.
+    pub async fn send_message(&mut self, message: &ChatMessage) -> Result<bool> {
+        if self.legacy {
+            return self.send_legacy(message).await;
+        }
+        let plaintext = Zeroizing::new(message.encode());
+        if self
+            .tracker
+            .lock()
+            .unwrap()
+            .is_duplicate(plaintext.as_bytes())
+        {
+            return Ok(false);
+        }
+
+        // Only chat lines use the sender chain, so that joins stay readable
+        // to participants that have not seen our chain announcement yet
+        let next = match message {
+            ChatMessage::Text { .. } => self.ratchet.lock().unwrap().next_send(&self.uid),
+            _ => None,
+        };
+        let encrypted = match next {
+            Some((announcement, position, message_key)) => {
+                if let Some(announcement) = announcement {
+                    let plaintext = Zeroizing::new(announcement.encode());
+                    let encrypted = self.encrypt(ContentType::Chat, plaintext.as_bytes())?;
+                    self.write.send(Frame::Binary(encrypted.into())).await?;
+                }
+                message::encrypt_chain_message(
+                    &message_key,
+                    &self.channel,
+                    &self.uid,
+                    position,
+                    self.identity.as_ref(),
+                    self.padding,
+                    &plaintext,
+                )?
+            }
+            None => self.encrypt(ContentType::Chat, plaintext.as_bytes())?,
+        };
+        self.write.send(Frame::Binary(encrypted.into())).await?;
+        Ok(true)
+    }
+
+    /// Sends `message` in the format of earlier clients, see
+    /// [`MlesClient::set_legacy`]
+    async fn send_legacy(&mut self, message: &ChatMessage) -> Result<bool> {
+        let Some(plaintext) = message.encode_legacy().map(Zeroizing::new) else {
+            return Ok(false);
+        };
+        if self
+            .tracker
+            .lock()
+            .unwrap()
+            .is_duplicate(plaintext.as_bytes())
+        {
+            return Ok(false);
+        }
+        let encrypted = message::encrypt_message_legacy(
+            self.keyring.lock().unwrap().current().as_bytes(),
+            &plaintext,
+        )?;
+        self.write.send(Frame::Binary(encrypted.into())).await?;
+        Ok(true)
+    }
+
+    /// Encrypts and sends a payload other than a chat message, e.g. CBOR
+    /// sensor data, signed if an identity is set. Unlike chat messages,
+    /// repeated payloads are sent every time.
+    pub async fn send_bytes(&mut self, content_type: ContentType, payload: &[u8]) -> Result<()> {
+        let encrypted = self.encrypt(content_type, payload)?;
+        self.write.send(Frame::Binary(encrypted.into())).await
+    }
+
+    /// Sends the file at `path` as an offer followed by its chunks, see
+    /// [`crate::transfer`]. Returns the offer that was sent.
+    pub async fn send_file(&mut self, path: &Path) -> Result<FileOffer> {
+        let mut file = OutgoingFile::open(path, &self.uid)?;
+        self.send_bytes(ContentType::FileOffer, file.offer().encode().as_bytes())
+            .await?;
+        while let Some(chunk) = file.next_chunk()? {
+            self.send_bytes(ContentType::FileChunk, &chunk).await?;
+        }
+        Ok(file.offer().clone())
+    }
+
+    /// Replaces the channel key with `key` for everyone in the channel by
+    /// announcing it under the current key. Returns the new key epoch, or
+    /// [`CryptoError::EpochsExhausted`] once the last epoch is reached.
+    pub async fn rekey(&mut self, key: ChannelKey) -> Result<u32> {
+        let next_epoch = self.keyring.lock().unwrap().next_epoch();
+        let key = key.with_epoch(next_epoch.ok_or(CryptoError::EpochsExhausted)?);
+        let epoch = key.epoch();
+        let plaintext = Zeroizing::new(RekeyAnnouncement::new(&self.uid, &key).encode());
+        let encrypted = self.encrypt(ContentType::Chat, plaintext.as_bytes())?;
+        self.write.send(Frame::Binary(encrypted.into())).await?;
+        self.keyring.lock().unwrap().install(key);
+        Ok(epoch)
+    }
+
+    /// Asks the channel for its key, see [`crate::keyshare`]
+    pub async fn request_key(&mut self, request: &KeyRequest) -> Result<()> {
+        self.write.send(Frame::Text(request.encode())).await
+    }
+
+    /// Sends the current channel key sealed to the newcomer behind
+    /// `request`. Returns false if the request is malformed.
+    pub async fn grant_key(&mut self, request: &KeyRequest) -> Result<bool> {
+        let grant = KeyGrant::seal(
+            &self.name,
+            &self.channel,
+            request,
+            self.keyring.lock().unwrap().current(),
+        );
+        let Some(grant) = grant else {
+            return Ok(false);
+        };
+        self.write.send(Frame::Text(grant.encode())).await?;
+        Ok(true)
+    }
+
+    /// Returns the fingerprint of the current channel key, which newcomers
+    /// check a [`KeyGrant`] against
+    pub fn key_fingerprint(&self) -> String {
+        message::key_fingerprint(self.keyring.lock().unwrap().current())
+    }
+
+    /// Encrypts `payload` under the current channel key, signed if an
+    /// identity is set
+    fn encrypt(&self, content_type: ContentType, payload: &[u8]) -> Result<Vec<u8>> {
+        message::encrypt_bytes(
+            self.keyring.lock().unwrap().current(),
+            &self.channel,
+            &self.uid,
+            self.identity.as_ref(),
+            self.padding,
+            content_type,
+            payload,
+        )
+    }
+
+    /// Returns the user ID messages are sent as
+    pub fn uid(&self) -> &str {
+        &self.uid
+    }
+
+    /// Returns the name chat messages are sent under
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the public key outgoing messages are signed with, if any
+    pub fn public_key(&self) -> Option<PublicKey> {
+        self.identity.as_ref().map(Identity::public_key)
+    }
+
+    /// Closes the connection to the server
+    pub async fn close(&mut self) -> Result<()> {
+        self.write.close().await
+    }
+}
+
+/// Receiving half of an [`MlesClient`], yielding decrypted chat messages.
+/// Messages that fail to decrypt or decode, claim a sender other than the one
+/// bound in their envelope, or were already seen, are skipped. Messages
+/// rejected by the replay guard are reported as [`Incoming::Replayed`].
+pub struct MlesReceiver<T: Transport = WsTransport> {
+    read: SplitStream<T>,
+    channel: String,
+    keyring: Arc<Mutex<Keyring>>,
+    /// Key the pseudonyms of senders are checked with, see [`message::pseudonym`].
+    /// It stays the key the client started with, as pseudonyms do not
+    /// follow rekeys.
+    id_key: ChannelKey,
+    /// Names behind the pseudonyms seen so far, for reporting senders
+    names: HashMap<String, String>,
+    tracker: Arc<Mutex<MessageTracker>>,
+    ratchet: Arc<Mutex<Ratchet>>,
+    replay_guard: Option<ReplayGuard>,
+    error: Option<Error>,
+}
+
+impl<T: Transport> MlesReceiver<T> {
+    /// Returns the connection error that ended the stream, if any
+    pub fn take_error(&mut self) -> Option<Error> {
+        self.error.take()
+    }
+
+    /// Returns the name behind `sender` if it is a known pseudonym
+    fn name_of(&self, sender: &str) -> String {
+        self.names
+            .get(sender)
+            .cloned()
+            .unwrap_or_else(|| sender.to_string())
+    }
+}
+
+impl<T: Transport> Stream for MlesReceiver<T> {
+    type Item = Incoming;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            match self.read.poll_next_unpin(cx) {
+                Poll::Ready(Some(Ok(Frame::Binary(data)))) => {
+                    let this = &mut *self;
+                    this.keyring.lock().unwrap().expire();
+                    this.ratchet.lock().unwrap().expire();
+                    let Some(decrypted) = message::decrypt_bytes_with(
+                        &this.channel,
+                        &data,
+                        |envelope| match &envelope.chain {
+                            Some(position) => this
+                                .ratchet
+                                .lock()
+                                .unwrap()
+                                .message_key(envelope.sender?, position),
+                            None => this.keyring.lock().unwrap().key_for(envelope),
+                        },
+                    ) else {
+                        continue;
+                    };
+                    // Delete the used chain key
+                    if let (Some(sender), Some(position)) = (&decrypted.sender, &decrypted.chain) {
+                        this.ratchet.lock().unwrap().commit(sender, position);
+                    }
+                    // Exact duplicates, e.g. the same message arriving through
+                    // two proxies, are dropped silently. Payloads are told
+                    // apart by their nonce, as senders may repeat them.
+                    let seen = match decrypted.content_type {
+                        ContentType::Chat => &decrypted.plaintext,
+                        _ => &decrypted.nonce,
+                    };
+                    if self.tracker.lock().unwrap().is_duplicate(seen) {
+                        continue;
+                    }
+                    if decrypted.content_type != ContentType::Chat {
+                        let sender = decrypted.sender.clone().unwrap_or_default();
+                        if let Some(guard) = self.replay_guard.as_mut()
+                            && let Some(timestamp) = decrypted.timestamp
+                            && let Err(reason) = guard.check(&sender, timestamp, &decrypted.nonce)
+                        {
+                            let sender = self.name_of(&sender);
+                            return Poll::Ready(Some(Incoming::Replayed { sender, reason }));
+                        }
+                        return Poll::Ready(Some(Incoming::Payload {
+                            sender: decrypted.sender,
+                            content_type: decrypted.content_type,
+                            payload: decrypted.plaintext,
+                            signer: decrypted.signer,
+                        }));
+                    }
+                    let Some(decrypted) = decrypted.into_text() else {
+                        continue;
+                    };
+                    let plaintext = Zeroizing::new(decrypted.plaintext);
+                    // Key announcements travel under the channel key and only
+                    // for their own sender
+                    let announced_by = |sender: &str| {
+                        decrypted.chain.is_none() && decrypted.sender.as_deref() == Some(sender)
+                    };
+                    if let Some(announcement) = RekeyAnnouncement::decode(&plaintext) {
+                        if announced_by(&announcement.sender)
+                            && let Some(key) = announcement.key()
+                            && self.keyring.lock().unwrap().install(key)
+                        {
+                            return Poll::Ready(Some(Incoming::Rekeyed {
+                                sender: self.name_of(&announcement.sender),
+                                epoch: announcement.epoch,
+                            }));
+                        }
+                        continue;
+                    }
+                    if let Some(announcement) = ChainAnnouncement::decode(&plaintext) {
+                        if announced_by(&announcement.sender) {
+                            self.ratchet.lock().unwrap().install(&announcement);
+                        }
+                        continue;
+                    }
+                    let Some(message) = ChatMessage::decode(&plaintext) else {
+                        continue;
+                    };
+                    // The sender claimed inside the payload must match the
+                    // authenticated envelope sender, or be the name behind
+                    // its pseudonym
+                    if let Some(sender) = &decrypted.sender
+                        && message.sender().is_none_or(|claimed| {
+                            claimed != sender
+                                && message::pseudonym(&self.id_key, claimed) != *sender
+                        })
+                    {
+                        continue;
+                    }
+                    if let (Some(sender), Some(claimed)) = (&decrypted.sender, message.sender())
+                        && claimed != sender
+                    {
+                        self.names.insert(sender.clone(), claimed.to_string());
+                    }
+                    // Newcomers need our sender chain before they can read us
+                    if let ChatMessage::Join { .. } = message {
+                        self.ratchet.lock().unwrap().request_announcement();
+                    }
+                    // Anything repeating a nonce or outside the window is a replay
+                    let sender = decrypted
+                        .sender
+                        .as_deref()
+                        .or(message.sender())
+                        .unwrap_or_default()
+                        .to_string();
+                    let timestamp = message.timestamp();
+                    if let Some(guard) = self.replay_guard.as_mut()
+                        && let Err(reason) = guard.check(&sender, timestamp, &decrypted.nonce)
+                    {
+                        let sender = self.name_of(&sender);
+                        return Poll::Ready(Some(Incoming::Replayed { sender, reason }));
+                    }
+                    return Poll::Ready(Some(Incoming::Message {
+                        message,
+                        signer: decrypted.signer,
+                    }));
+                }
+                // Key requests and grants are the only plaintext traffic,
+                // as newcomers cannot read anything else yet
+                Poll::Ready(Some(Ok(Frame::Text(text)))) => {
+                    if let Some(request) = KeyRequest::decode(&text) {
+                        return Poll::Ready(Some(Incoming::KeyRequest(request)));
+                    }
+                    if let Some(grant) = KeyGrant::decode(&text) {
+                        return Poll::Ready(Some(Incoming::KeyGrant(grant)));
+                    }
+                }
+                Poll::Ready(Some(Err(e))) => {
+                    self.error = Some(e);
+                    return Poll::Ready(None);
+                }
+                // Connection closed
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keyshare::PendingRequest;
+    use crate::secret::SecretKey;
+    use crate::transport::MemoryTransport;
+
+    type TestClient = MlesClient<MemoryTransport>;
+
+    /// Connects alice and bob to each other, both with the channel key `key`
+    async fn pair(key: [u8; 32]) -> (TestClient, TestClient) {
+        pair_with("alice", SecretKey::from(key), SecretKey::from(key)).await
+    }
+
+    /// Connects alice, authenticated as `alice_uid`, and bob to each other
+    async fn pair_with(
+        alice_uid: &str,
+        alice_key: impl Into<Keyring>,
+        bob_key: impl Into<Keyring>,
+    ) -> (TestClient, TestClient) {
+        let (local, remote) = MemoryTransport::pair();
+        let alice = MlesClient::with_transport(
+            local,
+            AuthFrame::builder(alice_uid, "test").build(),
+            alice_key,
+        )
+        .await
+        .unwrap();
+        let bob =
+            MlesClient::with_transport(remote, AuthFrame::builder("bob", "test").build(), bob_key)
+                .await
+                .unwrap();
+        (alice, bob)
+    }
+
+    #[tokio::test]
+    async fn test_receive_over_memory_transport() {
+        let key = ChannelKey::from(SecretKey::from([7u8; 32]));
+        let (local, mut server) = MemoryTransport::pair();
+        let auth = AuthFrame::builder("alice", "test").build();
+        let mut client = MlesClient::with_transport(local, auth.clone(), key.duplicate())
+            .await
+            .unwrap();
+
+        // Server side sees the join frame first
+        assert_eq!(
+            server.next().await.unwrap().unwrap(),
+            Frame::Text(auth.to_json())
+        );
+
+        let message = ChatMessage::text("bob", "hello");
+        let encrypted =
+            message::encrypt_message(&key, "test", "bob", Padding::None, &message.encode())
+                .unwrap();
+        // Payload claiming a sender other than the envelope one
+        let spoofed = ChatMessage::text("carol", "hi");
+        let spoofed =
+            message::encrypt_message(&key, "test", "bob", Padding::None, &spoofed.encode())
+                .unwrap();
+        server.send(Frame::Binary(spoofed.into())).await.unwrap();
+        server
+            .send(Frame::Binary(encrypted.clone().into()))
+            .await
+            .unwrap();
+        // Duplicates and undecryptable frames are skipped
+        server.send(Frame::Binary(encrypted.into())).await.unwrap();
+        server
+            .send(Frame::Binary(vec![0u8; 40].into()))
+            .await
+            .unwrap();
+        server.close().await.unwrap();
+
+        assert_eq!(
+            client.recv().await,
+            Some(Incoming::Message {
+                message,
+                signer: None
+            })
+        );
+        assert_eq!(client.recv().await, None);
+    }
+
+    #[tokio::test]
+    async fn test_replayed_nonce_reported() {
+        let key = ChannelKey::from(SecretKey::from([7u8; 32]));
+        let (local, mut server) = MemoryTransport::pair();
+        let auth = AuthFrame::builder("alice", "test").build();
+        let (_sender, mut receiver) = MlesClient::with_transport(local, auth, key.duplicate())
+            .await
+            .unwrap()
+            .split();
+        server.next().await.unwrap().unwrap();
+
+        let message = ChatMessage::text("bob", "hello");
+        let encrypted =
+            message::encrypt_message(&key, "test", "bob", Padding::None, &message.encode())
+                .unwrap();
+        server
+            .send(Frame::Binary(encrypted.clone().into()))
+            .await
+            .unwrap();
+        assert_eq!(
+            receiver.next().await,
+            Some(Incoming::Message {
+                message,
+                signer: None
+            })
+        );
+
+        // Once the duplicate tracker has forgotten the message, the replay
+        // guard still catches the repeated nonce
+        receiver.tracker.lock().unwrap().clear();
+        server.send(Frame::Binary(encrypted.into())).await.unwrap();
+        assert_eq!(
+            receiver.next().await,
+            Some(Incoming::Replayed {
+                sender: "bob".to_string(),
+                reason: ReplayError::RepeatedNonce,
+            })
+        );
+
+        // Payloads other than chat messages are guarded just the same
+        let encrypted = message::encrypt_bytes(
+            &key,
+            "test",
+            "bob",
+            None,
+            Padding::None,
+            ContentType::Cbor,
+            &[0xa1],
+        )
+        .unwrap();
+        server
+            .send(Frame::Binary(encrypted.clone().into()))
+            .await
+            .unwrap();
+        assert!(matches!(
+            receiver.next().await,
+            Some(Incoming::Payload { .. })
+        ));
+        receiver.tracker.lock().unwrap().clear();
+        server.send(Frame::Binary(encrypted.into())).await.unwrap();
+        assert_eq!(
+            receiver.next().await,
+            Some(Incoming::Replayed {
+                sender: "bob".to_string(),
+                reason: ReplayError::RepeatedNonce,
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_signed_messages_carry_signer() {
+        let key = [7u8; 32];
+        let (mut alice, mut bob) = pair(key).await;
+        let identity = Identity::from_bytes(&[9u8; 32]);
+        let public_key = identity.public_key();
+        alice.set_identity(Some(identity));
+
+        let sent = alice.send("signed").await.unwrap().unwrap();
+        // Alice's join text frame is skipped by the receiver
+        assert_eq!(
+            bob.recv().await,
+            Some(Incoming::Message {
+                message: sent,
+                signer: Some(public_key)
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_binary_payload_between_clients() {
+        let key = [7u8; 32];
+        let (mut alice, mut bob) = pair(key).await;
+
+        // Repeated readings are sent and received every time
+        let blob = [0xa1, 0x00, 0xff];
+        for _ in 0..2 {
+            alice
+                .sender
+                .send_bytes(ContentType::Cbor, &blob)
+                .await
+                .unwrap();
+        }
+        for _ in 0..2 {
+            assert_eq!(
+                bob.recv().await,
+                Some(Incoming::Payload {
+                    sender: Some("alice".to_string()),
+                    content_type: ContentType::Cbor,
+                    payload: blob.to_vec(),
+                    signer: None
+                })
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pseudonymous_sender() {
+        let key = ChannelKey::from(SecretKey::from([7u8; 32]));
+        let pseudonym = message::pseudonym(&key, "alice");
+        let (mut alice, mut bob) = pair_with(&pseudonym, key, SecretKey::from([7u8; 32])).await;
+        alice.set_name("alice");
+
+        let sent = alice.send("hi").await.unwrap().unwrap();
+        assert_eq!(sent.sender(), Some("alice"));
+        // Names other than the one behind the pseudonym are rejected
+        alice.set_name("mallory");
+        alice.send("spoofed").await.unwrap();
+        alice.set_name("alice");
+        let after = alice.send("after").await.unwrap().unwrap();
+
+        for message in [sent, after] {
+            assert_eq!(
+                bob.recv().await,
+                Some(Incoming::Message {
+                    message,
+                    signer: None
+                })
+            );
+        }
+
+        // Notices name the sender rather than the pseudonym
+        alice
+            .sender
+            .rekey(ChannelKey::from(SecretKey::from([8u8; 32])))
+            .await
+            .unwrap();
+        assert_eq!(
+            bob.recv().await,
+            Some(Incoming::Rekeyed {
+                sender: "alice".to_string(),
+                epoch: 1
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_ratchet_messages_between_clients() {
+        let key = [7u8; 32];
+        let (mut alice, mut bob) = pair(key).await;
+        alice.enable_ratchet();
+
+        let first = alice.send("first").await.unwrap().unwrap();
+        let second = alice.send("second").await.unwrap().unwrap();
+        // The chain announcement is consumed by the receiver
+        for message in [first, second] {
+            assert_eq!(
+                bob.recv().await,
+                Some(Incoming::Message {
+                    message,
+                    signer: None
+                })
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rekey_between_clients() {
+        let key = [7u8; 32];
+        let (mut alice, mut bob) = pair(key).await;
+
+        let before = alice.send("before").await.unwrap().unwrap();
+        let epoch = alice
+            .sender
+            .rekey(ChannelKey::from(SecretKey::from([8u8; 32])))
+            .await
+            .unwrap();
+        assert_eq!(epoch, 1);
+        let after = alice.send("after").await.unwrap().unwrap();
+
+        assert_eq!(
+            bob.recv().await,
+            Some(Incoming::Message {
+                message: before,
+                signer: None
+            })
+        );
+        assert_eq!(
+            bob.recv().await,
+            Some(Incoming::Rekeyed {
+                sender: "alice".to_string(),
+                epoch: 1
+            })
+        );
+        assert_eq!(
+            bob.recv().await,
+            Some(Incoming::Message {
+                message: after,
+                signer: None
+            })
+        );
+        assert_eq!(bob.sender.keyring.lock().unwrap().current().epoch(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_key_granted_to_newcomer() {
+        let (mut alice, mut bob) = pair_with(
+            "alice",
+            ChannelKey::from(SecretKey::from([7u8; 32])).with_epoch(1),
+            SecretKey::from([0u8; 32]),
+        )
+        .await;
+
+        let pending = PendingRequest::new("bob");
+        bob.sender.request_key(&pending.request()).await.unwrap();
+        let Some(Incoming::KeyRequest(request)) = alice.recv().await else {
+            panic!("expected a key request");
+        };
+        assert!(alice.sender.grant_key(&request).await.unwrap());
+        let sent = alice.send("welcome").await.unwrap().unwrap();
+
+        let Some(Incoming::KeyGrant(grant)) = bob.recv().await else {
+            panic!("expected a key grant");
+        };
+        bob.set_key(pending.open("test", &grant).unwrap());
+        assert_eq!(
+            bob.recv().await,
+            Some(Incoming::Message {
+                message: sent,
+                signer: None
+            })
+        );
+        assert_eq!(bob.sender.keyring.lock().unwrap().current().epoch(), 1);
+    }
+}
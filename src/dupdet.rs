@@ -1,71 +1,292 @@
-use indexmap::IndexSet;
+//! Duplicate detection for messages arriving more than once, e.g. through
+//! two proxies.
+//!
+//! Messages are tracked by their SipHash under a random key drawn for each
+//! tracker. Nobody but the tracker and its snapshot file knows the key, so
+//! nobody else can craft distinct messages that hash alike to get legitimate
+//! ones dropped as duplicates, as with the fixed all-zero key.
+//!
+//! A message is remembered until its time to live has passed or the tracker
+//! is full, whichever comes first, so that busy bridges keep a useful window
+//! and quiet ones do not drop a legitimate repeat forever.
+//!
+//! Trackers can be saved to a snapshot file and restored from it, so that a
+//! restarted proxy does not forward the history replayed by the server again.
+//! A snapshot holds the hash key, the time it was saved and the tracked
+//! hashes with their ages, ending in a Blake2b checksum. As it holds the key,
+//! it is readable only by the current user:
+//!
+//! ```text
+//! "MLESDUP1" | key (16) | saved at (8) | count (4) | count * (hash (8) | age ms (8)) | checksum (16)
+//! ```
+
+use crate::fsutil;
+use blake2::{Blake2b512, Digest};
+use indexmap::IndexMap;
+use rand::{RngCore, rngs::OsRng};
 use siphasher::sip::SipHasher;
+use std::fs;
 use std::hash::Hasher;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Default maximum number of message hashes to track
+pub const DEFAULT_CAPACITY: usize = 40_000;
+
+/// Default time a message is remembered
+pub const DEFAULT_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Magic and version at the start of snapshot files
+const SNAPSHOT_MAGIC: &[u8; 8] = b"MLESDUP1";
+
+/// Length of the snapshot header up to the first entry
+const SNAPSHOT_HEADER_LEN: usize = 36;
+
+/// Length of a snapshot entry
+const SNAPSHOT_ENTRY_LEN: usize = 16;
+
+/// Length of the checksum ending snapshot files
+const SNAPSHOT_CHECKSUM_LEN: usize = 16;
+
+/// Monotonic time source of a [`MessageTracker`]
+pub trait Clock {
+    fn now(&self) -> Instant;
+}
+
+/// Clock reading [`Instant::now`]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MonotonicClock;
 
-/// Maximum number of message hashes to track
-const MAX_SEEN_MESSAGES: usize = 40_000;
+impl Clock for MonotonicClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
 
-/// Tracks message hashes to detect duplicates using a fixed-size FIFO buffer
-pub struct MessageTracker {
-    seen_hashes: IndexSet<u64>,
+/// Tracks message hashes to detect duplicates, oldest first, bounded by
+/// a time to live and a capacity
+pub struct MessageTracker<C: Clock = MonotonicClock> {
+    /// SipHash key, kept private to the tracker and its snapshot file
+    key: (u64, u64),
+    /// Hashes with the time they were first seen
+    seen_hashes: IndexMap<u64, Instant>,
+    ttl: Duration,
+    capacity: usize,
+    clock: C,
 }
 
 impl MessageTracker {
-    /// Creates a new MessageTracker with pre-allocated capacity
+    /// Creates a new MessageTracker with pre-allocated capacity and a fresh
+    /// hash key
     pub fn new() -> Self {
+        Self::with_clock(MonotonicClock)
+    }
+}
+
+impl<C: Clock> MessageTracker<C> {
+    /// Creates a new MessageTracker reading the time from `clock`
+    pub fn with_clock(clock: C) -> Self {
         Self {
-            seen_hashes: IndexSet::with_capacity(MAX_SEEN_MESSAGES),
+            key: (OsRng.next_u64(), OsRng.next_u64()),
+            seen_hashes: IndexMap::with_capacity(DEFAULT_CAPACITY),
+            ttl: DEFAULT_TTL,
+            capacity: DEFAULT_CAPACITY,
+            clock,
         }
     }
 
+    /// Sets how long a message is remembered, at least a second, as a zero
+    /// time to live would forget every message at once
+    pub fn set_ttl(&mut self, ttl: Duration) {
+        self.ttl = ttl.max(Duration::from_secs(1));
+    }
+
+    /// Sets the maximum number of messages remembered, at least one
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity.max(1);
+    }
+
     /// Checks if a message is a duplicate and adds it to the tracker if not
     /// Returns true if the message was already seen
-    pub fn is_duplicate(&mut self, message_hash: u64) -> bool {
+    pub fn is_duplicate(&mut self, message: &[u8]) -> bool {
+        let message_hash = self.hash(message);
+        let now = self.clock.now();
+        self.expire(now);
+
         // O(1) lookup for existing hash
-        if self.seen_hashes.contains(&message_hash) {
+        if self.seen_hashes.contains_key(&message_hash) {
             return true;
         }
 
         // Insert new hash
-        self.seen_hashes.insert(message_hash);
+        self.seen_hashes.insert(message_hash, now);
 
-        // Maintain fixed capacity by removing oldest (first inserted) hash
-        if self.seen_hashes.len() > MAX_SEEN_MESSAGES {
-            self.seen_hashes.shift_remove_index(0);
+        // Maintain the capacity by removing the oldest (first inserted) hashes
+        if self.seen_hashes.len() > self.capacity {
+            let excess = self.seen_hashes.len() - self.capacity;
+            self.seen_hashes.drain(..excess);
         }
 
         false
     }
 
     /// Returns the number of currently tracked message hashes
-    #[allow(dead_code)]
     pub fn tracked_count(&self) -> usize {
         self.seen_hashes.len()
     }
 
     /// Clears all tracked message hashes
-    #[allow(dead_code)]
     pub fn clear(&mut self) {
         self.seen_hashes.clear();
     }
+
+    /// Writes the tracked hashes, their ages and the hash key to `path`,
+    /// readable only by the current user. The file is replaced atomically.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        fsutil::write_private(path, &self.snapshot())
+    }
+
+    /// Encodes the tracker as saved by [`MessageTracker::save`], for writing
+    /// it later without holding on to the tracker
+    pub fn snapshot(&self) -> Vec<u8> {
+        self.encode(unix_time())
+    }
+
+    /// Restores the hashes saved to `path` with [`MessageTracker::save`],
+    /// aged by the time since, and returns how many are still within the
+    /// time to live. Fails on missing, truncated or corrupted files, leaving
+    /// the tracker unchanged.
+    pub fn load(&mut self, path: &Path) -> io::Result<usize> {
+        self.decode(&fs::read(path)?, unix_time())
+    }
+
+    /// Encodes a snapshot saved at Unix time `saved_at`
+    fn encode(&self, saved_at: u64) -> Vec<u8> {
+        let now = self.clock.now();
+        let mut bytes = Vec::with_capacity(
+            SNAPSHOT_HEADER_LEN
+                + self.seen_hashes.len() * SNAPSHOT_ENTRY_LEN
+                + SNAPSHOT_CHECKSUM_LEN,
+        );
+        bytes.extend_from_slice(SNAPSHOT_MAGIC);
+        bytes.extend_from_slice(&self.key.0.to_le_bytes());
+        bytes.extend_from_slice(&self.key.1.to_le_bytes());
+        bytes.extend_from_slice(&saved_at.to_le_bytes());
+        bytes.extend_from_slice(&(self.seen_hashes.len() as u32).to_le_bytes());
+        for (hash, seen) in &self.seen_hashes {
+            let age = now.saturating_duration_since(*seen).as_millis() as u64;
+            bytes.extend_from_slice(&hash.to_le_bytes());
+            bytes.extend_from_slice(&age.to_le_bytes());
+        }
+        let checksum = Blake2b512::digest(&bytes);
+        bytes.extend_from_slice(&checksum[..SNAPSHOT_CHECKSUM_LEN]);
+        bytes
+    }
+
+    /// Restores a snapshot read at Unix time `now`
+    fn decode(&mut self, bytes: &[u8], now: u64) -> io::Result<usize> {
+        let invalid = |reason| io::Error::new(io::ErrorKind::InvalidData, reason);
+        let (body, checksum) = bytes
+            .split_at_checked(bytes.len().saturating_sub(SNAPSHOT_CHECKSUM_LEN))
+            .filter(|(body, _)| body.len() >= SNAPSHOT_HEADER_LEN)
+            .ok_or_else(|| invalid("truncated snapshot"))?;
+        if Blake2b512::digest(body)[..SNAPSHOT_CHECKSUM_LEN] != *checksum {
+            return Err(invalid("corrupted snapshot"));
+        }
+        if body[..8] != *SNAPSHOT_MAGIC {
+            return Err(invalid("not a deduplication snapshot"));
+        }
+        let u64_at = |offset: usize| {
+            u64::from_le_bytes(body[offset..offset + 8].try_into().expect("8 bytes"))
+        };
+        let key = (u64_at(8), u64_at(16));
+        let downtime = Duration::from_secs(now.saturating_sub(u64_at(24)));
+        let count = u32::from_le_bytes(body[32..36].try_into().expect("4 bytes")) as usize;
+        let entries = &body[SNAPSHOT_HEADER_LEN..];
+        if entries.len() != count * SNAPSHOT_ENTRY_LEN {
+            return Err(invalid("truncated snapshot"));
+        }
+
+        let now = self.clock.now();
+        let mut seen_hashes = IndexMap::with_capacity(self.capacity.min(count));
+        for entry in entries.chunks_exact(SNAPSHOT_ENTRY_LEN) {
+            let hash = u64::from_le_bytes(entry[..8].try_into().expect("8 bytes"));
+            let age = u64::from_le_bytes(entry[8..].try_into().expect("8 bytes"));
+            let age = Duration::from_millis(age) + downtime;
+            if age < self.ttl
+                && let Some(seen) = now.checked_sub(age)
+            {
+                seen_hashes.insert(hash, seen);
+            }
+        }
+        if seen_hashes.len() > self.capacity {
+            let excess = seen_hashes.len() - self.capacity;
+            seen_hashes.drain(..excess);
+        }
+        self.key = key;
+        self.seen_hashes = seen_hashes;
+        Ok(self.seen_hashes.len())
+    }
+
+    /// Drops the hashes first seen a time to live or longer before `now`
+    fn expire(&mut self, now: Instant) {
+        let expired = self
+            .seen_hashes
+            .values()
+            .take_while(|seen| now.saturating_duration_since(**seen) >= self.ttl)
+            .count();
+        self.seen_hashes.drain(..expired);
+    }
+
+    /// Hashes a message with the key of this tracker
+    fn hash(&self, data: &[u8]) -> u64 {
+        let mut hasher = SipHasher::new_with_keys(self.key.0, self.key.1);
+        hasher.write(data);
+        hasher.finish()
+    }
 }
 
-/// Hashes a binary message using AHash for fast, high-quality hashing
-pub fn hash_binary_message(data: &[u8]) -> u64 {
-    let mut hasher = SipHasher::new();
-    hasher.write(data);
-    hasher.finish()
+impl Default for MessageTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Returns the current Unix time in seconds
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    /// Clock advanced by hand
+    #[derive(Clone)]
+    struct ManualClock(Rc<Cell<Instant>>);
+
+    impl ManualClock {
+        fn advance(&self, by: Duration) {
+            self.0.set(self.0.get() + by);
+        }
+    }
+
+    impl Clock for ManualClock {
+        fn now(&self) -> Instant {
+            self.0.get()
+        }
+    }
 
     #[test]
     fn test_duplicate_detection() {
         let mut tracker = MessageTracker::new();
-        let msg1 = hash_binary_message(b"test1");
-        let msg2 = hash_binary_message(b"test2");
+        let msg1 = b"test1";
+        let msg2 = b"test2";
 
         // First occurrence is not a duplicate
         assert!(!tracker.is_duplicate(msg1));
@@ -82,37 +303,46 @@ mod tests {
         let mut tracker = MessageTracker::new();
 
         // Fill beyond capacity
-        for i in 0..MAX_SEEN_MESSAGES + 10 {
-            tracker.is_duplicate(i as u64);
+        for i in 0..DEFAULT_CAPACITY + 10 {
+            tracker.is_duplicate(&i.to_le_bytes());
         }
 
         // Verify size is maintained
-        assert_eq!(tracker.tracked_count(), MAX_SEEN_MESSAGES);
+        assert_eq!(tracker.tracked_count(), DEFAULT_CAPACITY);
 
         // Verify oldest messages were removed (FIFO)
-        assert!(!tracker.is_duplicate((MAX_SEEN_MESSAGES + 10) as u64));
-        assert!(!tracker.seen_hashes.contains(&0));
-        assert!(!tracker.seen_hashes.contains(&1));
+        assert!(!tracker.is_duplicate(&(DEFAULT_CAPACITY + 10).to_le_bytes()));
+        assert!(
+            !tracker
+                .seen_hashes
+                .contains_key(&tracker.hash(&0usize.to_le_bytes()))
+        );
+        assert!(
+            !tracker
+                .seen_hashes
+                .contains_key(&tracker.hash(&1usize.to_le_bytes()))
+        );
     }
 
     #[test]
     fn test_hash_consistency() {
+        let tracker = MessageTracker::new();
         let data = b"test message";
-        let hash1 = hash_binary_message(data);
-        let hash2 = hash_binary_message(data);
+        let hash1 = tracker.hash(data);
+        let hash2 = tracker.hash(data);
 
         // Same input should produce same hash
         assert_eq!(hash1, hash2);
 
         // Different input should produce different hash
-        let hash3 = hash_binary_message(b"different message");
+        let hash3 = tracker.hash(b"different message");
         assert_ne!(hash1, hash3);
     }
 
     #[test]
     fn test_clear() {
         let mut tracker = MessageTracker::new();
-        let msg = hash_binary_message(b"test");
+        let msg = b"test";
 
         assert!(!tracker.is_duplicate(msg));
         assert!(tracker.is_duplicate(msg));
@@ -121,4 +351,119 @@ mod tests {
         assert_eq!(tracker.tracked_count(), 0);
         assert!(!tracker.is_duplicate(msg));
     }
+
+    #[test]
+    fn test_fixed_key_collision_is_not_duplicate() {
+        // Distinct messages with the same SipHash under the all-zero key,
+        // found with Pollard's rho
+        let first = 0xfe703d27e678f28au64.to_le_bytes();
+        let second = 0x7dc67c43f99c4572u64.to_le_bytes();
+        let fixed_key = |data: &[u8]| {
+            let mut hasher = SipHasher::new();
+            hasher.write(data);
+            hasher.finish()
+        };
+        assert_eq!(fixed_key(&first), fixed_key(&second));
+
+        let mut tracker = MessageTracker::new();
+        assert!(!tracker.is_duplicate(&first));
+        assert!(!tracker.is_duplicate(&second));
+        assert!(tracker.is_duplicate(&second));
+        assert_ne!(tracker.hash(&first), fixed_key(&first));
+        assert_ne!(tracker.key, MessageTracker::new().key);
+    }
+
+    #[test]
+    fn test_ttl_expiry() {
+        let clock = ManualClock(Rc::new(Cell::new(Instant::now())));
+        let mut tracker = MessageTracker::with_clock(clock.clone());
+        tracker.set_ttl(Duration::from_secs(60));
+
+        assert!(!tracker.is_duplicate(b"first"));
+        clock.advance(Duration::from_secs(30));
+        assert!(!tracker.is_duplicate(b"second"));
+        clock.advance(Duration::from_secs(29));
+        assert!(tracker.is_duplicate(b"first"));
+
+        // A repeat does not extend the time a message is remembered
+        clock.advance(Duration::from_secs(1));
+        assert!(!tracker.is_duplicate(b"first"));
+        assert!(tracker.is_duplicate(b"second"));
+        assert_eq!(tracker.tracked_count(), 2);
+
+        clock.advance(Duration::from_secs(60));
+        assert!(!tracker.is_duplicate(b"third"));
+        assert_eq!(tracker.tracked_count(), 1);
+    }
+
+    #[test]
+    fn test_capacity_setting() {
+        let mut tracker = MessageTracker::new();
+        tracker.set_capacity(2);
+        for message in [b"one", b"two", b"six"] {
+            assert!(!tracker.is_duplicate(message));
+        }
+        assert_eq!(tracker.tracked_count(), 2);
+        assert!(!tracker.is_duplicate(b"one"));
+        assert!(tracker.is_duplicate(b"six"));
+
+        // Zero settings would make every message new
+        tracker.set_capacity(0);
+        tracker.set_ttl(Duration::ZERO);
+        assert!(!tracker.is_duplicate(b"ten"));
+        assert!(tracker.is_duplicate(b"ten"));
+        assert_eq!(tracker.tracked_count(), 1);
+    }
+
+    #[test]
+    fn test_snapshot_roundtrip() {
+        let clock = ManualClock(Rc::new(Cell::new(Instant::now())));
+        let mut tracker = MessageTracker::with_clock(clock.clone());
+        tracker.set_ttl(Duration::from_secs(60));
+        tracker.is_duplicate(b"first");
+        clock.advance(Duration::from_secs(50));
+        tracker.is_duplicate(b"second");
+        let snapshot = tracker.encode(1000);
+
+        // Restored with the same key, aged by five seconds of downtime
+        let mut restored = MessageTracker::with_clock(clock.clone());
+        restored.set_ttl(Duration::from_secs(60));
+        assert_eq!(restored.decode(&snapshot, 1005).unwrap(), 2);
+        assert!(restored.is_duplicate(b"first"));
+        assert!(restored.is_duplicate(b"second"));
+        clock.advance(Duration::from_secs(5));
+        assert!(!restored.is_duplicate(b"first"));
+
+        // Entries past the time to live are dropped
+        let mut restored = MessageTracker::with_clock(clock.clone());
+        restored.set_ttl(Duration::from_secs(60));
+        assert_eq!(restored.decode(&snapshot, 1011).unwrap(), 1);
+
+        let path = std::env::temp_dir().join(format!("mles-dupdet-{}.state", std::process::id()));
+        // Files differing only in their extension are left alone
+        let sibling = path.with_extension("tmp");
+        fs::write(&sibling, b"other").unwrap();
+        tracker.save(&path).unwrap();
+        assert_eq!(fs::read(&sibling).unwrap(), b"other");
+        fs::remove_file(&sibling).unwrap();
+        let mut loaded = MessageTracker::with_clock(clock);
+        assert_eq!(loaded.load(&path).unwrap(), 2);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_corrupted_snapshot_ignored() {
+        let mut tracker = MessageTracker::new();
+        tracker.is_duplicate(b"kept");
+        let mut snapshot = MessageTracker::new().encode(1000);
+
+        assert!(tracker.decode(&snapshot[..20], 1000).is_err());
+        assert!(tracker.decode(&[], 1000).is_err());
+        let last = snapshot.len() - 1;
+        snapshot[last] ^= 1;
+        let error = tracker.decode(&snapshot, 1000).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(tracker.is_duplicate(b"kept"));
+        assert!(tracker.load(Path::new("/nonexistent/mles-dupdet")).is_err());
+    }
 }
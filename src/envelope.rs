@@ -0,0 +1,563 @@
+//! Self-describing wrapper around encrypted Mles payloads.
+//!
+//! Current messages start with a fixed header followed by the sender uid:
+//!
+//! ```text
+//! magic "MLE" (3) | version (1) | cipher id (1) | KDF id (1) | flags (1)
+//!     | KDF parameters | sender length (1) | sender uid | nonce (24) | ciphertext
+//! ```
+//!
+//! The KDF parameters are empty for the default derivation, see
+//! [`KdfParams::to_bytes`] for the others.
+//!
+//! Everything before the nonce, followed by the channel name, is bound into
+//! the AEAD as associated data (see [`Envelope::associated_data`]).
+//!
+//! With [`FLAG_EPOCH`] set, the sender uid is followed by the key epoch the
+//! message was encrypted under (see [`crate::keyring`]):
+//!
+//! ```text
+//! ... | sender uid | epoch (4, big endian) | ...
+//! ```
+//!
+//! With [`FLAG_RATCHET`] set, the sender uid or epoch is followed by the position in
+//! the sender's key chain the message was encrypted with:
+//!
+//! ```text
+//! ... | sender uid | chain id (16) | chain index (4, big endian) | nonce (24) | ...
+//! ```
+//!
+//! With [`FLAG_SIGNED`] set, the plaintext starts with the sender's Ed25519
+//! public key and a signature over the associated data and the message:
+//!
+//! ```text
+//! public key (32) | signature (64) | message
+//! ```
+//!
+//! With [`FLAG_TYPED`] set, the message starts with its [`ContentType`] and
+//! the time it was sent, which chat messages carry in their own encoding;
+//! untyped messages are chat messages:
+//!
+//! ```text
+//! content type (1) | timestamp (8, Unix seconds, big endian) | payload
+//! ```
+//!
+//! With [`FLAG_PADDED`] set, the whole plaintext is padded as described in
+//! [`crate::padding`].
+//!
+//! Version 1 messages lacked the sender field and associated data, so they
+//! could be replayed into any channel sharing the key; they are refused.
+//! Messages from earlier clients carry no header at all and are plain
+//! `nonce (24) | ciphertext`; they are decoded as [`VERSION_LEGACY`].
+
+use crate::kdf::KdfParams;
+
+/// Magic bytes that start every versioned envelope
+pub const MAGIC: [u8; 3] = *b"MLE";
+
+/// Version of headerless messages sent by earlier clients
+pub const VERSION_LEGACY: u8 = 0;
+
+/// First versioned envelope, without associated data. No longer accepted.
+pub const VERSION_UNBOUND: u8 = 1;
+
+/// Envelope version produced by this implementation
+pub const VERSION_CURRENT: u8 = 2;
+
+/// Length of the fixed part of the versioned header
+pub const HEADER_LEN: usize = 7;
+
+/// Length of the XChaCha20 nonce
+pub const NONCE_LEN: usize = 24;
+
+/// Header flag marking a plaintext signed with the sender's identity key
+pub const FLAG_SIGNED: u8 = 0x01;
+
+/// Header flag marking a message encrypted with a sender chain key
+pub const FLAG_RATCHET: u8 = 0x02;
+
+/// Header flag marking a message encrypted under a key epoch other than 0
+pub const FLAG_EPOCH: u8 = 0x04;
+
+/// Header flag marking a padded plaintext
+pub const FLAG_PADDED: u8 = 0x08;
+
+/// Header flag marking a message that starts with its content type
+pub const FLAG_TYPED: u8 = 0x10;
+
+/// Length of a sender chain id
+pub const CHAIN_ID_LEN: usize = 16;
+
+/// Maximum length in bytes of the sender uid
+pub const MAX_SENDER_LEN: usize = u8::MAX as usize;
+
+/// AEAD cipher used for the ciphertext
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum CipherId {
+    XChaCha20Poly1305 = 1,
+}
+
+impl CipherId {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            1 => Some(CipherId::XChaCha20Poly1305),
+            _ => None,
+        }
+    }
+}
+
+/// Key derivation function used to derive the channel key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum KdfId {
+    /// Scrypt with default parameters, salted with Blake2b(channel)
+    ScryptBlake2bSalt = 1,
+    /// Scrypt with explicit parameters and salt
+    Scrypt = 2,
+    /// Argon2id with explicit parameters and salt
+    Argon2id = 3,
+}
+
+impl KdfId {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            1 => Some(KdfId::ScryptBlake2bSalt),
+            2 => Some(KdfId::Scrypt),
+            3 => Some(KdfId::Argon2id),
+            _ => None,
+        }
+    }
+}
+
+/// Kind of payload carried inside the AEAD
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ContentType {
+    /// JSON chat message, see [`crate::chat::ChatMessage`]
+    #[default]
+    Chat = 0,
+    /// Opaque bytes
+    Binary = 1,
+    /// CBOR encoded data
+    Cbor = 2,
+    /// JSON other than chat messages
+    Json = 3,
+    /// File announcement, see [`crate::transfer::FileOffer`]
+    FileOffer = 4,
+    /// Encrypted file chunk, see [`crate::transfer`]
+    FileChunk = 5,
+}
+
+impl ContentType {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(ContentType::Chat),
+            1 => Some(ContentType::Binary),
+            2 => Some(ContentType::Cbor),
+            3 => Some(ContentType::Json),
+            4 => Some(ContentType::FileOffer),
+            5 => Some(ContentType::FileChunk),
+            _ => None,
+        }
+    }
+}
+
+/// Envelope header describing how the payload was encrypted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub version: u8,
+    pub cipher: CipherId,
+    pub kdf: KdfId,
+    pub flags: u8,
+}
+
+impl Header {
+    /// Header for messages produced by this implementation
+    pub fn current() -> Self {
+        Self {
+            version: VERSION_CURRENT,
+            cipher: CipherId::XChaCha20Poly1305,
+            kdf: KdfId::ScryptBlake2bSalt,
+            flags: 0,
+        }
+    }
+
+    /// Implied header of headerless messages from earlier clients
+    pub fn legacy() -> Self {
+        Self {
+            version: VERSION_LEGACY,
+            ..Self::current()
+        }
+    }
+
+    /// Returns true if the message carries no header on the wire
+    pub fn is_legacy(&self) -> bool {
+        self.version == VERSION_LEGACY
+    }
+
+    /// Returns true if the header carries a sender and associated data
+    pub fn is_bound(&self) -> bool {
+        self.version >= VERSION_CURRENT
+    }
+
+    /// Returns true if the plaintext carries a sender signature
+    pub fn is_signed(&self) -> bool {
+        self.flags & FLAG_SIGNED != 0
+    }
+
+    /// Returns true if the message was encrypted with a sender chain key
+    pub fn is_ratchet(&self) -> bool {
+        self.flags & FLAG_RATCHET != 0
+    }
+
+    /// Returns true if the message starts with its content type
+    pub fn is_typed(&self) -> bool {
+        self.flags & FLAG_TYPED != 0
+    }
+
+    /// Returns true if the plaintext is padded
+    pub fn is_padded(&self) -> bool {
+        self.flags & FLAG_PADDED != 0
+    }
+
+    /// Returns true if the header is followed by a key epoch
+    pub fn has_epoch(&self) -> bool {
+        self.flags & FLAG_EPOCH != 0
+    }
+
+    /// Serializes the fixed header, empty for legacy messages
+    pub fn to_bytes(&self) -> Vec<u8> {
+        if self.is_legacy() {
+            return Vec::new();
+        }
+        let mut bytes = Vec::with_capacity(HEADER_LEN);
+        bytes.extend_from_slice(&MAGIC);
+        bytes.push(self.version);
+        bytes.push(self.cipher as u8);
+        bytes.push(self.kdf as u8);
+        bytes.push(self.flags);
+        bytes
+    }
+
+    /// Parses a fixed header, returning None if `bytes` does not start
+    /// with one this implementation understands
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < HEADER_LEN || bytes[..MAGIC.len()] != MAGIC {
+            return None;
+        }
+        let version = bytes[3];
+        // Unbound envelopes would let a message be replayed into another channel
+        if version != VERSION_CURRENT {
+            return None;
+        }
+        let flags = bytes[6];
+        let kdf = KdfId::from_u8(bytes[5])?;
+        if flags & !(FLAG_SIGNED | FLAG_RATCHET | FLAG_EPOCH | FLAG_PADDED | FLAG_TYPED) != 0 {
+            return None;
+        }
+        Some(Self {
+            version,
+            cipher: CipherId::from_u8(bytes[4])?,
+            kdf,
+            flags,
+        })
+    }
+}
+
+/// Position of a message in its sender's key chain
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ChainPosition {
+    pub chain_id: [u8; CHAIN_ID_LEN],
+    pub index: u32,
+}
+
+/// Parsed envelope borrowing its fields from the wire bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Envelope<'a> {
+    pub header: Header,
+    /// Parameters of the KDF named in the header
+    pub kdf: KdfParams,
+    /// Sender uid, present in bound envelopes
+    pub sender: Option<&'a str>,
+    /// Key epoch, 0 unless [`FLAG_EPOCH`] is set
+    pub epoch: u32,
+    /// Chain position, present in ratchet envelopes
+    pub chain: Option<ChainPosition>,
+    pub nonce: &'a [u8],
+    pub ciphertext: &'a [u8],
+}
+
+impl<'a> Envelope<'a> {
+    /// Parses `bytes` as a versioned envelope
+    pub fn parse(bytes: &'a [u8]) -> Option<Self> {
+        let header = Header::parse(bytes)?;
+        let (kdf, mut body) = KdfParams::parse(header.kdf, &bytes[HEADER_LEN..])?;
+        let mut sender = None;
+        let mut epoch = 0;
+        let mut chain = None;
+        if header.is_bound() {
+            let (&len, rest) = body.split_first()?;
+            if rest.len() < len as usize {
+                return None;
+            }
+            let (name, rest) = rest.split_at(len as usize);
+            sender = Some(std::str::from_utf8(name).ok()?);
+            body = rest;
+        }
+        if header.has_epoch() {
+            let (value, rest) = body.split_first_chunk::<4>()?;
+            epoch = u32::from_be_bytes(*value);
+            body = rest;
+        }
+        if header.is_ratchet() {
+            let (chain_id, rest) = body.split_first_chunk::<CHAIN_ID_LEN>()?;
+            let (index, rest) = rest.split_first_chunk::<4>()?;
+            chain = Some(ChainPosition {
+                chain_id: *chain_id,
+                index: u32::from_be_bytes(*index),
+            });
+            body = rest;
+        }
+        Self::split_body(header, kdf, sender, epoch, chain, body)
+    }
+
+    /// Parses `bytes` as a headerless legacy message
+    pub fn parse_legacy(bytes: &'a [u8]) -> Option<Self> {
+        Self::split_body(
+            Header::legacy(),
+            KdfParams::ChannelSalt,
+            None,
+            0,
+            None,
+            bytes,
+        )
+    }
+
+    /// Returns the possible interpretations of `bytes`, most specific first.
+    ///
+    /// A legacy message starts with a random nonce that may happen to look
+    /// like a header, so the legacy interpretation is always offered too.
+    pub fn candidates(bytes: &'a [u8]) -> impl Iterator<Item = Self> {
+        Self::parse(bytes)
+            .into_iter()
+            .chain(Self::parse_legacy(bytes))
+    }
+
+    fn split_body(
+        header: Header,
+        kdf: KdfParams,
+        sender: Option<&'a str>,
+        epoch: u32,
+        chain: Option<ChainPosition>,
+        body: &'a [u8],
+    ) -> Option<Self> {
+        if body.len() < NONCE_LEN {
+            return None;
+        }
+        let (nonce, ciphertext) = body.split_at(NONCE_LEN);
+        Some(Self {
+            header,
+            kdf,
+            sender,
+            epoch,
+            chain,
+            nonce,
+            ciphertext,
+        })
+    }
+
+    /// Serializes everything preceding the nonce
+    pub fn prefix_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.header.to_bytes();
+        bytes.extend_from_slice(&self.kdf.to_bytes());
+        if self.header.is_bound() {
+            let sender = self.sender.unwrap_or_default();
+            bytes.push(sender.len() as u8);
+            bytes.extend_from_slice(sender.as_bytes());
+        }
+        if self.header.has_epoch() {
+            bytes.extend_from_slice(&self.epoch.to_be_bytes());
+        }
+        if let Some(chain) = &self.chain {
+            bytes.extend_from_slice(&chain.chain_id);
+            bytes.extend_from_slice(&chain.index.to_be_bytes());
+        }
+        bytes
+    }
+
+    /// Associated data authenticated by the AEAD: the header including version
+    /// and sender, followed by the channel name. Empty for unbound envelopes.
+    pub fn associated_data(&self, channel: &str) -> Vec<u8> {
+        if !self.header.is_bound() {
+            return Vec::new();
+        }
+        let mut aad = self.prefix_bytes();
+        aad.extend_from_slice(channel.as_bytes());
+        aad
+    }
+
+    /// Serializes the envelope for the wire
+    pub fn to_bytes(&self) -> Vec<u8> {
+        [&self.prefix_bytes(), self.nonce, self.ciphertext].concat()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_header_wire_bytes() {
+        assert_eq!(Header::current().to_bytes(), b"MLE\x02\x01\x01\x00");
+        assert!(Header::legacy().to_bytes().is_empty());
+    }
+
+    #[test]
+    fn test_envelope_roundtrip() {
+        let nonce = [9u8; NONCE_LEN];
+        let envelope = Envelope {
+            header: Header::current(),
+            kdf: KdfParams::ChannelSalt,
+            sender: Some("alice"),
+            epoch: 0,
+            chain: None,
+            nonce: &nonce,
+            ciphertext: b"ciphertext",
+        };
+        let bytes = envelope.to_bytes();
+        assert_eq!(bytes.len(), HEADER_LEN + 1 + 5 + NONCE_LEN + 10);
+        assert_eq!(Envelope::parse(&bytes), Some(envelope));
+        assert_eq!(
+            envelope.associated_data("ops"),
+            b"MLE\x02\x01\x01\x00\x05aliceops"
+        );
+    }
+
+    #[test]
+    fn test_ratchet_envelope_roundtrip() {
+        let nonce = [9u8; NONCE_LEN];
+        let envelope = Envelope {
+            header: Header {
+                flags: FLAG_RATCHET,
+                ..Header::current()
+            },
+            kdf: KdfParams::ChannelSalt,
+            sender: Some("alice"),
+            epoch: 0,
+            chain: Some(ChainPosition {
+                chain_id: [4u8; CHAIN_ID_LEN],
+                index: 258,
+            }),
+            nonce: &nonce,
+            ciphertext: b"ciphertext",
+        };
+        let bytes = envelope.to_bytes();
+        assert_eq!(&bytes[13 + CHAIN_ID_LEN..17 + CHAIN_ID_LEN], &[0, 0, 1, 2]);
+        assert_eq!(Envelope::parse(&bytes), Some(envelope));
+    }
+
+    #[test]
+    fn test_epoch_envelope_roundtrip() {
+        let nonce = [9u8; NONCE_LEN];
+        let envelope = Envelope {
+            header: Header {
+                flags: FLAG_EPOCH,
+                ..Header::current()
+            },
+            kdf: KdfParams::ChannelSalt,
+            sender: Some("alice"),
+            epoch: 3,
+            chain: None,
+            nonce: &nonce,
+            ciphertext: b"ciphertext",
+        };
+        let bytes = envelope.to_bytes();
+        assert_eq!(&bytes[13..17], &[0, 0, 0, 3]);
+        assert_eq!(Envelope::parse(&bytes), Some(envelope));
+    }
+
+    #[test]
+    fn test_kdf_params_roundtrip() {
+        let nonce = [9u8; NONCE_LEN];
+        let kdf = KdfParams::Argon2id {
+            m_cost: 65536,
+            t_cost: 3,
+            p_cost: 1,
+            salt: [5u8; 16],
+        };
+        let envelope = Envelope {
+            header: Header {
+                kdf: KdfId::Argon2id,
+                ..Header::current()
+            },
+            kdf,
+            sender: Some("alice"),
+            epoch: 0,
+            chain: None,
+            nonce: &nonce,
+            ciphertext: b"ciphertext",
+        };
+        let bytes = envelope.to_bytes();
+        assert_eq!(&bytes[HEADER_LEN..HEADER_LEN + 4], &65536u32.to_be_bytes());
+        assert_eq!(Envelope::parse(&bytes), Some(envelope));
+        assert!(
+            envelope
+                .associated_data("ops")
+                .starts_with(&bytes[..HEADER_LEN + 28])
+        );
+
+        // Only bound envelopes are accepted
+        let mut unbound = bytes.clone();
+        unbound[3] = VERSION_UNBOUND;
+        assert!(Envelope::parse(&unbound).is_none());
+    }
+
+    #[test]
+    fn test_legacy_fallback() {
+        let legacy = [1u8; NONCE_LEN + 16];
+        let candidates: Vec<_> = Envelope::candidates(&legacy).collect();
+        assert_eq!(candidates.len(), 1);
+        assert!(candidates[0].header.is_legacy());
+        assert!(candidates[0].associated_data("ops").is_empty());
+
+        // A versioned message is also offered as legacy in case the
+        // "header" was really the start of a random nonce
+        let mut versioned = Header::current().to_bytes();
+        versioned.push(0);
+        versioned.extend_from_slice(&legacy);
+        let candidates: Vec<_> = Envelope::candidates(&versioned).collect();
+        assert_eq!(candidates.len(), 2);
+        assert_eq!(candidates[0].header, Header::current());
+        assert!(candidates[1].header.is_legacy());
+    }
+
+    #[test]
+    fn test_unbound_version_rejected() {
+        let mut bytes = b"MLE\x01\x01\x01\x00".to_vec();
+        bytes.extend_from_slice(&[0u8; NONCE_LEN]);
+        assert!(Envelope::parse(&bytes).is_none());
+        // Only the headerless reading remains
+        let candidates: Vec<_> = Envelope::candidates(&bytes).collect();
+        assert_eq!(candidates.len(), 1);
+        assert!(candidates[0].header.is_legacy());
+    }
+
+    #[test]
+    fn test_unknown_header_values_rejected() {
+        let mut bytes = Header::current().to_bytes();
+        bytes.push(0);
+        bytes.extend_from_slice(&[0u8; NONCE_LEN]);
+        for (index, value) in [(3, 3u8), (4, 9), (5, 9), (6, 0x80)] {
+            let mut changed = bytes.clone();
+            changed[index] = value;
+            assert!(Envelope::parse(&changed).is_none());
+        }
+        assert!(Envelope::parse(&bytes).is_some());
+
+        bytes[6] = FLAG_SIGNED;
+        assert!(Envelope::parse(&bytes).unwrap().header.is_signed());
+        bytes[3] = VERSION_UNBOUND;
+        assert!(Envelope::parse(&bytes).is_none());
+    }
+}
@@ -0,0 +1,189 @@
+use std::error::Error as StdError;
+use std::fmt;
+use std::io;
+use tokio_tungstenite::tungstenite;
+
+/// Result type used throughout the crate
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Crate-wide error type.
+///
+/// Each variant maps to a distinct process exit code via [`Error::exit_code`]
+/// so that supervisors can tell failure classes apart.
+#[derive(Debug)]
+pub enum Error {
+    /// Could not establish a connection to a Mles server
+    Connect(Box<tungstenite::Error>),
+    /// Channel join was rejected
+    Handshake(Box<tungstenite::Error>),
+    /// Key derivation or encryption failed
+    Crypto(CryptoError),
+    /// MQTT broker setup or communication failed
+    Mqtt(MqttError),
+    /// Local terminal or file I/O failed
+    Io(io::Error),
+    /// An established server connection failed
+    Protocol(Box<tungstenite::Error>),
+    /// Server URL was invalid
+    Url(Box<tungstenite::Error>),
+    /// A proxy task panicked or was cancelled
+    Task(tokio::task::JoinError),
+}
+
+impl Error {
+    /// Returns the process exit code for this error class
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Connect(_) => 2,
+            Error::Handshake(_) => 3,
+            Error::Crypto(_) => 4,
+            Error::Mqtt(_) => 5,
+            Error::Io(_) => 6,
+            Error::Protocol(_) => 7,
+            Error::Url(_) => 8,
+            Error::Task(_) => 9,
+        }
+    }
+
+    pub(crate) fn connect(e: tungstenite::Error) -> Self {
+        Error::Connect(Box::new(e))
+    }
+
+    pub(crate) fn protocol(e: tungstenite::Error) -> Self {
+        Error::Protocol(Box::new(e))
+    }
+
+    pub(crate) fn url(e: tungstenite::Error) -> Self {
+        Error::Url(Box::new(e))
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Connect(e) => write!(f, "connection failed: {}", e),
+            Error::Handshake(e) => write!(f, "handshake failed: {}", e),
+            Error::Crypto(e) => write!(f, "crypto error: {}", e),
+            Error::Mqtt(e) => write!(f, "MQTT error: {}", e),
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+            Error::Protocol(e) => write!(f, "protocol error: {}", e),
+            Error::Url(e) => write!(f, "invalid server URL: {}", e),
+            Error::Task(e) => write!(f, "task failed: {}", e),
+        }
+    }
+}
+
+impl StdError for Error {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match self {
+            Error::Connect(e) | Error::Handshake(e) | Error::Protocol(e) | Error::Url(e) => {
+                Some(e.as_ref())
+            }
+            Error::Crypto(e) => Some(e),
+            Error::Mqtt(e) => Some(e),
+            Error::Io(e) => Some(e),
+            Error::Task(e) => Some(e),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<CryptoError> for Error {
+    fn from(e: CryptoError) -> Self {
+        Error::Crypto(e)
+    }
+}
+
+impl From<MqttError> for Error {
+    fn from(e: MqttError) -> Self {
+        Error::Mqtt(e)
+    }
+}
+
+/// Failures in key derivation and message encryption
+#[derive(Debug)]
+pub enum CryptoError {
+    /// Key derivation failed
+    KeyDerivation(scrypt::password_hash::Error),
+    /// AEAD encryption failed
+    Encryption,
+    /// Sender uid does not fit in the envelope
+    InvalidSender,
+    /// KDF cost parameters were rejected
+    InvalidKdfParams,
+    /// Keystore did not decrypt with the given passphrase
+    Keystore,
+    /// No key epoch is left for a rekey
+    EpochsExhausted,
+}
+
+impl fmt::Display for CryptoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CryptoError::KeyDerivation(e) => write!(f, "key derivation failed: {}", e),
+            CryptoError::Encryption => write!(f, "encryption failed"),
+            CryptoError::InvalidSender => write!(f, "sender uid is too long"),
+            CryptoError::InvalidKdfParams => write!(f, "invalid KDF parameters"),
+            CryptoError::EpochsExhausted => write!(f, "no key epochs left"),
+            CryptoError::Keystore => {
+                write!(
+                    f,
+                    "cannot unlock keystore: wrong passphrase or corrupted file"
+                )
+            }
+        }
+    }
+}
+
+impl StdError for CryptoError {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match self {
+            CryptoError::KeyDerivation(e) => Some(e),
+            CryptoError::Encryption
+            | CryptoError::InvalidSender
+            | CryptoError::InvalidKdfParams
+            | CryptoError::Keystore
+            | CryptoError::EpochsExhausted => None,
+        }
+    }
+}
+
+/// Failures while talking to an MQTT broker
+#[derive(Debug)]
+pub enum MqttError {
+    /// Broker URL could not be parsed
+    Url(url::ParseError),
+    /// Broker URL has no host
+    MissingHost,
+    /// Connecting to the broker failed
+    Connection(Box<rumqttc::ConnectionError>),
+    /// Subscribing or publishing failed
+    Client(rumqttc::ClientError),
+}
+
+impl fmt::Display for MqttError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MqttError::Url(e) => write!(f, "invalid broker URL: {}", e),
+            MqttError::MissingHost => write!(f, "no host in MQTT URL"),
+            MqttError::Connection(e) => write!(f, "failed to establish MQTT connection: {}", e),
+            MqttError::Client(e) => write!(f, "MQTT request failed: {}", e),
+        }
+    }
+}
+
+impl StdError for MqttError {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match self {
+            MqttError::Url(e) => Some(e),
+            MqttError::MissingHost => None,
+            MqttError::Connection(e) => Some(e.as_ref()),
+            MqttError::Client(e) => Some(e),
+        }
+    }
+}
@@ -0,0 +1,171 @@
+//! Long-term Ed25519 identities used to sign channel messages.
+//!
+//! An identity is stored locally as its raw 32-byte secret seed. The public
+//! key travels inside each signed message, see [`crate::envelope::FLAG_SIGNED`].
+
+use blake2::{Blake2b512, Digest};
+use ed25519_dalek::{Signature, Signer, SigningKey};
+use rand::rngs::OsRng;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use zeroize::Zeroizing;
+
+pub use ed25519_dalek::{PUBLIC_KEY_LENGTH, SECRET_KEY_LENGTH, SIGNATURE_LENGTH};
+
+/// Encoded Ed25519 public key
+pub type PublicKey = [u8; PUBLIC_KEY_LENGTH];
+
+/// Ed25519 key pair identifying a user across sessions
+pub struct Identity {
+    signing_key: SigningKey,
+}
+
+impl Identity {
+    /// Generates a new random identity
+    pub fn generate() -> Self {
+        Self {
+            signing_key: SigningKey::generate(&mut OsRng),
+        }
+    }
+
+    /// Creates an identity from its secret seed
+    pub fn from_bytes(seed: &[u8; SECRET_KEY_LENGTH]) -> Self {
+        Self {
+            signing_key: SigningKey::from_bytes(seed),
+        }
+    }
+
+    /// Default location of the identity file in the user's config directory
+    pub fn default_path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("mles-client").join("identity"))
+    }
+
+    /// Loads an identity saved with [`Identity::save`]
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let bytes = Zeroizing::new(fs::read(path)?);
+        let seed: &[u8; SECRET_KEY_LENGTH] = bytes[..].try_into().map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} is not an identity file", path.display()),
+            )
+        })?;
+        Ok(Self::from_bytes(seed))
+    }
+
+    /// Saves the secret seed to `path`, readable only by the current user
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        io::Write::write_all(&mut options.open(path)?, self.signing_key.as_bytes())
+    }
+
+    /// Loads the identity at `path`, generating and saving a new one if
+    /// the file does not exist yet
+    pub fn load_or_generate(path: &Path) -> io::Result<Self> {
+        match Self::load(path) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let identity = Self::generate();
+                identity.save(path)?;
+                Ok(identity)
+            }
+            result => result,
+        }
+    }
+
+    /// Returns the public half of the identity
+    pub fn public_key(&self) -> PublicKey {
+        self.signing_key.verifying_key().to_bytes()
+    }
+
+    /// Signs `data` with the identity key
+    pub fn sign(&self, data: &[u8]) -> Signature {
+        self.signing_key.sign(data)
+    }
+}
+
+/// Returns a short human-readable fingerprint of a public key
+pub fn fingerprint(public_key: &PublicKey) -> String {
+    let hash = Blake2b512::digest(public_key);
+    hash[..16]
+        .chunks(2)
+        .map(|pair| format!("{:02x}{:02x}", pair[0], pair[1]))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Returns a safety number for the pair of identities with fingerprints
+/// `ours` and `theirs`. Both users compute the same digits, which they can
+/// compare over another channel to rule out an interposed key.
+pub fn safety_number(ours: &str, theirs: &str) -> String {
+    let (first, second) = if ours <= theirs {
+        (ours, theirs)
+    } else {
+        (theirs, ours)
+    };
+    let hash = Blake2b512::new()
+        .chain_update(first)
+        .chain_update(second)
+        .finalize();
+    hash[..30]
+        .chunks(5)
+        .map(|chunk| {
+            let value = chunk.iter().fold(0u64, |acc, &b| (acc << 8) | b as u64);
+            format!("{:05}", value % 100_000)
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::{Verifier, VerifyingKey};
+
+    #[test]
+    fn test_save_and_load() {
+        let path = std::env::temp_dir().join(format!("mles-identity-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let identity = Identity::load_or_generate(&path).unwrap();
+        let loaded = Identity::load_or_generate(&path).unwrap();
+        assert_eq!(identity.public_key(), loaded.public_key());
+
+        let signature = loaded.sign(b"hello");
+        let public_key = VerifyingKey::from_bytes(&identity.public_key()).unwrap();
+        assert!(public_key.verify(b"hello", &signature).is_ok());
+
+        fs::write(&path, b"short").unwrap();
+        assert_eq!(
+            Identity::load(&path).err().map(|e| e.kind()),
+            Some(io::ErrorKind::InvalidData)
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_fingerprint_format() {
+        let identity = Identity::from_bytes(&[1u8; SECRET_KEY_LENGTH]);
+        let fingerprint = fingerprint(&identity.public_key());
+        assert_eq!(fingerprint.len(), 8 * 4 + 7);
+        assert_ne!(
+            fingerprint,
+            super::fingerprint(&Identity::from_bytes(&[2u8; SECRET_KEY_LENGTH]).public_key())
+        );
+    }
+
+    #[test]
+    fn test_safety_number_symmetric() {
+        let alice = fingerprint(&Identity::from_bytes(&[1u8; SECRET_KEY_LENGTH]).public_key());
+        let bob = fingerprint(&Identity::from_bytes(&[2u8; SECRET_KEY_LENGTH]).public_key());
+        let number = safety_number(&alice, &bob);
+        assert_eq!(number, safety_number(&bob, &alice));
+        assert_eq!(number.len(), 6 * 5 + 5);
+        assert!(number.split(' ').all(|group| group.parse::<u32>().is_ok()));
+    }
+}
@@ -0,0 +1,287 @@
+//! `mles://` invite URIs carrying what is needed to join a channel.
+//!
+//! ```text
+//! mles://<host>[:<port>]/<channel>?key=..&fp=..&mles_key=..&kdf=..&epoch=..&expires=..
+//!     &opaque=1&pseudonym=1&padding=..
+//! ```
+//!
+//! The server is reached over `wss://`, or `ws://` with `tls=0`. All
+//! parameters are optional: `key` is the shared passphrase, or `fp` the
+//! fingerprint of the channel key (see [`message::key_fingerprint`]) when
+//! the passphrase is shared another way. `kdf` and `epoch` are as given with
+//! `--kdf` and `--epoch`, and `expires` is a Unix time after which the
+//! invite is refused. `opaque` and `pseudonym` turn on `--opaque-channel`
+//! and `--pseudonym`, which all members need to find each other, and
+//! `padding` is the `--padding` the channel is used with.
+
+use crate::kdf::{ChannelKey, KdfParams};
+use crate::message;
+use crate::padding::Padding;
+use chrono::{DateTime, Utc};
+use percent_encoding::percent_decode_str;
+use std::fmt;
+use std::str::FromStr;
+use url::Url;
+use zeroize::Zeroize;
+
+/// URI scheme of invites
+pub const SCHEME: &str = "mles";
+
+/// Invite to a channel on a Mles server
+#[derive(Clone, PartialEq, Eq)]
+pub struct Invite {
+    /// Server host with optional port
+    address: String,
+    /// True for `wss://` servers
+    tls: bool,
+    pub channel: String,
+    /// Shared passphrase the channel key is derived from
+    pub passphrase: Option<String>,
+    /// Fingerprint of the channel key, for checking a passphrase shared
+    /// another way
+    pub fingerprint: Option<String>,
+    /// Authentication key, as otherwise passed in `MLES_KEY`
+    pub mles_key: Option<String>,
+    pub kdf: KdfParams,
+    pub epoch: u32,
+    pub expires: Option<DateTime<Utc>>,
+    /// Join under a keyed hash of the channel name
+    pub opaque_channel: bool,
+    /// Join under a keyed hash of the uid
+    pub pseudonym: bool,
+    pub padding: Padding,
+}
+
+impl Invite {
+    /// Creates an invite to `channel` on the server at the WebSocket URL
+    /// `server`. Fails for URLs an invite cannot express, e.g. with a path.
+    pub fn new(server: &str, channel: &str) -> Result<Self, String> {
+        let url = Url::parse(server).map_err(|e| format!("invalid server URL: {}", e))?;
+        let tls = match url.scheme() {
+            "wss" => true,
+            "ws" => false,
+            scheme => return Err(format!("unsupported server scheme '{}'", scheme)),
+        };
+        if !matches!(url.path(), "" | "/") || url.query().is_some() {
+            return Err("server URLs with a path or query cannot be put in invites".to_string());
+        }
+        let host = url.host_str().ok_or("server URL has no host")?;
+        Ok(Self {
+            address: match url.port() {
+                Some(port) => format!("{}:{}", host, port),
+                None => host.to_string(),
+            },
+            tls,
+            channel: channel.to_string(),
+            passphrase: None,
+            fingerprint: None,
+            mles_key: None,
+            kdf: KdfParams::ChannelSalt,
+            epoch: 0,
+            expires: None,
+            opaque_channel: false,
+            pseudonym: false,
+            padding: Padding::None,
+        })
+    }
+
+    /// Returns the WebSocket URL of the server
+    pub fn server(&self) -> String {
+        let scheme = if self.tls { "wss" } else { "ws" };
+        format!("{}://{}", scheme, self.address)
+    }
+
+    /// Returns true if the invite expired before `now`
+    pub fn is_expired_at(&self, now: DateTime<Utc>) -> bool {
+        self.expires.is_some_and(|expires| expires < now)
+    }
+
+    /// Returns false if the invite carries a fingerprint that `key` does
+    /// not match
+    pub fn matches(&self, key: &ChannelKey) -> bool {
+        self.fingerprint
+            .as_ref()
+            .is_none_or(|fingerprint| *fingerprint == message::key_fingerprint(key))
+    }
+}
+
+impl Drop for Invite {
+    fn drop(&mut self) {
+        self.passphrase.zeroize();
+        self.mles_key.zeroize();
+    }
+}
+
+impl fmt::Debug for Invite {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let redacted = |secret: &Option<String>| secret.as_ref().map(|_| "..");
+        f.debug_struct("Invite")
+            .field("address", &self.address)
+            .field("tls", &self.tls)
+            .field("channel", &self.channel)
+            .field("passphrase", &redacted(&self.passphrase))
+            .field("fingerprint", &self.fingerprint)
+            .field("mles_key", &redacted(&self.mles_key))
+            .field("kdf", &self.kdf)
+            .field("epoch", &self.epoch)
+            .field("expires", &self.expires)
+            .field("opaque_channel", &self.opaque_channel)
+            .field("pseudonym", &self.pseudonym)
+            .field("padding", &self.padding)
+            .finish()
+    }
+}
+
+/// Formats the invite as an `mles://` URI
+impl fmt::Display for Invite {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut url = Url::parse(&format!("{}://{}", SCHEME, self.address))
+            .expect("server address was validated");
+        url.path_segments_mut()
+            .expect("URLs with a host have a path")
+            .push(&self.channel);
+        let mut params = Vec::new();
+        if let Some(passphrase) = &self.passphrase {
+            params.push(("key", passphrase.clone()));
+        }
+        if let Some(fingerprint) = &self.fingerprint {
+            params.push(("fp", fingerprint.clone()));
+        }
+        if let Some(mles_key) = &self.mles_key {
+            params.push(("mles_key", mles_key.clone()));
+        }
+        if self.kdf != KdfParams::ChannelSalt {
+            params.push(("kdf", self.kdf.to_string()));
+        }
+        if self.epoch != 0 {
+            params.push(("epoch", self.epoch.to_string()));
+        }
+        if let Some(expires) = self.expires {
+            params.push(("expires", expires.timestamp().to_string()));
+        }
+        if self.opaque_channel {
+            params.push(("opaque", "1".to_string()));
+        }
+        if self.pseudonym {
+            params.push(("pseudonym", "1".to_string()));
+        }
+        if self.padding != Padding::None {
+            params.push(("padding", self.padding.to_string()));
+        }
+        if !self.tls {
+            params.push(("tls", "0".to_string()));
+        }
+        if !params.is_empty() {
+            url.query_pairs_mut().extend_pairs(&params);
+        }
+        params.iter_mut().for_each(|(_, value)| value.zeroize());
+        write!(f, "{}", url)
+    }
+}
+
+/// Parses an `mles://` URI. Unknown parameters are ignored.
+impl FromStr for Invite {
+    type Err = String;
+
+    fn from_str(uri: &str) -> Result<Self, Self::Err> {
+        let url = Url::parse(uri).map_err(|e| format!("invalid invite: {}", e))?;
+        if url.scheme() != SCHEME {
+            return Err(format!("invites start with {}://", SCHEME));
+        }
+        let channel = url
+            .path_segments()
+            .and_then(|mut segments| segments.next())
+            .filter(|channel| !channel.is_empty())
+            .ok_or("invite has no channel")?;
+        let channel = percent_decode_str(channel)
+            .decode_utf8()
+            .map_err(|_| "invite channel is not UTF-8")?;
+        let server = format!(
+            "wss://{}",
+            &url[url::Position::BeforeHost..url::Position::AfterPort]
+        );
+        let mut invite = Self::new(&server, &channel)?;
+        for (name, value) in url.query_pairs() {
+            match &*name {
+                "key" => invite.passphrase = Some(value.into_owned()),
+                "fp" => invite.fingerprint = Some(value.into_owned()),
+                "mles_key" => invite.mles_key = Some(value.into_owned()),
+                "kdf" => invite.kdf = value.parse()?,
+                "epoch" => invite.epoch = value.parse().map_err(|_| "invalid invite epoch")?,
+                "expires" => {
+                    invite.expires = value
+                        .parse()
+                        .ok()
+                        .and_then(|secs| DateTime::from_timestamp(secs, 0))
+                        .map(Some)
+                        .ok_or("invalid invite expiry")?;
+                }
+                "opaque" => invite.opaque_channel = value != "0",
+                "pseudonym" => invite.pseudonym = value != "0",
+                "padding" => invite.padding = value.parse()?,
+                "tls" => invite.tls = value != "0",
+                _ => {}
+            }
+        }
+        Ok(invite)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::secret::SecretKey;
+
+    #[test]
+    fn test_uri_roundtrip() {
+        let mut invite = Invite::new("wss://mles.io", "ops team/ä").unwrap();
+        assert_eq!(invite.to_string(), "mles://mles.io/ops%20team%2F%C3%A4");
+        assert_eq!(invite.to_string().parse(), Ok(invite.clone()));
+
+        invite.passphrase = Some("correct horse & battery".to_string());
+        invite.mles_key = Some("k".to_string());
+        invite.kdf = "scrypt:log_n=10,salt=AAECAwQFBgcICQoLDA0ODw"
+            .parse()
+            .unwrap();
+        invite.epoch = 2;
+        invite.expires = DateTime::from_timestamp(1767225600, 0);
+        invite.opaque_channel = true;
+        invite.pseudonym = true;
+        invite.padding = Padding::Padme;
+        let uri = invite.to_string();
+        assert!(uri.ends_with("&opaque=1&pseudonym=1&padding=padme"));
+        assert!(uri.starts_with("mles://mles.io/ops%20team%2F%C3%A4?key=correct+horse+%26"));
+        let parsed: Invite = uri.parse().unwrap();
+        assert_eq!(parsed, invite);
+        let debug = format!("{:?}", parsed);
+        assert!(!debug.contains("correct horse") && !debug.contains("\"k\""));
+        assert_eq!(parsed.server(), "wss://mles.io");
+
+        let local: Invite = "mles://localhost:8080/dev?tls=0&future=1".parse().unwrap();
+        assert_eq!(local.server(), "ws://localhost:8080");
+        assert_eq!(local.channel, "dev");
+        assert_eq!(local.to_string(), "mles://localhost:8080/dev?tls=0");
+    }
+
+    #[test]
+    fn test_rejected_and_checked() {
+        assert!(Invite::new("wss://mles.io/path", "ops").is_err());
+        assert!(Invite::new("https://mles.io", "ops").is_err());
+        assert!("mles://mles.io".parse::<Invite>().is_err());
+        assert!("https://mles.io/ops".parse::<Invite>().is_err());
+        assert!("mles://mles.io/ops?epoch=x".parse::<Invite>().is_err());
+        assert!("mles://mles.io/ops?padding=x".parse::<Invite>().is_err());
+
+        let key = ChannelKey::from(SecretKey::from([3u8; 32]));
+        let mut invite = Invite::new("wss://mles.io", "ops").unwrap();
+        assert!(invite.matches(&key));
+        invite.fingerprint = Some(message::key_fingerprint(&key));
+        assert!(invite.matches(&key));
+        assert!(!invite.matches(&ChannelKey::from(SecretKey::from([4u8; 32]))));
+
+        invite.expires = DateTime::from_timestamp(1767225600, 0);
+        let expires = invite.expires.unwrap();
+        assert!(!invite.is_expired_at(expires));
+        assert!(invite.is_expired_at(expires + chrono::Duration::seconds(1)));
+    }
+}
@@ -0,0 +1,362 @@
+//! Key derivation from the shared channel passphrase.
+//!
+//! The default derivation is scrypt with the recommended parameters, salted
+//! with Blake2b(channel), as used by earlier clients. Channels can instead
+//! pick their own scrypt or Argon2id cost parameters with a random salt,
+//! described by a spec string such as `argon2id:m=65536,t=3,p=1,salt=<base64>`
+//! that all members pass with `--kdf`. The parameters travel in the envelope
+//! of every message, see [`KdfParams::to_bytes`].
+
+use crate::envelope::{Envelope, KdfId};
+use crate::error::{CryptoError, Result};
+use crate::message;
+use crate::secret::SecretKey;
+use argon2::{Algorithm, Argon2, Version};
+use base64::{Engine as _, engine::general_purpose::STANDARD_NO_PAD};
+use rand::{RngCore, rngs::OsRng};
+use std::fmt;
+use std::str::FromStr;
+use zeroize::Zeroizing;
+
+/// Length of the random salt of configurable KDFs
+pub const SALT_LEN: usize = 16;
+
+/// Key derivation function and parameters used for a channel key
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum KdfParams {
+    /// Scrypt with the recommended parameters, salted with Blake2b(channel)
+    #[default]
+    ChannelSalt,
+    /// Scrypt with explicit cost parameters
+    Scrypt {
+        log_n: u8,
+        r: u32,
+        p: u32,
+        salt: [u8; SALT_LEN],
+    },
+    /// Argon2id with explicit memory (KiB), time and parallelism costs
+    Argon2id {
+        m_cost: u32,
+        t_cost: u32,
+        p_cost: u32,
+        salt: [u8; SALT_LEN],
+    },
+}
+
+impl KdfParams {
+    /// Returns the identifier carried in the envelope header
+    pub fn id(&self) -> KdfId {
+        match self {
+            KdfParams::ChannelSalt => KdfId::ScryptBlake2bSalt,
+            KdfParams::Scrypt { .. } => KdfId::Scrypt,
+            KdfParams::Argon2id { .. } => KdfId::Argon2id,
+        }
+    }
+
+    /// Derives a 256-bit key from `password` for `channel`
+    pub fn derive(&self, password: &str, channel: &str) -> Result<SecretKey> {
+        let mut key = SecretKey::zeroed();
+        let out = key.as_mut_bytes();
+        match self {
+            KdfParams::ChannelSalt => return message::derive_key(password, channel),
+            KdfParams::Scrypt { log_n, r, p, salt } => {
+                let params = scrypt::Params::new(*log_n, *r, *p, out.len())
+                    .map_err(|_| CryptoError::InvalidKdfParams)?;
+                scrypt::scrypt(password.as_bytes(), salt, &params, out)
+                    .expect("32-byte output is valid for scrypt");
+            }
+            KdfParams::Argon2id {
+                m_cost,
+                t_cost,
+                p_cost,
+                salt,
+            } => {
+                let params = argon2::Params::new(*m_cost, *t_cost, *p_cost, Some(out.len()))
+                    .map_err(|_| CryptoError::InvalidKdfParams)?;
+                Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+                    .hash_password_into(password.as_bytes(), salt, out)
+                    .map_err(|e| CryptoError::KeyDerivation(e.into()))?;
+            }
+        }
+        Ok(key)
+    }
+
+    /// Serializes the parameters as carried after the envelope header, empty
+    /// for the default derivation:
+    ///
+    /// ```text
+    /// scrypt:   log_n (1) | r (4) | p (4) | salt (16)
+    /// argon2id: m_cost (4) | t_cost (4) | p_cost (4) | salt (16)
+    /// ```
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            KdfParams::ChannelSalt => Vec::new(),
+            KdfParams::Scrypt { log_n, r, p, salt } => {
+                [&[*log_n][..], &r.to_be_bytes(), &p.to_be_bytes(), salt].concat()
+            }
+            KdfParams::Argon2id {
+                m_cost,
+                t_cost,
+                p_cost,
+                salt,
+            } => [
+                &m_cost.to_be_bytes()[..],
+                &t_cost.to_be_bytes(),
+                &p_cost.to_be_bytes(),
+                salt,
+            ]
+            .concat(),
+        }
+    }
+
+    /// Parses the parameters for `id` from the start of `bytes`, returning
+    /// them with the remaining bytes
+    pub fn parse(id: KdfId, bytes: &[u8]) -> Option<(Self, &[u8])> {
+        fn u32_at(bytes: &[u8]) -> Option<(u32, &[u8])> {
+            let (value, rest) = bytes.split_first_chunk::<4>()?;
+            Some((u32::from_be_bytes(*value), rest))
+        }
+
+        match id {
+            KdfId::ScryptBlake2bSalt => Some((KdfParams::ChannelSalt, bytes)),
+            KdfId::Scrypt => {
+                let (&log_n, rest) = bytes.split_first()?;
+                let (r, rest) = u32_at(rest)?;
+                let (p, rest) = u32_at(rest)?;
+                let (salt, rest) = rest.split_first_chunk::<SALT_LEN>()?;
+                let salt = *salt;
+                Some((KdfParams::Scrypt { log_n, r, p, salt }, rest))
+            }
+            KdfId::Argon2id => {
+                let (m_cost, rest) = u32_at(bytes)?;
+                let (t_cost, rest) = u32_at(rest)?;
+                let (p_cost, rest) = u32_at(rest)?;
+                let (salt, rest) = rest.split_first_chunk::<SALT_LEN>()?;
+                let salt = *salt;
+                Some((
+                    KdfParams::Argon2id {
+                        m_cost,
+                        t_cost,
+                        p_cost,
+                        salt,
+                    },
+                    rest,
+                ))
+            }
+        }
+    }
+}
+
+/// Formats the parameters as a spec accepted by [`KdfParams::from_str`]
+impl fmt::Display for KdfParams {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KdfParams::ChannelSalt => write!(f, "default"),
+            KdfParams::Scrypt { log_n, r, p, salt } => write!(
+                f,
+                "scrypt:log_n={},r={},p={},salt={}",
+                log_n,
+                r,
+                p,
+                STANDARD_NO_PAD.encode(salt)
+            ),
+            KdfParams::Argon2id {
+                m_cost,
+                t_cost,
+                p_cost,
+                salt,
+            } => write!(
+                f,
+                "argon2id:m={},t={},p={},salt={}",
+                m_cost,
+                t_cost,
+                p_cost,
+                STANDARD_NO_PAD.encode(salt)
+            ),
+        }
+    }
+}
+
+/// Parses `default`, `scrypt:log_n=..,r=..,p=..,salt=..` or
+/// `argon2id:m=..,t=..,p=..,salt=..`. Omitted costs take the library
+/// defaults and `salt=random` generates a new salt.
+impl FromStr for KdfParams {
+    type Err = String;
+
+    fn from_str(spec: &str) -> std::result::Result<Self, Self::Err> {
+        let (name, options) = spec.split_once(':').unwrap_or((spec, ""));
+        let mut values = Vec::new();
+        for option in options.split(',').filter(|option| !option.is_empty()) {
+            let (key, value) = option
+                .split_once('=')
+                .ok_or_else(|| format!("expected key=value, got '{}'", option))?;
+            values.push((key, value));
+        }
+        let get = |key: &str| values.iter().find(|(k, _)| *k == key).map(|(_, v)| *v);
+        let number = |key: &str, default: u32| -> std::result::Result<u32, String> {
+            get(key).map_or(Ok(default), |value| {
+                value
+                    .parse()
+                    .map_err(|_| format!("invalid value for {}: '{}'", key, value))
+            })
+        };
+        let salt = || -> std::result::Result<[u8; SALT_LEN], String> {
+            match get("salt") {
+                None => Err("salt is required, use salt=random to generate one".to_string()),
+                Some("random") => {
+                    let mut salt = [0u8; SALT_LEN];
+                    OsRng.fill_bytes(&mut salt);
+                    Ok(salt)
+                }
+                Some(value) => STANDARD_NO_PAD
+                    .decode(value.trim_end_matches('='))
+                    .ok()
+                    .and_then(|salt| salt.try_into().ok())
+                    .ok_or_else(|| format!("salt must be {} bytes of base64", SALT_LEN)),
+            }
+        };
+
+        match name {
+            "default" if options.is_empty() => Ok(KdfParams::ChannelSalt),
+            "scrypt" => {
+                let log_n = number("log_n", scrypt::Params::RECOMMENDED_LOG_N.into())?;
+                Ok(KdfParams::Scrypt {
+                    log_n: log_n.try_into().map_err(|_| "log_n is too large")?,
+                    r: number("r", scrypt::Params::RECOMMENDED_R)?,
+                    p: number("p", scrypt::Params::RECOMMENDED_P)?,
+                    salt: salt()?,
+                })
+            }
+            "argon2id" => Ok(KdfParams::Argon2id {
+                m_cost: number("m", argon2::Params::DEFAULT_M_COST)?,
+                t_cost: number("t", argon2::Params::DEFAULT_T_COST)?,
+                p_cost: number("p", argon2::Params::DEFAULT_P_COST)?,
+                salt: salt()?,
+            }),
+            _ => Err(format!("unknown KDF '{}'", spec)),
+        }
+    }
+}
+
+/// Channel key together with the parameters it was derived with and its epoch
+#[derive(Debug)]
+pub struct ChannelKey {
+    key: SecretKey,
+    kdf: KdfParams,
+    epoch: u32,
+}
+
+impl ChannelKey {
+    /// Derives the key for `channel` from `password` with `kdf`
+    pub fn derive(password: &str, channel: &str, kdf: KdfParams) -> Result<Self> {
+        Ok(Self::new(kdf.derive(password, channel)?, kdf))
+    }
+
+    /// Wraps a key derived with `kdf`
+    pub fn new(key: SecretKey, kdf: KdfParams) -> Self {
+        Self { key, kdf, epoch: 0 }
+    }
+
+    /// Assigns the key to key epoch `epoch`, see [`crate::keyring`]
+    pub fn with_epoch(self, epoch: u32) -> Self {
+        Self { epoch, ..self }
+    }
+
+    /// Returns the raw key
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        self.key.as_bytes()
+    }
+
+    /// Returns the parameters the key was derived with
+    pub fn kdf(&self) -> &KdfParams {
+        &self.kdf
+    }
+
+    /// Returns the key epoch, 0 for the initial key of a channel
+    pub fn epoch(&self) -> u32 {
+        self.epoch
+    }
+
+    /// Returns the key if `envelope` is a channel message of this key's
+    /// epoch whose KDF parameters match the ones this key was derived with
+    pub fn key_for(&self, envelope: &Envelope) -> Option<Zeroizing<[u8; 32]>> {
+        (!envelope.header.is_ratchet() && envelope.kdf == self.kdf && envelope.epoch == self.epoch)
+            .then(|| Zeroizing::new(*self.key.as_bytes()))
+    }
+
+    /// Returns a second copy of the key, see [`SecretKey::duplicate`]
+    pub fn duplicate(&self) -> Self {
+        Self {
+            key: self.key.duplicate(),
+            ..*self
+        }
+    }
+}
+
+/// Wraps a key from [`message::derive_key`], the default derivation
+impl From<SecretKey> for ChannelKey {
+    fn from(key: SecretKey) -> Self {
+        Self::new(key, KdfParams::ChannelSalt)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spec_roundtrip() {
+        let spec = "argon2id:m=1024,t=2,p=1,salt=AAECAwQFBgcICQoLDA0ODw";
+        let params: KdfParams = spec.parse().unwrap();
+        assert_eq!(params.to_string(), spec);
+        assert_eq!(
+            KdfParams::parse(KdfId::Argon2id, &params.to_bytes()),
+            Some((params, &[][..]))
+        );
+
+        let params: KdfParams = "scrypt:log_n=10,salt=random".parse().unwrap();
+        assert!(matches!(
+            params,
+            KdfParams::Scrypt {
+                log_n: 10,
+                r: 8,
+                p: 1,
+                ..
+            }
+        ));
+        assert_eq!(params.to_string().parse::<KdfParams>(), Ok(params));
+        assert_eq!(params.to_bytes().len(), 1 + 4 + 4 + SALT_LEN);
+
+        assert_eq!("default".parse(), Ok(KdfParams::ChannelSalt));
+        assert!("scrypt:log_n=10".parse::<KdfParams>().is_err());
+        assert!("bcrypt".parse::<KdfParams>().is_err());
+    }
+
+    #[test]
+    fn test_derivations_differ_by_salt() {
+        let spec = |salt: &str| -> KdfParams {
+            format!("scrypt:log_n=4,r=1,p=1,salt={}", salt)
+                .parse()
+                .unwrap()
+        };
+        let first = spec("AAAAAAAAAAAAAAAAAAAAAA");
+        let second = spec("AQEBAQEBAQEBAQEBAQEBAQ");
+        let derive = |kdf: &KdfParams| *kdf.derive("secret", "ops").unwrap().as_bytes();
+        let key = derive(&first);
+        assert_eq!(key, derive(&first));
+        assert_ne!(key, derive(&second));
+
+        let argon2: KdfParams = "argon2id:m=64,t=1,p=1,salt=AAAAAAAAAAAAAAAAAAAAAA"
+            .parse()
+            .unwrap();
+        assert_ne!(key, derive(&argon2));
+
+        let invalid = KdfParams::Scrypt {
+            log_n: 0,
+            r: 0,
+            p: 0,
+            salt: [0; SALT_LEN],
+        };
+        assert!(invalid.derive("secret", "ops").is_err());
+    }
+}
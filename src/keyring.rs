@@ -0,0 +1,299 @@
+//! Channel keys of successive epochs, so that the shared passphrase can be
+//! changed without every member switching at the same moment.
+//!
+//! The key derived from the original passphrase is epoch 0. A member changing
+//! the passphrase sends a [`RekeyAnnouncement`] under the current epoch,
+//! carrying the key of the next one. Messages are sent under the newest epoch
+//! and decrypted with any epoch in the ring; older epochs are dropped once
+//! they have been superseded for longer than the expiry.
+//!
+//! Members joining later pass the new passphrase together with its epoch
+//! number. Two members rekeying at the same time pick the same epoch number
+//! for different keys. Every member then settles on the key with the lower
+//! fingerprint, whichever order the announcements arrive in; messages sent
+//! under the other key in the meantime are lost to those who switched.
+
+use crate::envelope::Envelope;
+use crate::kdf::ChannelKey;
+use crate::message::key_fingerprint;
+use crate::secret::SecretKey;
+use base64::{Engine as _, engine::general_purpose::STANDARD};
+use chrono::{DateTime, Duration, SubsecRound, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use zeroize::{Zeroize, Zeroizing};
+
+/// Default time superseded epochs stay readable
+pub const DEFAULT_EXPIRY: Duration = Duration::hours(24);
+
+/// Highest epoch a key can be installed for, leaving no room for a rekey
+pub const MAX_EPOCH: u32 = u32::MAX - 1;
+
+/// Announcement of the key of a new epoch, sent under the current one.
+///
+/// Encoded like chat messages, e.g.
+/// `{"type":"rekey","sender":"alice","timestamp":...,"epoch":1,"kdf":"default","key":...}`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename = "rekey")]
+pub struct RekeyAnnouncement {
+    pub sender: String,
+    pub timestamp: DateTime<Utc>,
+    pub epoch: u32,
+    /// KDF spec the key was derived with, see [`crate::kdf::KdfParams`]
+    pub kdf: String,
+    /// Base64 channel key
+    pub key: String,
+}
+
+impl RekeyAnnouncement {
+    /// Creates the announcement of `key` from `sender`
+    pub fn new(sender: &str, key: &ChannelKey) -> Self {
+        Self {
+            sender: sender.to_string(),
+            timestamp: Utc::now().trunc_subsecs(0),
+            epoch: key.epoch(),
+            kdf: key.kdf().to_string(),
+            key: STANDARD.encode(key.as_bytes()),
+        }
+    }
+
+    /// Encodes the announcement into the plaintext that gets encrypted
+    pub fn encode(&self) -> String {
+        serde_json::to_string(self).expect("RekeyAnnouncement serialization cannot fail")
+    }
+
+    /// Decodes a decrypted plaintext, returning None if it is not an announcement
+    pub fn decode(plaintext: &str) -> Option<Self> {
+        serde_json::from_str(plaintext).ok()
+    }
+
+    /// Returns the announced key, or None if it is malformed
+    pub fn key(&self) -> Option<ChannelKey> {
+        let key = Zeroizing::new(STANDARD.decode(&self.key).ok()?);
+        let key = SecretKey::try_from(&key[..]).ok()?;
+        let kdf = self.kdf.parse().ok()?;
+        Some(ChannelKey::new(key, kdf).with_epoch(self.epoch))
+    }
+}
+
+impl Drop for RekeyAnnouncement {
+    fn drop(&mut self) {
+        self.key.zeroize();
+    }
+}
+
+/// Key of one epoch
+struct Epoch {
+    key: ChannelKey,
+    /// When a newer epoch was installed
+    superseded_at: Option<DateTime<Utc>>,
+}
+
+/// Keys of the epochs a channel is readable with, newest last
+pub struct Keyring {
+    epochs: BTreeMap<u32, Epoch>,
+    expiry: Duration,
+}
+
+impl Keyring {
+    /// Creates a keyring holding only `key`, at the epoch it is assigned to
+    pub fn new(key: ChannelKey) -> Self {
+        let mut epochs = BTreeMap::new();
+        epochs.insert(
+            key.epoch(),
+            Epoch {
+                key,
+                superseded_at: None,
+            },
+        );
+        Self {
+            epochs,
+            expiry: DEFAULT_EXPIRY,
+        }
+    }
+
+    /// Sets how long superseded epochs stay readable
+    pub fn set_expiry(&mut self, expiry: Duration) {
+        self.expiry = expiry;
+    }
+
+    /// Returns the key of the newest epoch, which messages are sent under
+    pub fn current(&self) -> &ChannelKey {
+        let (_, epoch) = self
+            .epochs
+            .last_key_value()
+            .expect("keyring always holds an epoch");
+        &epoch.key
+    }
+
+    /// Returns the number of the epoch following the newest one, or None if
+    /// it would be past [`MAX_EPOCH`]
+    pub fn next_epoch(&self) -> Option<u32> {
+        self.current()
+            .epoch()
+            .checked_add(1)
+            .filter(|&epoch| epoch <= MAX_EPOCH)
+    }
+
+    /// Installs `key` as the newest epoch at the current time, see
+    /// [`Keyring::install_at`]
+    pub fn install(&mut self, key: ChannelKey) -> bool {
+        self.install_at(key, Utc::now())
+    }
+
+    /// Installs `key` as the newest epoch at time `now`. Returns false if
+    /// its epoch is past [`MAX_EPOCH`] or not newer than the current one,
+    /// e.g. because the announcement was replayed. A different key for the
+    /// current epoch replaces it if its fingerprint is lower, so that
+    /// concurrent rekeys settle on the same key everywhere.
+    pub fn install_at(&mut self, key: ChannelKey, now: DateTime<Utc>) -> bool {
+        let current = self.current();
+        if key.epoch() > MAX_EPOCH || key.epoch() < current.epoch() {
+            return false;
+        }
+        if key.epoch() == current.epoch() {
+            if key_fingerprint(&key) >= key_fingerprint(current) {
+                return false;
+            }
+            if let Some(mut current) = self.epochs.last_entry() {
+                current.get_mut().key = key;
+            }
+            return true;
+        }
+        if let Some(mut current) = self.epochs.last_entry() {
+            current.get_mut().superseded_at = Some(now);
+        }
+        self.epochs.insert(
+            key.epoch(),
+            Epoch {
+                key,
+                superseded_at: None,
+            },
+        );
+        true
+    }
+
+    /// Drops all epochs in favour of `key`, e.g. a key granted to a
+    /// newcomer that joined with a placeholder
+    pub fn replace(&mut self, key: ChannelKey) {
+        *self = Self {
+            expiry: self.expiry,
+            ..Self::new(key)
+        };
+    }
+
+    /// Drops the epochs superseded longer than the expiry ago
+    pub fn expire(&mut self) {
+        self.expire_at(Utc::now());
+    }
+
+    /// Drops the epochs superseded longer than the expiry before `now`
+    pub fn expire_at(&mut self, now: DateTime<Utc>) {
+        let expiry = self.expiry;
+        self.epochs.retain(|_, epoch| {
+            epoch
+                .superseded_at
+                .is_none_or(|superseded_at| superseded_at + expiry > now)
+        });
+    }
+
+    /// Returns the numbers of the epochs in the ring, oldest first
+    pub fn epochs(&self) -> impl Iterator<Item = u32> + '_ {
+        self.epochs.keys().copied()
+    }
+
+    /// Returns the key for `envelope` if its epoch is in the ring
+    pub fn key_for(&self, envelope: &Envelope) -> Option<Zeroizing<[u8; 32]>> {
+        self.epochs.get(&envelope.epoch)?.key.key_for(envelope)
+    }
+}
+
+impl From<ChannelKey> for Keyring {
+    fn from(key: ChannelKey) -> Self {
+        Self::new(key)
+    }
+}
+
+/// Keyring holding a key from [`crate::message::derive_key`] as epoch 0
+impl From<SecretKey> for Keyring {
+    fn from(key: SecretKey) -> Self {
+        Self::new(key.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{decrypt_message_with, encrypt_message};
+    use crate::padding::Padding;
+
+    #[test]
+    fn test_rotation_overlap_and_expiry() {
+        let mut keyring = Keyring::new(ChannelKey::from(SecretKey::from([1u8; 32])));
+        let old = encrypt_message(keyring.current(), "ops", "alice", Padding::None, "old").unwrap();
+
+        let now = Utc::now();
+        let next =
+            ChannelKey::from(SecretKey::from([2u8; 32])).with_epoch(keyring.next_epoch().unwrap());
+        assert!(keyring.install_at(next.duplicate(), now));
+        assert!(!keyring.install_at(next, now));
+        assert_eq!(keyring.current().epoch(), 1);
+        let new = encrypt_message(keyring.current(), "ops", "alice", Padding::None, "new").unwrap();
+
+        let decrypt = |keyring: &Keyring, encrypted: &[u8]| {
+            decrypt_message_with("ops", encrypted, |envelope| keyring.key_for(envelope))
+                .map(|decrypted| decrypted.plaintext)
+        };
+        assert_eq!(decrypt(&keyring, &old).as_deref(), Some("old"));
+        assert_eq!(decrypt(&keyring, &new).as_deref(), Some("new"));
+
+        keyring.expire_at(now + DEFAULT_EXPIRY - Duration::seconds(1));
+        assert_eq!(keyring.epochs().collect::<Vec<_>>(), [0, 1]);
+        keyring.expire_at(now + DEFAULT_EXPIRY);
+        assert_eq!(keyring.epochs().collect::<Vec<_>>(), [1]);
+        assert_eq!(decrypt(&keyring, &old), None);
+        assert_eq!(decrypt(&keyring, &new).as_deref(), Some("new"));
+    }
+
+    #[test]
+    fn test_announcement_roundtrip() {
+        let key = ChannelKey::from(SecretKey::from([2u8; 32])).with_epoch(4);
+        let announcement = RekeyAnnouncement::new("alice", &key);
+        let decoded = RekeyAnnouncement::decode(&announcement.encode()).unwrap();
+        let decoded = decoded.key().unwrap();
+        assert_eq!(decoded.as_bytes(), key.as_bytes());
+        assert_eq!((decoded.kdf(), decoded.epoch()), (key.kdf(), 4));
+
+        let text = crate::chat::ChatMessage::text("alice", "hi").encode();
+        assert_eq!(RekeyAnnouncement::decode(&text), None);
+    }
+
+    #[test]
+    fn test_concurrent_rekeys_and_last_epoch() {
+        let first = ChannelKey::from(SecretKey::from([2u8; 32])).with_epoch(1);
+        let second = ChannelKey::from(SecretKey::from([3u8; 32])).with_epoch(1);
+        let (lower, higher) = if key_fingerprint(&first) < key_fingerprint(&second) {
+            (first, second)
+        } else {
+            (second, first)
+        };
+
+        // Both orders of arrival end on the key with the lower fingerprint
+        for (a, b) in [(&lower, &higher), (&higher, &lower)] {
+            let mut keyring = Keyring::new(ChannelKey::from(SecretKey::from([1u8; 32])));
+            assert!(keyring.install(a.duplicate()));
+            assert_eq!(
+                keyring.install(b.duplicate()),
+                a.as_bytes() == higher.as_bytes()
+            );
+            assert_eq!(keyring.current().as_bytes(), lower.as_bytes());
+            assert_eq!(keyring.epochs().collect::<Vec<_>>(), [0, 1]);
+        }
+
+        let mut keyring = Keyring::new(lower.duplicate().with_epoch(MAX_EPOCH - 1));
+        assert_eq!(keyring.next_epoch(), Some(MAX_EPOCH));
+        assert!(!keyring.install(higher.duplicate().with_epoch(u32::MAX)));
+        assert!(keyring.install(higher.duplicate().with_epoch(MAX_EPOCH)));
+        assert_eq!(keyring.next_epoch(), None);
+    }
+}
@@ -0,0 +1,271 @@
+//! Handing the channel key to a newcomer who does not know the passphrase.
+//!
+//! The newcomer publishes a [`KeyRequest`] carrying an ephemeral X25519
+//! public key. A member who approves it answers with a [`KeyGrant`]: the
+//! current channel key, encoded like a [`RekeyAnnouncement`], sealed with
+//! XChaCha20-Poly1305 under a key derived with Blake2b from an X25519 key
+//! agreement between a fresh key of the member and the requested one.
+//!
+//! The newcomer cannot read encrypted frames yet, so both travel as JSON
+//! text frames, e.g.
+//! `{"type":"key_request","uid":"bob","public_key":...}`.
+//!
+//! Requests are not authenticated: anyone, the server included, can publish
+//! one under any uid. Members should compare [`KeyRequest::fingerprint`]
+//! with the newcomer over another channel before approving.
+//!
+//! Grants are not authenticated either: anyone who sees the request can seal
+//! a key of their own to it and read whatever the newcomer then sends.
+//! Newcomers should only use a granted key whose
+//! [`message::key_fingerprint`](crate::message::key_fingerprint) matches an
+//! invite or the one a member reads out to them.
+
+use crate::kdf::ChannelKey;
+use crate::keyring::RekeyAnnouncement;
+use base64::{Engine as _, engine::general_purpose::STANDARD};
+use blake2::digest::Mac;
+use blake2::{Blake2b512, Blake2bMac512, Digest};
+use chacha20poly1305::{
+    KeyInit, XChaCha20Poly1305,
+    aead::{Aead, Payload},
+};
+use rand::{RngCore, rngs::OsRng};
+use serde::{Deserialize, Serialize};
+use x25519_dalek::{PublicKey, StaticSecret};
+use zeroize::Zeroizing;
+
+/// Text frames of the exchange, told apart by their type
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum TextFrame {
+    KeyRequest(KeyRequest),
+    KeyGrant(KeyGrant),
+}
+
+/// Request for the channel key from a newcomer
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyRequest {
+    pub uid: String,
+    /// Base64 X25519 public key the grant is sealed to
+    pub public_key: String,
+}
+
+impl KeyRequest {
+    /// Encodes the request into a text frame
+    pub fn encode(&self) -> String {
+        serde_json::to_string(&TextFrame::KeyRequest(self.clone()))
+            .expect("KeyRequest serialization cannot fail")
+    }
+
+    /// Decodes a text frame, returning None if it is not a request
+    pub fn decode(text: &str) -> Option<Self> {
+        match serde_json::from_str(text).ok()? {
+            TextFrame::KeyRequest(request) => Some(request),
+            TextFrame::KeyGrant(_) => None,
+        }
+    }
+
+    /// Returns a short human-readable fingerprint of the public key, for
+    /// checking with the newcomer before approving
+    pub fn fingerprint(&self) -> String {
+        let hash = Blake2b512::digest(self.public_key.as_bytes());
+        hash[..8]
+            .chunks(2)
+            .map(|pair| format!("{:02x}{:02x}", pair[0], pair[1]))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    fn x25519_key(&self) -> Option<PublicKey> {
+        let bytes: [u8; 32] = STANDARD.decode(&self.public_key).ok()?.try_into().ok()?;
+        Some(PublicKey::from(bytes))
+    }
+}
+
+/// Channel key sealed to the public key of a [`KeyRequest`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyGrant {
+    /// Newcomer the key is sealed to
+    pub uid: String,
+    /// Member who approved the request
+    pub sender: String,
+    /// Base64 X25519 public key of the member, used once
+    pub public_key: String,
+    /// Base64 nonce
+    pub nonce: String,
+    /// Base64 sealed announcement of the key
+    pub ciphertext: String,
+}
+
+impl KeyGrant {
+    /// Seals `key` from `sender` in `channel` to the requesting newcomer.
+    /// Returns None if the request carries a malformed public key.
+    pub fn seal(
+        sender: &str,
+        channel: &str,
+        request: &KeyRequest,
+        key: &ChannelKey,
+    ) -> Option<Self> {
+        let recipient = request.x25519_key()?;
+        let secret = StaticSecret::random_from_rng(OsRng);
+        let public_key = PublicKey::from(&secret);
+        let cipher = cipher(&secret, &recipient, &public_key, &recipient)?;
+
+        let mut nonce = [0u8; 24];
+        OsRng.fill_bytes(&mut nonce);
+        let plaintext = Zeroizing::new(RekeyAnnouncement::new(sender, key).encode());
+        let aad = aad(channel, &request.uid);
+        let payload = Payload {
+            msg: plaintext.as_bytes(),
+            aad: &aad,
+        };
+        let ciphertext = cipher.encrypt(&nonce.into(), payload).ok()?;
+        Some(Self {
+            uid: request.uid.clone(),
+            sender: sender.to_string(),
+            public_key: STANDARD.encode(public_key.as_bytes()),
+            nonce: STANDARD.encode(nonce),
+            ciphertext: STANDARD.encode(ciphertext),
+        })
+    }
+
+    /// Encodes the grant into a text frame
+    pub fn encode(&self) -> String {
+        serde_json::to_string(&TextFrame::KeyGrant(self.clone()))
+            .expect("KeyGrant serialization cannot fail")
+    }
+
+    /// Decodes a text frame, returning None if it is not a grant
+    pub fn decode(text: &str) -> Option<Self> {
+        match serde_json::from_str(text).ok()? {
+            TextFrame::KeyGrant(grant) => Some(grant),
+            TextFrame::KeyRequest(_) => None,
+        }
+    }
+}
+
+/// Key pair of a newcomer waiting for a [`KeyGrant`]
+pub struct PendingRequest {
+    uid: String,
+    secret: StaticSecret,
+}
+
+impl PendingRequest {
+    /// Creates a fresh key pair for `uid`
+    pub fn new(uid: &str) -> Self {
+        Self {
+            uid: uid.to_string(),
+            secret: StaticSecret::random_from_rng(OsRng),
+        }
+    }
+
+    /// Returns the request to publish in the channel
+    pub fn request(&self) -> KeyRequest {
+        KeyRequest {
+            uid: self.uid.clone(),
+            public_key: STANDARD.encode(PublicKey::from(&self.secret).as_bytes()),
+        }
+    }
+
+    /// Opens a grant sealed to this request in `channel`, returning None if
+    /// it is meant for someone else or fails to decrypt. The key may come
+    /// from anyone and must be confirmed before use, see the module docs.
+    pub fn open(&self, channel: &str, grant: &KeyGrant) -> Option<ChannelKey> {
+        if grant.uid != self.uid {
+            return None;
+        }
+        let sender: [u8; 32] = STANDARD.decode(&grant.public_key).ok()?.try_into().ok()?;
+        let nonce: [u8; 24] = STANDARD.decode(&grant.nonce).ok()?.try_into().ok()?;
+        let ciphertext = STANDARD.decode(&grant.ciphertext).ok()?;
+        let sender = PublicKey::from(sender);
+        let cipher = cipher(
+            &self.secret,
+            &sender,
+            &sender,
+            &PublicKey::from(&self.secret),
+        )?;
+        let aad = aad(channel, &self.uid);
+        let payload = Payload {
+            msg: &ciphertext,
+            aad: &aad,
+        };
+        let plaintext = Zeroizing::new(cipher.decrypt(&nonce.into(), payload).ok()?);
+        let announcement = RekeyAnnouncement::decode(std::str::from_utf8(&plaintext).ok()?)?;
+        announcement.key()
+    }
+}
+
+/// Derives the sealing cipher from the key agreement of `secret` with
+/// `other`, bound to the public keys of the granting member and the
+/// newcomer. Returns None for low-order public keys.
+fn cipher(
+    secret: &StaticSecret,
+    other: &PublicKey,
+    sender: &PublicKey,
+    recipient: &PublicKey,
+) -> Option<XChaCha20Poly1305> {
+    let shared = secret.diffie_hellman(other);
+    if !shared.was_contributory() {
+        return None;
+    }
+    let mut mac = <Blake2bMac512 as KeyInit>::new_from_slice(shared.as_bytes())
+        .expect("32-byte keys are valid for Blake2b");
+    mac.update(b"mles key grant");
+    mac.update(sender.as_bytes());
+    mac.update(recipient.as_bytes());
+    let mut key = Zeroizing::new([0u8; 32]);
+    key.copy_from_slice(&mac.finalize().into_bytes()[..32]);
+    Some(XChaCha20Poly1305::new(key.as_ref().into()))
+}
+
+/// Associated data binding a grant to its channel and newcomer
+fn aad(channel: &str, uid: &str) -> Vec<u8> {
+    [channel.as_bytes(), &[0], uid.as_bytes()].concat()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::secret::SecretKey;
+
+    #[test]
+    fn test_grant_opens_for_requester_only() {
+        let key = ChannelKey::from(SecretKey::from([5u8; 32])).with_epoch(2);
+        let pending = PendingRequest::new("bob");
+        let request = KeyRequest::decode(&pending.request().encode()).unwrap();
+        assert_eq!(request.fingerprint().len(), 19);
+
+        let grant = KeyGrant::seal("alice", "ops", &request, &key).unwrap();
+        assert!(KeyRequest::decode(&grant.encode()).is_none());
+        let grant = KeyGrant::decode(&grant.encode()).unwrap();
+        let opened = pending.open("ops", &grant).unwrap();
+        assert_eq!(opened.as_bytes(), key.as_bytes());
+        assert_eq!(opened.epoch(), 2);
+
+        // Wrong channel, another newcomer's key pair, or another addressee
+        assert!(pending.open("dev", &grant).is_none());
+        let other = PendingRequest::new("bob");
+        assert!(other.open("ops", &grant).is_none());
+        let renamed = KeyGrant {
+            uid: "carol".to_string(),
+            ..grant
+        };
+        assert!(pending.open("ops", &renamed).is_none());
+    }
+
+    #[test]
+    fn test_malformed_request_is_not_sealed() {
+        let key = ChannelKey::from(SecretKey::from([5u8; 32]));
+        let request = KeyRequest {
+            uid: "bob".to_string(),
+            public_key: STANDARD.encode([0u8; 32]),
+        };
+        assert!(KeyGrant::seal("alice", "ops", &request, &key).is_none());
+        let request = KeyRequest {
+            uid: "bob".to_string(),
+            public_key: "not base64".to_string(),
+        };
+        assert!(KeyGrant::seal("alice", "ops", &request, &key).is_none());
+        assert!(KeyRequest::decode("{\"type\":\"rekey\"}").is_none());
+    }
+}
@@ -0,0 +1,259 @@
+//! Local store of channel passphrases and `MLES_KEY` values, encrypted
+//! under a master passphrase.
+//!
+//! The store is a JSON file holding the KDF spec (see [`KdfParams`]) the
+//! master key was derived with, a nonce, and the XChaCha20-Poly1305
+//! encrypted list of entries:
+//!
+//! ```text
+//! {"kdf":"scrypt:log_n=17,r=8,p=1,salt=...","nonce":...,"ciphertext":...}
+//! ```
+//!
+//! Entries are looked up by server URL and channel, so unattended proxies
+//! and chat sessions can start without the secrets being typed in.
+
+use crate::error::{CryptoError, Result};
+use crate::fsutil;
+use crate::kdf::KdfParams;
+use crate::secret::SecretKey;
+use base64::{Engine as _, engine::general_purpose::STANDARD};
+use chacha20poly1305::{
+    KeyInit, XChaCha20Poly1305,
+    aead::{Aead, Payload},
+};
+use rand::{RngCore, rngs::OsRng};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use zeroize::{Zeroize, Zeroizing};
+
+/// KDF spec new stores are created with: scrypt with the recommended costs
+pub const DEFAULT_KDF: &str = "scrypt:salt=random";
+
+/// Associated data binding the ciphertext to its purpose
+const AAD: &[u8] = b"mles keystore";
+
+/// Secrets stored for one channel on one server
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeystoreEntry {
+    pub server: String,
+    pub channel: String,
+    /// Shared passphrase the channel key is derived from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub passphrase: Option<String>,
+    /// Authentication key, as otherwise passed in `MLES_KEY`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mles_key: Option<String>,
+}
+
+impl Drop for KeystoreEntry {
+    fn drop(&mut self) {
+        self.passphrase.zeroize();
+        self.mles_key.zeroize();
+    }
+}
+
+impl fmt::Debug for KeystoreEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let redacted = |secret: &Option<String>| secret.as_ref().map(|_| "..");
+        f.debug_struct("KeystoreEntry")
+            .field("server", &self.server)
+            .field("channel", &self.channel)
+            .field("passphrase", &redacted(&self.passphrase))
+            .field("mles_key", &redacted(&self.mles_key))
+            .finish()
+    }
+}
+
+/// Encrypted file as stored on disk
+#[derive(Serialize, Deserialize)]
+struct StoreFile {
+    kdf: String,
+    /// Base64 nonce
+    nonce: String,
+    /// Base64 encrypted JSON list of entries
+    ciphertext: String,
+}
+
+/// Unlocked keystore, saved back to its file with [`Keystore::save`]
+pub struct Keystore {
+    path: PathBuf,
+    kdf: KdfParams,
+    key: SecretKey,
+    entries: Vec<KeystoreEntry>,
+}
+
+impl Keystore {
+    /// Default location of the store in the user's config directory
+    pub fn default_path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("mles-client").join("keystore.json"))
+    }
+
+    /// Creates an empty store at `path` protected by `master`, with the
+    /// master key derived with `kdf`. Nothing is written until it is saved.
+    pub fn create(path: &Path, master: &str, kdf: KdfParams) -> Result<Self> {
+        Ok(Self {
+            path: path.to_path_buf(),
+            kdf,
+            key: kdf.derive(master, "")?,
+            entries: Vec::new(),
+        })
+    }
+
+    /// Opens the store at `path` with `master`. Fails with
+    /// [`CryptoError::Keystore`] if the passphrase is wrong.
+    pub fn open(path: &Path, master: &str) -> Result<Self> {
+        let file: StoreFile = serde_json::from_slice(&fs::read(path)?).map_err(io::Error::from)?;
+        let kdf: KdfParams = file
+            .kdf
+            .parse()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "malformed keystore");
+        let nonce: [u8; 24] = STANDARD
+            .decode(&file.nonce)
+            .ok()
+            .and_then(|nonce| nonce.try_into().ok())
+            .ok_or_else(invalid)?;
+        let ciphertext = STANDARD.decode(&file.ciphertext).map_err(|_| invalid())?;
+
+        let key = kdf.derive(master, "")?;
+        let payload = Payload {
+            msg: &ciphertext,
+            aad: AAD,
+        };
+        let plaintext = Zeroizing::new(
+            XChaCha20Poly1305::new(key.as_bytes().into())
+                .decrypt(&nonce.into(), payload)
+                .map_err(|_| CryptoError::Keystore)?,
+        );
+        let entries = serde_json::from_slice(&plaintext).map_err(io::Error::from)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            kdf,
+            key,
+            entries,
+        })
+    }
+
+    /// Encrypts the store under a fresh nonce and writes it to its file,
+    /// readable only by the current user. The file is replaced atomically,
+    /// so a crash never leaves a truncated store behind.
+    pub fn save(&self) -> Result<()> {
+        let plaintext = Zeroizing::new(
+            serde_json::to_vec(&self.entries).expect("entry serialization cannot fail"),
+        );
+        let mut nonce = [0u8; 24];
+        OsRng.fill_bytes(&mut nonce);
+        let payload = Payload {
+            msg: &plaintext,
+            aad: AAD,
+        };
+        let ciphertext = XChaCha20Poly1305::new(self.key.as_bytes().into())
+            .encrypt(&nonce.into(), payload)
+            .map_err(|_| CryptoError::Encryption)?;
+        let file = StoreFile {
+            kdf: self.kdf.to_string(),
+            nonce: STANDARD.encode(nonce),
+            ciphertext: STANDARD.encode(ciphertext),
+        };
+
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let json = serde_json::to_vec_pretty(&file).map_err(io::Error::from)?;
+        fsutil::write_private(&self.path, &json)?;
+        Ok(())
+    }
+
+    /// Returns the entry for `channel` on `server`
+    pub fn get(&self, server: &str, channel: &str) -> Option<&KeystoreEntry> {
+        self.entries
+            .iter()
+            .find(|entry| entry.server == server && entry.channel == channel)
+    }
+
+    /// Adds `entry`, replacing the one for the same server and channel
+    pub fn insert(&mut self, entry: KeystoreEntry) {
+        self.remove(&entry.server, &entry.channel);
+        self.entries.push(entry);
+    }
+
+    /// Removes the entry for `channel` on `server`, returning false if there
+    /// was none
+    pub fn remove(&mut self, server: &str, channel: &str) -> bool {
+        let len = self.entries.len();
+        self.entries
+            .retain(|entry| entry.server != server || entry.channel != channel);
+        self.entries.len() != len
+    }
+
+    /// Returns the entries in the order they were added
+    pub fn entries(&self) -> &[KeystoreEntry] {
+        &self.entries
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+
+    fn entry(channel: &str, passphrase: &str) -> KeystoreEntry {
+        KeystoreEntry {
+            server: "wss://mles.io".to_string(),
+            channel: channel.to_string(),
+            passphrase: Some(passphrase.to_string()),
+            mles_key: None,
+        }
+    }
+
+    #[test]
+    fn test_saved_and_reopened() {
+        let path = std::env::temp_dir().join(format!("mles-keystore-{}", std::process::id()));
+        let kdf = "scrypt:log_n=4,r=1,p=1,salt=random".parse().unwrap();
+        let mut keystore = Keystore::create(&path, "master", kdf).unwrap();
+        keystore.insert(entry("ops", "secret"));
+        keystore.save().unwrap();
+
+        let keystore = Keystore::open(&path, "master").unwrap();
+        assert_eq!(
+            keystore.get("wss://mles.io", "ops"),
+            Some(&entry("ops", "secret"))
+        );
+        assert_eq!(keystore.get("wss://other", "ops"), None);
+        assert!(!fs::read_to_string(&path).unwrap().contains("secret"));
+        #[cfg(unix)]
+        assert_eq!(
+            std::os::unix::fs::PermissionsExt::mode(&fs::metadata(&path).unwrap().permissions())
+                & 0o777,
+            0o600
+        );
+        let debug = format!("{:?}", entry("ops", "secret"));
+        assert!(debug.contains("ops") && !debug.contains("secret"));
+
+        assert!(matches!(
+            Keystore::open(&path, "wrong"),
+            Err(Error::Crypto(CryptoError::Keystore))
+        ));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_insert_replaces_and_remove() {
+        let kdf = "scrypt:log_n=4,r=1,p=1,salt=random".parse().unwrap();
+        let mut keystore = Keystore::create(Path::new("unused"), "master", kdf).unwrap();
+        keystore.insert(entry("ops", "first"));
+        keystore.insert(entry("dev", "other"));
+        keystore.insert(entry("ops", "second"));
+        assert_eq!(
+            keystore.entries(),
+            [entry("dev", "other"), entry("ops", "second")]
+        );
+
+        assert!(keystore.remove("wss://mles.io", "ops"));
+        assert!(!keystore.remove("wss://mles.io", "ops"));
+        assert_eq!(keystore.entries(), [entry("dev", "other")]);
+    }
+}
@@ -0,0 +1,35 @@
+//! Mles v2 protocol client library.
+//!
+//! Provides [`MlesClient`] for connecting to a Mles server with end-to-end
+//! encryption, together with the building blocks used by the `mles-client`
+//! binary: key derivation and message encryption, duplicate detection, and the
+//! Mles-to-Mles and Mles-to-MQTT proxies.
+
+pub mod chat;
+pub mod client;
+pub mod dupdet;
+pub mod envelope;
+pub mod error;
+mod fsutil;
+pub mod handshake;
+pub mod identity;
+pub mod invite;
+pub mod kdf;
+pub mod keyring;
+pub mod keyshare;
+pub mod keystore;
+pub mod message;
+pub mod mqtt_proxy;
+pub mod padding;
+pub mod proxy;
+pub mod ratchet;
+pub mod replay;
+pub mod secret;
+pub mod transfer;
+pub mod transport;
+pub mod trust;
+
+pub use chat::ChatMessage;
+pub use client::{MlesClient, MlesReceiver, MlesSender};
+pub use error::{Error, Result};
+pub use transport::{Frame, MemoryTransport, Transport, WsTransport};
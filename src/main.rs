@@ -1,31 +1,41 @@
-use chrono::{DateTime, Local, Utc};
-use clap::Parser;
+use chrono::{DateTime, Duration, Local, Utc};
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser, Subcommand};
 use crossterm::{
     cursor, execute,
     style::{Color, SetBackgroundColor, SetForegroundColor},
     terminal::{Clear, ClearType, size},
 };
-use futures_util::{SinkExt, StreamExt};
-use rand::seq::SliceRandom;
+use futures_util::StreamExt;
+use mles_client::client::Incoming;
+use mles_client::dupdet::{self, MessageTracker};
+use mles_client::envelope::ContentType;
+use mles_client::error::CryptoError;
+use mles_client::handshake::AuthFrame;
+use mles_client::identity::{self, Identity, PublicKey};
+use mles_client::invite::Invite;
+use mles_client::kdf::{ChannelKey, KdfParams};
+use mles_client::keyring::Keyring;
+use mles_client::keyshare::{KeyRequest, PendingRequest};
+use mles_client::keystore::{DEFAULT_KDF, Keystore, KeystoreEntry};
+use mles_client::padding::Padding;
+use mles_client::replay::{ReplayError, ReplayGuard};
+use mles_client::secret::SecretKey;
+use mles_client::transfer::{Download, Downloads, FileOffer};
+use mles_client::trust::{TrustStatus, TrustStore};
+use mles_client::{ChatMessage, Error, MlesClient, Result, message, mqtt_proxy, proxy};
+use qrcode::QrCode;
+use qrcode::render::unicode::Dense1x2;
+use rand::{RngCore, rngs::OsRng, seq::SliceRandom};
 use rpassword::read_password;
-use serde_json::json;
-use siphasher::sip::SipHasher;
 use std::collections::{HashMap, HashSet};
 use std::env;
-use std::hash::Hasher;
 use std::io::{self, Write};
+use std::path::{Path, PathBuf};
 use std::process;
 use std::sync::Arc;
 use tokio::sync::Mutex;
-use tokio_tungstenite::tungstenite::protocol::CloseFrame;
-use tokio_tungstenite::{
-    connect_async, tungstenite::client::IntoClientRequest, tungstenite::protocol::Message,
-};
-
-mod dupdet;
-mod message;
-mod mqtt_proxy;
-mod proxy;
+use zeroize::{Zeroize, Zeroizing};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -49,297 +59,1124 @@ struct Args {
     /// MQTT broker URL for MQTT proxy mode
     #[arg(long)]
     mqtt_broker: Option<String>,
+
+    /// Forward a message again in proxy modes once this many seconds have
+    /// passed since it was first seen
+    #[arg(
+        long,
+        default_value_t = dupdet::DEFAULT_TTL.as_secs(),
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    dedup_ttl: u64,
+
+    /// Maximum number of messages remembered for deduplication in proxy modes
+    #[arg(
+        long,
+        default_value_t = dupdet::DEFAULT_CAPACITY,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+    )]
+    dedup_capacity: usize,
+
+    /// File the proxy modes keep their deduplication state in across
+    /// restarts, saved every minute and on shutdown
+    #[arg(long)]
+    dedup_state: Option<PathBuf>,
+
+    /// Reject chat messages older than this many seconds
+    #[arg(long, default_value_t = 86400)]
+    replay_window: u32,
+
+    /// Accept chat messages up to this many seconds ahead of the local clock
+    #[arg(long, default_value_t = 300)]
+    max_clock_skew: u32,
+
+    /// Identity file used to sign chat messages, generated on first use
+    /// [default: mles-client/identity in the user config directory]
+    #[arg(long)]
+    identity: Option<PathBuf>,
+
+    /// Send unsigned messages without announcing an identity key
+    #[arg(long, conflicts_with = "identity")]
+    no_identity: bool,
+
+    /// Send chat messages with per-message sender chain keys for forward secrecy
+    #[arg(long)]
+    ratchet: bool,
+
+    /// Send chat messages in the format of earlier and browser clients:
+    /// unsigned, unpadded and not bound to the sender
+    #[arg(long, conflicts_with_all = ["ratchet", "pseudonym", "opaque_channel", "identity"])]
+    legacy: bool,
+
+    /// Key derivation for the shared key: "default", "scrypt:log_n=..,r=..,p=..,salt=.."
+    /// or "argon2id:m=..,t=..,p=..,salt=..", with salt=random to generate a salt
+    #[arg(long, env = "MLES_KDF", default_value = "default")]
+    kdf: KdfParams,
+
+    /// Pad messages to hide their length: "none", "padme" or "pow2"
+    #[arg(long, default_value = "none")]
+    padding: Padding,
+
+    /// Key epoch the shared key belongs to, as announced by /rekey
+    #[arg(long, default_value_t = 0)]
+    epoch: u32,
+
+    /// Keep reading messages under a replaced channel key for this many seconds
+    #[arg(long, default_value_t = 86400)]
+    key_expiry: u32,
+
+    /// Directory received files are saved to [default: the user's download directory]
+    #[arg(long)]
+    download_dir: Option<PathBuf>,
+
+    /// Send a keyed hash of the channel name to the server instead of the name
+    #[arg(long)]
+    opaque_channel: bool,
+
+    /// Send a keyed hash of the uid to the server and in message envelopes;
+    /// chat messages still show the uid
+    #[arg(long)]
+    pseudonym: bool,
+
+    /// Ask the channel for the shared key instead of prompting for it;
+    /// a member sends it with /approve
+    #[arg(long, conflicts_with_all = ["opaque_channel", "pseudonym"])]
+    request_key: bool,
+
+    /// Encrypted keystore holding shared keys and MLES_KEY values
+    /// [default: mles-client/keystore.json in the user config directory]
+    #[arg(long, env = "MLES_KEYSTORE")]
+    keystore: Option<PathBuf>,
+
+    /// mles:// invite to join, in place of --server, --channel, --kdf and --epoch,
+    /// also turning on the --opaque-channel, --pseudonym and --padding it carries
+    invite: Option<Invite>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Send a file to the channel and exit
+    SendFile {
+        /// File to send
+        path: PathBuf,
+    },
+    /// Manage the secrets stored in the keystore
+    Keystore {
+        #[command(subcommand)]
+        action: KeystoreCommand,
+    },
+    /// Create invites to the channel
+    Invite {
+        #[command(subcommand)]
+        action: InviteCommand,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum KeystoreCommand {
+    /// Store the shared key and MLES_KEY for the channel on the server
+    Add,
+    /// List the servers and channels with stored secrets
+    List,
+    /// Remove the secrets stored for the channel on the server
+    Remove,
+}
+
+#[derive(Subcommand, Debug)]
+enum InviteCommand {
+    /// Print an invite URI for the channel on the server, with a QR code
+    Create {
+        /// Put the shared key and MLES_KEY in the invite instead of the
+        /// key fingerprint
+        #[arg(long)]
+        include_key: bool,
+
+        /// Make the invite expire after this many seconds
+        #[arg(long)]
+        expires_in: Option<u32>,
+    },
+}
+
+/// Chat message as shown in the UI
+struct UiMessage {
+    message: ChatMessage,
+    /// True if the message was signed with the key pinned for the sender
+    verified: bool,
+    /// True for warnings that must stand out
+    alert: bool,
+}
+
+impl UiMessage {
+    fn alert(body: &str) -> Self {
+        Self {
+            alert: true,
+            ..ChatMessage::system(body).into()
+        }
+    }
+}
+
+impl From<ChatMessage> for UiMessage {
+    fn from(message: ChatMessage) -> Self {
+        Self {
+            message,
+            verified: false,
+            alert: false,
+        }
+    }
 }
 
 #[tokio::main]
 async fn main() {
     let args = Args::parse();
 
-    if let Some(mqtt_broker) = args.mqtt_broker {
-        // Get necessary information
-        let uid = args.uid.unwrap_or_else(|| {
-            print!("UID: ");
-            io::stdout().flush().unwrap();
-            let mut input = String::new();
-            io::stdin().read_line(&mut input).unwrap();
-            input.trim().to_string()
-        });
-
-        let channel = args.channel.unwrap_or_else(|| {
-            print!("Channel: ");
-            io::stdout().flush().unwrap();
-            let mut input = String::new();
-            io::stdin().read_line(&mut input).unwrap();
-            input.trim().to_string()
-        });
+    if let Err(e) = run(args).await {
+        eprintln!("\nError: {}", e);
+        process::exit(e.exit_code());
+    }
+    process::exit(0);
+}
 
-        // Run in MQTT proxy mode
-        if let Err(e) = mqtt_proxy::run_mqtt_proxy(args.server, mqtt_broker, channel, uid).await {
-            eprintln!("MQTT Proxy error: {}", e);
-            process::exit(1);
+async fn run(mut args: Args) -> Result<()> {
+    if let Some(invite) = &args.invite {
+        if invite.is_expired_at(Utc::now()) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "invite has expired").into());
         }
-    } else if args.proxy_server.is_some() {
-        let proxy_server = args.proxy_server.unwrap();
-        // Get necessary information
-        let uid = args.uid.unwrap_or_else(|| {
-            print!("UID: ");
-            io::stdout().flush().unwrap();
-            let mut input = String::new();
-            io::stdin().read_line(&mut input).unwrap();
-            input.trim().to_string()
-        });
+        args.server = invite.server();
+        args.channel = Some(invite.channel.clone());
+        args.kdf = invite.kdf;
+        args.epoch = invite.epoch;
+        args.opaque_channel |= invite.opaque_channel;
+        args.pseudonym |= invite.pseudonym;
+        if invite.padding != Padding::None {
+            args.padding = invite.padding;
+        }
+        // Clap checked the conflicts before the invite turned on its options
+        for (option, given) in [
+            ("--legacy", args.legacy),
+            ("--request-key", args.request_key),
+        ] {
+            if given && (args.opaque_channel || args.pseudonym) {
+                Args::command()
+                    .error(
+                        ErrorKind::ArgumentConflict,
+                        format!(
+                            "{} cannot join invites with opaque channel names or pseudonyms",
+                            option
+                        ),
+                    )
+                    .exit();
+            }
+        }
+    }
 
-        let channel = args.channel.unwrap_or_else(|| {
-            print!("Channel: ");
-            io::stdout().flush().unwrap();
-            let mut input = String::new();
-            io::stdin().read_line(&mut input).unwrap();
-            input.trim().to_string()
+    let keystore_path = args.keystore.take().or_else(Keystore::default_path);
+    if let Some(Command::Keystore { action }) = &args.command {
+        let path = keystore_path.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, "no config directory for keystore")
+        })?;
+        return run_keystore(action, &path, &args.server, args.channel);
+    }
+
+    // Get necessary information
+    let channel = prompt_if_missing(args.channel.take(), "Channel: ")?;
+    let keystore = match keystore_path {
+        Some(path) if path.exists() => Some(Keystore::open(&path, &keystore_passphrase(false)?)?),
+        _ => None,
+    };
+    let stored = keystore
+        .as_ref()
+        .and_then(|keystore| keystore.get(&args.server, &channel));
+    let mles_key = env::var("MLES_KEY")
+        .ok()
+        .or_else(|| stored.and_then(|entry| entry.mles_key.clone()))
+        .or_else(|| {
+            args.invite
+                .as_ref()
+                .and_then(|invite| invite.mles_key.clone())
         });
 
+    if let Some(Command::Invite {
+        action: InviteCommand::Create {
+            include_key,
+            expires_in,
+        },
+    }) = args.command
+    {
+        let key = shared_key(stored, args.invite.as_ref())?;
+        return create_invite(&args, &channel, &key, mles_key, include_key, expires_in);
+    }
+    let uid = prompt_if_missing(args.uid, "UID: ")?;
+
+    if let Some(mqtt_broker) = args.mqtt_broker {
+        // Run in MQTT proxy mode
+        let auth = AuthFrame::builder(&uid, &channel)
+            .mles_key_opt(mles_key)
+            .build();
+        let message_tracker = dedup_tracker(args.dedup_ttl, args.dedup_capacity);
+        mqtt_proxy::run_mqtt_proxy(
+            args.server,
+            mqtt_broker,
+            auth,
+            message_tracker,
+            args.dedup_state,
+        )
+        .await
+    } else if let Some(proxy_server) = args.proxy_server {
         // Run in proxy mode
-        if let Err(e) = proxy::run_proxy(args.server, proxy_server, channel, uid).await {
-            eprintln!("Proxy {}", e);
-            process::exit(1);
-        }
+        let auth = AuthFrame::builder(&uid, &channel)
+            .mles_key_opt(mles_key)
+            .build();
+        let message_tracker = dedup_tracker(args.dedup_ttl, args.dedup_capacity);
+        proxy::run_proxy(
+            args.server,
+            proxy_server,
+            auth,
+            message_tracker,
+            args.dedup_state,
+        )
+        .await
     } else {
-        let message_tracker = Arc::new(Mutex::new(dupdet::MessageTracker::new()));
-        let message_tracker_clone = Arc::clone(&message_tracker);
-        let url = args.server;
-        let mut request = url.into_client_request().expect("Invalid request");
-        request
-            .headers_mut()
-            .insert("Sec-WebSocket-Protocol", "mles-websocket".parse().unwrap());
-
-        // Try to connect and exit on failure
-        let (ws_stream, _) = connect_async(request).await.unwrap_or_else(|e| {
-            eprintln!("Failed to connect: {}", e);
-            process::exit(1);
-        });
-        let (write, mut read) = ws_stream.split();
-        let write = Arc::new(Mutex::new(write)); // Share write between tasks
-        let write_clone = Arc::clone(&write);
-        let messages = Arc::new(Mutex::new(Vec::new()));
-        let messages_clone = Arc::clone(&messages);
-        let user_colors = Arc::new(Mutex::new(HashMap::new()));
-
-        // Ask for UID and Channel
-        let mut uid = String::new();
-        let mut channel = String::new();
-        let mut stdout = io::stdout();
-
-        if let Some(arg_uid) = args.uid {
-            uid = arg_uid;
+        let pending = args.request_key.then(|| PendingRequest::new(&uid));
+        let encryption_key = if pending.is_some() {
+            // Placeholder until a member grants the channel key
+            let mut placeholder = [0u8; 32];
+            OsRng.fill_bytes(&mut placeholder);
+            ChannelKey::from(SecretKey::from(placeholder))
         } else {
-            print!("UID: ");
-            stdout.flush().unwrap();
-            io::stdin().read_line(&mut uid).unwrap();
+            let key = shared_key(stored, args.invite.as_ref())?;
+            let encryption_key =
+                ChannelKey::derive(&key, &channel, args.kdf)?.with_epoch(args.epoch);
+            check_invite(args.invite.as_ref(), &encryption_key)?;
+            encryption_key
+        };
+        let mut notices = Vec::new();
+        if args.kdf != KdfParams::ChannelSalt {
+            notices.push(format!(
+                "Channel members need the same key derivation: --kdf {}",
+                args.kdf
+            ));
         }
+        let server_channel = if args.opaque_channel {
+            let server_channel = message::opaque_channel(&encryption_key, &channel);
+            notices.push(format!(
+                "Joined as channel {} on the server",
+                server_channel
+            ));
+            server_channel
+        } else {
+            channel.clone()
+        };
+        let server_uid = if args.pseudonym {
+            message::pseudonym(&encryption_key, &uid)
+        } else {
+            uid.clone()
+        };
+        let auth = AuthFrame::builder(&server_uid, &server_channel)
+            .mles_key_opt(mles_key)
+            .build();
+        let mut keyring = Keyring::new(encryption_key);
+        keyring.set_expiry(Duration::seconds(args.key_expiry.into()));
+        let replay_guard = ReplayGuard::new(
+            Duration::seconds(args.replay_window.into()),
+            Duration::seconds(args.max_clock_skew.into()),
+        );
 
-        if let Some(arg_channel) = args.channel {
-            channel = arg_channel;
+        let identity = if args.no_identity || args.legacy {
+            None
         } else {
-            print!("Channel: ");
-            stdout.flush().unwrap();
-            io::stdin().read_line(&mut channel).unwrap();
+            let path = args
+                .identity
+                .or_else(Identity::default_path)
+                .ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::NotFound, "no config directory for identity")
+                })?;
+            Some(Identity::load_or_generate(&path)?)
+        };
+        let trust_store = match TrustStore::default_path() {
+            Some(path) => TrustStore::load(&path)?,
+            None => TrustStore::in_memory(),
+        };
+
+        let mut client = MlesClient::connect(&args.server, auth, keyring).await?;
+        client.set_replay_guard(Some(replay_guard));
+        client.set_identity(identity);
+        client.set_padding(args.padding);
+        client.set_name(&uid);
+        client.set_legacy(args.legacy);
+        if args.ratchet {
+            client.enable_ratchet();
+        }
+        if let Some(pending) = pending {
+            let key = wait_for_key(&mut client, &channel, &pending, args.invite.as_ref()).await?;
+            client.set_key(key);
+        }
+        if let Some(Command::SendFile { path }) = args.command {
+            return send_file(client, &path).await;
+        }
+        let downloads = Downloads::new(args.download_dir.unwrap_or_else(Downloads::default_dir));
+        // Opaque channel names and pseudonyms are derived from the key the
+        // channel started with, which members joining later cannot know
+        let rekeyable = !args.opaque_channel && !args.pseudonym;
+        run_chat(
+            client,
+            channel,
+            args.kdf,
+            rekeyable,
+            notices,
+            trust_store,
+            downloads,
+        )
+        .await
+    }
+}
+
+/// Runs a keystore subcommand for `channel` on `server`
+fn run_keystore(
+    action: &KeystoreCommand,
+    path: &Path,
+    server: &str,
+    channel: Option<String>,
+) -> Result<()> {
+    match action {
+        KeystoreCommand::Add => {
+            let channel = prompt_if_missing(channel, "Channel: ")?;
+            let mut keystore = if path.exists() {
+                Keystore::open(path, &keystore_passphrase(false)?)?
+            } else {
+                let kdf = DEFAULT_KDF.parse().expect("default keystore KDF is valid");
+                Keystore::create(path, &keystore_passphrase(true)?, kdf)?
+            };
+            let optional =
+                |secret: Zeroizing<String>| (!secret.is_empty()).then(|| secret.to_string());
+            let entry = KeystoreEntry {
+                server: server.to_string(),
+                channel,
+                passphrase: optional(read_hidden("Shared key (empty for none): ")?),
+                mles_key: optional(read_hidden("MLES_KEY (empty for none): ")?),
+            };
+            println!("Stored secrets for {} on {}", entry.channel, server);
+            keystore.insert(entry);
+            keystore.save()
+        }
+        KeystoreCommand::List | KeystoreCommand::Remove if !path.exists() => {
+            println!("No keystore at {}", path.display());
+            Ok(())
+        }
+        KeystoreCommand::List => {
+            let keystore = Keystore::open(path, &keystore_passphrase(false)?)?;
+            for entry in keystore.entries() {
+                let secrets: Vec<_> = [
+                    entry.passphrase.as_ref().map(|_| "shared key"),
+                    entry.mles_key.as_ref().map(|_| "MLES_KEY"),
+                ]
+                .into_iter()
+                .flatten()
+                .collect();
+                println!("{} {}: {}", entry.server, entry.channel, secrets.join(", "));
+            }
+            Ok(())
         }
-        print!("Shared key: ");
-        stdout.flush().unwrap();
-        let key = read_password().unwrap();
-        let uid = uid.trim().to_string();
-        let channel = channel.trim().to_string();
-        let encryption_key = message::derive_key(&key, &channel);
-
-        let first_message = {
-            let mut hasher = SipHasher::new();
-            hasher.write(uid.as_bytes());
-            hasher.write(channel.as_bytes());
-
-            // If MLES_KEY exists, include it in the hash
-            if let Ok(mles_key) = env::var("MLES_KEY") {
-                hasher.write(mles_key.as_bytes());
+        KeystoreCommand::Remove => {
+            let channel = prompt_if_missing(channel, "Channel: ")?;
+            let mut keystore = Keystore::open(path, &keystore_passphrase(false)?)?;
+            if !keystore.remove(server, &channel) {
+                println!("No secrets stored for {} on {}", channel, server);
+                return Ok(());
             }
+            println!("Removed secrets for {} on {}", channel, server);
+            keystore.save()
+        }
+    }
+}
+
+/// Creates the duplicate tracker of the proxy modes
+fn dedup_tracker(ttl: u64, capacity: usize) -> MessageTracker {
+    let mut tracker = MessageTracker::new();
+    tracker.set_ttl(std::time::Duration::from_secs(ttl));
+    tracker.set_capacity(capacity);
+    tracker
+}
 
-            let hash = hasher.finish();
+/// Fails if `invite` carries a key fingerprint that `key` does not match
+fn check_invite(invite: Option<&Invite>, key: &ChannelKey) -> io::Result<()> {
+    if invite.is_some_and(|invite| !invite.matches(key)) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "shared key does not match the key fingerprint of the invite",
+        ));
+    }
+    Ok(())
+}
 
-            json!({
-                "uid": uid,
-                "channel": channel,
-                "auth": format!("{:016x}", hash)
-            })
-            .to_string()
+/// Asks the channel for its key and waits until a member approves the
+/// request and sends it
+async fn wait_for_key(
+    client: &mut MlesClient,
+    channel: &str,
+    pending: &PendingRequest,
+    invite: Option<&Invite>,
+) -> Result<ChannelKey> {
+    let request = pending.request();
+    client.request_key(&request).await?;
+    println!(
+        "Asked {} for the shared key with fingerprint {}",
+        channel,
+        request.fingerprint()
+    );
+    println!(
+        "Waiting for a member to check it with you and /approve {}",
+        request.uid
+    );
+    while let Some(incoming) = client.recv().await {
+        let Incoming::KeyGrant(grant) = incoming else {
+            continue;
+        };
+        let Some(key) = pending.open(channel, &grant) else {
+            continue;
         };
-        // Send first message
-        {
-            let mut write_guard = write.lock().await;
-            write_guard
-                .send(Message::Text(first_message.into()))
-                .await
-                .unwrap();
+        // Anyone can seal a key of their own to our request, so it is only
+        // taken if it matches the invite or the member confirms it
+        let fingerprint = message::key_fingerprint(&key);
+        let confirmed = match invite.and_then(|invite| invite.fingerprint.as_ref()) {
+            Some(expected) => *expected == fingerprint,
+            None => confirm(&format!(
+                "{} (unverified) sent a shared key with fingerprint {}. \
+                 Does it match the one the member you asked sees? [y/N] ",
+                grant.sender, fingerprint
+            ))?,
+        };
+        if confirmed {
+            println!("Accepted the shared key with fingerprint {}", fingerprint);
+            return Ok(key);
         }
+        println!("Ignored the shared key with fingerprint {}", fingerprint);
+    }
+    Err(io::Error::new(
+        io::ErrorKind::ConnectionAborted,
+        "connection closed before the shared key was sent",
+    )
+    .into())
+}
+
+/// Returns the shared key stored in the keystore or carried by the invite,
+/// or prompts for it
+fn shared_key(
+    stored: Option<&KeystoreEntry>,
+    invite: Option<&Invite>,
+) -> io::Result<Zeroizing<String>> {
+    let passphrase = stored
+        .and_then(|entry| entry.passphrase.as_deref())
+        .or_else(|| invite.and_then(|invite| invite.passphrase.as_deref()));
+    match passphrase {
+        Some(passphrase) => Ok(Zeroizing::new(passphrase.to_string())),
+        None => read_hidden("Shared key: "),
+    }
+}
+
+/// Prints an invite to `channel` on the server as a URI and a QR code
+fn create_invite(
+    args: &Args,
+    channel: &str,
+    key: &str,
+    mles_key: Option<String>,
+    include_key: bool,
+    expires_in: Option<u32>,
+) -> Result<()> {
+    let mut invite = Invite::new(&args.server, channel)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    invite.kdf = args.kdf;
+    invite.epoch = args.epoch;
+    invite.opaque_channel = args.opaque_channel;
+    invite.pseudonym = args.pseudonym;
+    invite.padding = args.padding;
+    invite.expires = expires_in.map(|secs| Utc::now() + Duration::seconds(secs.into()));
+    if include_key {
+        invite.passphrase = Some(key.to_string());
+        invite.mles_key = mles_key;
+    } else {
+        let encryption_key = ChannelKey::derive(key, channel, args.kdf)?;
+        invite.fingerprint = Some(message::key_fingerprint(&encryption_key));
+    }
 
-        // Initialize the UI immediately after connecting
-        {
-            let msgs = messages.lock().await;
-            let colors = user_colors.lock().await;
-            print_ui(&*msgs, &*colors, &uid);
+    let uri = Zeroizing::new(invite.to_string());
+    let code = QrCode::new(uri.as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+    // Light modules on dark, as most terminals are
+    let qr = Zeroizing::new(
+        code.render::<Dense1x2>()
+            .dark_color(Dense1x2::Light)
+            .light_color(Dense1x2::Dark)
+            .build(),
+    );
+    println!("{}\n\n{}", *uri, *qr);
+    if include_key {
+        println!("Anyone with this invite can read and write the channel.");
+    } else {
+        println!("Share the key separately; the invite only lets members check it.");
+    }
+    Ok(())
+}
+
+/// Reads the keystore passphrase from MLES_KEYSTORE_PASSPHRASE, or prompts
+/// for it, twice if `confirm` is set
+fn keystore_passphrase(confirm: bool) -> io::Result<Zeroizing<String>> {
+    if let Ok(passphrase) = env::var("MLES_KEYSTORE_PASSPHRASE") {
+        return Ok(Zeroizing::new(passphrase));
+    }
+    let passphrase = read_hidden("Keystore passphrase: ")?;
+    if confirm && *read_hidden("Repeat keystore passphrase: ")? != *passphrase {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "keystore passphrases do not match",
+        ));
+    }
+    Ok(passphrase)
+}
+
+fn read_hidden(prompt: &str) -> io::Result<Zeroizing<String>> {
+    print!("{}", prompt);
+    io::stdout().flush()?;
+    Ok(Zeroizing::new(read_password()?))
+}
+
+fn prompt_if_missing(value: Option<String>, prompt: &str) -> io::Result<String> {
+    if let Some(value) = value {
+        return Ok(value);
+    }
+    print!("{}", prompt);
+    io::stdout().flush()?;
+    let mut input = String::new();
+    io::stdin().read_line(&mut input)?;
+    Ok(input.trim().to_string())
+}
+
+/// Asks a yes or no question, defaulting to no
+fn confirm(prompt: &str) -> io::Result<bool> {
+    let answer = prompt_if_missing(None, prompt)?;
+    Ok(answer.eq_ignore_ascii_case("y") || answer.eq_ignore_ascii_case("yes"))
+}
+
+/// Sends a single file to the channel and disconnects
+async fn send_file(client: MlesClient, path: &Path) -> Result<()> {
+    let (mut sender, _receiver) = client.split();
+    let offer = sender.send_file(path).await?;
+    println!(
+        "Sent {} ({} bytes in {} chunks)",
+        offer.name, offer.size, offer.chunks
+    );
+    sender.close().await
+}
+
+async fn run_chat(
+    client: MlesClient,
+    channel: String,
+    kdf: KdfParams,
+    rekeyable: bool,
+    notices: Vec<String>,
+    mut trust_store: TrustStore,
+    mut downloads: Downloads,
+) -> Result<()> {
+    let uid = client.name().to_string();
+    let (mut sender, mut receiver) = client.split();
+    let public_key = sender.public_key();
+    let own_fingerprint = public_key.as_ref().map(identity::fingerprint);
+    let mut initial: Vec<_> = notices
+        .iter()
+        .map(|notice| UiMessage::from(ChatMessage::system(notice)))
+        .collect();
+    if let Some(fingerprint) = &own_fingerprint {
+        initial.push(UiMessage::from(ChatMessage::system(&format!(
+            "Signing messages as {}",
+            fingerprint
+        ))));
+    }
+    // Our own messages are marked verified like everyone else's, only while
+    // our key is the one pinned for our uid
+    if let Some(public_key) = &public_key {
+        match trust_store.check(&channel, &uid, public_key) {
+            TrustStatus::Known => {}
+            TrustStatus::New => trust_store.save()?,
+            TrustStatus::Changed { previous } => initial.push(UiMessage::alert(&format!(
+                "WARNING: {} is pinned to identity key {} on this channel, not to yours. \
+                 Use /trust {} to pin yours.",
+                uid, previous, uid
+            ))),
         }
+    }
+    let messages = Arc::new(Mutex::new(initial));
+    let messages_clone = Arc::clone(&messages);
+    let history = Arc::clone(&messages);
+    let user_colors = Arc::new(Mutex::new(HashMap::new()));
+    let trust_store = Arc::new(Mutex::new(trust_store));
+    let trust_store_clone = Arc::clone(&trust_store);
+    let key_requests = Arc::new(Mutex::new(HashMap::<String, KeyRequest>::new()));
+    let key_requests_clone = Arc::clone(&key_requests);
 
-        // Create a channel to signal program termination
-        let (shutdown_tx, mut shutdown_rx) = tokio::sync::mpsc::channel::<()>(1);
-        let shutdown_tx_clone = shutdown_tx.clone();
-
-        // Spawn a task to receive messages
-        let uid_clone = uid.clone();
-        let user_colors_clone = Arc::clone(&user_colors);
-        let message_handler = tokio::spawn(async move {
-            while let Some(Ok(msg)) = read.next().await {
-                if let Message::Binary(data) = msg {
-                    if let Some(decrypted) = message::decrypt_message(&encryption_key, &data) {
-                        let msg_hash = dupdet::hash_binary_message(decrypted.as_bytes());
-                        let mut tracker = message_tracker_clone.lock().await;
-                        if !tracker.is_duplicate(msg_hash) {
-                            let mut msgs = messages_clone.lock().await;
-                            let mut colors = user_colors_clone.lock().await;
-
-                            if let Ok(parsed) =
-                                serde_json::from_str::<serde_json::Value>(&decrypted)
-                            {
-                                if let Some(join_uid) = parsed.get("uid").and_then(|v| v.as_str()) {
-                                    if join_uid != uid_clone {
-                                        assign_color(&mut colors, join_uid);
-                                        msgs.push(format!("{} joined.", join_uid));
-                                    }
-                                }
-                            } else {
-                                let parts: Vec<&str> = decrypted.splitn(2, ' ').collect();
-                                if parts.len() == 2 {
-                                    let timestamp = parts[0];
-                                    let rest = parts[1];
-
-                                    if let Some((sender, message)) = rest.split_once(':') {
-                                        assign_color(&mut colors, sender);
-                                        msgs.push(format!("{} {}: {}", timestamp, sender, message));
-                                    }
-                                }
-                            }
-                            print_ui(&msgs, &colors, &uid_clone);
+    // Announce ourselves and our identity key to the channel
+    let join = match &public_key {
+        Some(public_key) => ChatMessage::join_with_key(&uid, public_key),
+        None => ChatMessage::join(&uid),
+    };
+    sender.send_message(&join).await?;
+    let sender = Arc::new(Mutex::new(sender)); // Share sender between tasks
+    let sender_clone = Arc::clone(&sender);
+
+    // Initialize the UI immediately after connecting
+    {
+        let msgs = messages.lock().await;
+        let colors = user_colors.lock().await;
+        print_ui(&msgs, &colors, &uid)?;
+    }
+
+    // Create a channel to signal program termination, carrying the error if any
+    let (shutdown_tx, mut shutdown_rx) = tokio::sync::mpsc::channel::<Option<Error>>(1);
+    let shutdown_tx_clone = shutdown_tx.clone();
+
+    // Spawn a task to receive messages
+    let uid_clone = uid.clone();
+    let channel_clone = channel.clone();
+    let user_colors_clone = Arc::clone(&user_colors);
+    let message_handler = tokio::spawn(async move {
+        let mut stale = 0;
+        let mut stale_notice = None;
+        let mut warned = HashSet::new();
+        while let Some(incoming) = receiver.next().await {
+            let (message, warning) = match incoming {
+                Incoming::Message { message, signer } => {
+                    // Our own join echoed back needs no notice
+                    if let ChatMessage::Join { sender, .. } = &message
+                        && *sender == uid_clone
+                    {
+                        continue;
+                    }
+                    let mut store = trust_store_clone.lock().await;
+                    match check_identity(&mut store, &channel_clone, &message, signer) {
+                        Ok((verified, warning)) => (
+                            UiMessage {
+                                verified,
+                                ..message.into()
+                            },
+                            // Each warning is shown once rather than with
+                            // every message
+                            warning
+                                .filter(|warning| warned.insert(warning.clone()))
+                                .map(|warning| UiMessage::alert(&warning)),
+                        ),
+                        Err(e) => {
+                            let _ = shutdown_tx.send(Some(e.into())).await;
+                            return;
+                        }
+                    }
+                }
+                // The server replays the channel history on join, so stale
+                // messages are counted in a single notice
+                Incoming::Replayed {
+                    reason: ReplayError::Stale,
+                    ..
+                } => {
+                    stale += 1;
+                    let notice = UiMessage::from(ChatMessage::system(&format!(
+                        "Skipped {} message(s) older than the replay window",
+                        stale
+                    )));
+                    let mut msgs = messages_clone.lock().await;
+                    match stale_notice {
+                        Some(index) => msgs[index] = notice,
+                        None => {
+                            stale_notice = Some(msgs.len());
+                            msgs.push(notice);
                         }
                     }
+                    let colors = user_colors_clone.lock().await;
+                    if let Err(e) = print_ui(&msgs, &colors, &uid_clone) {
+                        let _ = shutdown_tx.send(Some(e.into())).await;
+                        return;
+                    }
+                    continue;
                 }
+                Incoming::Replayed { sender, reason } => (
+                    UiMessage::from(ChatMessage::system(&format!(
+                        "Rejected message from {}: {}",
+                        sender, reason
+                    ))),
+                    None,
+                ),
+                Incoming::Payload {
+                    sender: Some(sender),
+                    content_type,
+                    payload,
+                    ..
+                } => match download_notice(&mut downloads, &sender, content_type, &payload) {
+                    Some(notice) => (notice, None),
+                    None => continue,
+                },
+                // Other payloads are for applications using the library
+                Incoming::Payload { .. } => continue,
+                Incoming::Rekeyed { sender, epoch } => (
+                    UiMessage::from(ChatMessage::system(&format!(
+                        "{} changed the shared key (epoch {})",
+                        sender, epoch
+                    ))),
+                    None,
+                ),
+                Incoming::KeyRequest(request) => {
+                    if request.uid == uid_clone {
+                        continue;
+                    }
+                    let notice = format!(
+                        "{} (unverified) asks for the shared key with fingerprint {}. \
+                         Check it with them, then /approve {}",
+                        request.uid,
+                        request.fingerprint(),
+                        request.uid
+                    );
+                    // Approving grants the latest request, so a replaced
+                    // one must stand out
+                    let replaced = key_requests_clone
+                        .lock()
+                        .await
+                        .insert(request.uid.clone(), request)
+                        .is_some();
+                    let message = if replaced {
+                        UiMessage::alert(&notice)
+                    } else {
+                        ChatMessage::system(&notice).into()
+                    };
+                    (message, None)
+                }
+                Incoming::KeyGrant(grant) => (
+                    UiMessage::from(ChatMessage::system(&format!(
+                        "{} (unverified) sent the shared key to {}",
+                        grant.sender, grant.uid
+                    ))),
+                    None,
+                ),
+            };
+
+            let mut msgs = messages_clone.lock().await;
+            let mut colors = user_colors_clone.lock().await;
+            if let Some(sender) = message.message.sender() {
+                assign_color(&mut colors, sender);
             }
-            // Connection closed
-            let _ = shutdown_tx.send(()).await;
-        });
+            msgs.extend(warning);
+            msgs.push(message);
+            if let Err(e) = print_ui(&msgs, &colors, &uid_clone) {
+                let _ = shutdown_tx.send(Some(e.into())).await;
+                return;
+            }
+        }
+        // Connection closed
+        let _ = shutdown_tx.send(receiver.take_error()).await;
+    });
 
-        let message_tracker_send = Arc::clone(&message_tracker);
-        // Input handling in a separate task
-        let input_handler = tokio::spawn(async move {
-            let mut input = String::new();
-
-            loop {
-                input.clear();
-                {
-                    let msgs = messages.lock().await;
-                    let colors = user_colors.lock().await;
-                    print_ui(&*msgs, &*colors, &uid); // Dereference the MutexGuards
-                } // Guards are dropped here
-                print!("\r> ");
-                io::stdout().flush().unwrap();
-
-                // Use tokio's stdin to make it cancellable
-                let mut line = String::new();
-                if let Ok(_) = tokio::io::AsyncBufReadExt::read_line(
-                    &mut tokio::io::BufReader::new(tokio::io::stdin()),
-                    &mut line,
-                )
-                .await
-                {
-                    let input = line.trim();
-                    if !input.is_empty() {
-                        let timestamp = get_timestamp();
-                        let formatted_message = format!("{} {}: {}", timestamp, uid, input);
-                        let msg_hash = dupdet::hash_binary_message(formatted_message.as_bytes());
-                        let mut tracker = message_tracker_send.lock().await;
-                        if !tracker.is_duplicate(msg_hash) {
-                            let mut msgs = messages.lock().await;
-                            msgs.push(format!("{} {}: {}", timestamp, uid, input));
-                            drop(msgs);
-
-                            let mut write_guard = write.lock().await;
-                            if let Err(e) = write_guard
-                                .send(Message::Binary(
-                                    message::encrypt_message(&encryption_key, &formatted_message)
-                                        .into(),
-                                ))
-                                .await
-                            {
-                                eprintln!("\nFailed to send message: {}", e);
-                                let _ = shutdown_tx_clone.send(()).await;
+    // Input handling in a separate task
+    let input_handler = tokio::spawn(async move {
+        loop {
+            let result = {
+                let msgs = messages.lock().await;
+                let colors = user_colors.lock().await;
+                print_ui(&msgs, &colors, &uid)
+            }; // Guards are dropped here
+            if let Err(e) = result {
+                let _ = shutdown_tx_clone.send(Some(e.into())).await;
+                break;
+            }
+
+            // Use tokio's stdin to make it cancellable
+            let mut line = Zeroizing::new(String::new());
+            if tokio::io::AsyncBufReadExt::read_line(
+                &mut tokio::io::BufReader::new(tokio::io::stdin()),
+                &mut line,
+            )
+            .await
+            .is_ok()
+            {
+                let input = line.trim();
+                if let Some(other) = input.strip_prefix("/verify ") {
+                    let store = trust_store.lock().await;
+                    let notice =
+                        verify_notice(&store, &channel, own_fingerprint.as_deref(), other.trim());
+                    messages.lock().await.push(notice);
+                } else if let Some(other) = input.strip_prefix("/trust ") {
+                    let other = other.trim();
+                    let mut store = trust_store.lock().await;
+                    let notice = match store.accept(&channel, other) {
+                        Some(fingerprint) => {
+                            if let Err(e) = store.save() {
+                                let _ = shutdown_tx_clone.send(Some(e.into())).await;
+                                break;
+                            }
+                            format!("Pinned identity key {} for {}", fingerprint, other)
+                        }
+                        None => format!("No changed identity key to trust for {}", other),
+                    };
+                    messages
+                        .lock()
+                        .await
+                        .push(ChatMessage::system(&notice).into());
+                } else if input.starts_with("/rekey ") && !rekeyable {
+                    messages.lock().await.push(
+                        ChatMessage::system(
+                            "The shared key cannot be changed with --opaque-channel or \
+                             --pseudonym, as members joining later would end up elsewhere",
+                        )
+                        .into(),
+                    );
+                } else if let Some(new_key) = input.strip_prefix("/rekey ") {
+                    let notice = match ChannelKey::derive(new_key.trim(), &channel, kdf) {
+                        Ok(key) => match sender.lock().await.rekey(key).await {
+                            Ok(epoch) => ChatMessage::system(&format!(
+                                "Changed the shared key (epoch {}). \
+                                 Members joining later need the new key with --epoch {}",
+                                epoch, epoch
+                            )),
+                            Err(e @ Error::Crypto(CryptoError::EpochsExhausted)) => {
+                                ChatMessage::system(&format!("Cannot change the shared key: {}", e))
+                            }
+                            Err(e) => {
+                                let _ = shutdown_tx_clone.send(Some(e)).await;
+                                break;
+                            }
+                        },
+                        Err(e) => ChatMessage::system(&format!("Cannot derive key: {}", e)),
+                    };
+                    messages.lock().await.push(notice.into());
+                } else if let Some(newcomer) = input.strip_prefix("/approve ") {
+                    let newcomer = newcomer.trim();
+                    let request = key_requests.lock().await.remove(newcomer);
+                    let notice = match request {
+                        Some(request) => match sender.lock().await.grant_key(&request).await {
+                            Ok(true) => format!(
+                                "Sent the shared key with fingerprint {} to {}. \
+                                 They accept it once you confirm the fingerprint with them",
+                                sender.lock().await.key_fingerprint(),
+                                newcomer
+                            ),
+                            Ok(false) => format!("Malformed key request from {}", newcomer),
+                            Err(e) => {
+                                let _ = shutdown_tx_clone.send(Some(e)).await;
                                 break;
                             }
+                        },
+                        None => format!("No key request from {}", newcomer),
+                    };
+                    messages
+                        .lock()
+                        .await
+                        .push(ChatMessage::system(&notice).into());
+                } else if let Some(path) = input.strip_prefix("/send ") {
+                    let path = path.trim();
+                    let notice = match sender.lock().await.send_file(Path::new(path)).await {
+                        Ok(offer) => {
+                            format!("Sent {} ({} bytes)", offer.name, offer.size)
+                        }
+                        Err(Error::Io(e)) => format!("Cannot send {}: {}", path, e),
+                        Err(e) => {
+                            let _ = shutdown_tx_clone.send(Some(e)).await;
+                            break;
+                        }
+                    };
+                    messages
+                        .lock()
+                        .await
+                        .push(ChatMessage::system(&notice).into());
+                } else if !input.is_empty() {
+                    let verified = own_fingerprint.is_some()
+                        && trust_store.lock().await.fingerprint(&channel, &uid)
+                            == own_fingerprint.as_deref();
+                    let mut sender_guard = sender.lock().await;
+                    match sender_guard.send(input).await {
+                        Ok(Some(message)) => {
+                            let mut msgs = messages.lock().await;
+                            msgs.push(UiMessage {
+                                verified,
+                                ..message.into()
+                            });
+                        }
+                        Ok(None) => {}
+                        Err(e) => {
+                            let _ = shutdown_tx_clone.send(Some(e)).await;
+                            break;
                         }
                     }
                 }
             }
-        });
+        }
+    });
 
-        // Wait for either task to finish
-        tokio::select! {
-            _ = shutdown_rx.recv() => {
-            }
-            _ = tokio::signal::ctrl_c() => {
-                // Send close frame
-                            let mut write_guard = write_clone.lock().await;
-                            let _ = write_guard.send(Message::Close(Some(CloseFrame {
-                                code: tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode::Normal,
-                                reason: "Client shutdown".into(),
-                            }))).await;
+    // Wait for either task to finish
+    let result = tokio::select! {
+        error = shutdown_rx.recv() => {
+            match error.flatten() {
+                Some(e) => Err(e),
+                None => Ok(()),
             }
         }
-        // Abort the tasks before cleanup
-        message_handler.abort();
-        input_handler.abort();
-
-        // Wait for tasks to finish
-        let _ = tokio::join!(message_handler, input_handler);
-
-        // Clean up and exit
-        execute!(
-            io::stdout(),
-            Clear(ClearType::All),
-            cursor::MoveTo(0, 0),
-            SetBackgroundColor(Color::Reset),
-            SetForegroundColor(Color::Reset)
-        )
-        .unwrap();
+        _ = tokio::signal::ctrl_c() => {
+            // Send leave notice and close frame
+            let mut sender_guard = sender_clone.lock().await;
+            let leave = ChatMessage::leave(sender_guard.name());
+            let _ = sender_guard.send_message(&leave).await;
+            let _ = sender_guard.close().await;
+            Ok(())
+        }
+    };
+    // Abort the tasks before cleanup
+    message_handler.abort();
+    input_handler.abort();
 
-        process::exit(0);
+    // Wait for tasks to finish
+    let _ = tokio::join!(message_handler, input_handler);
+
+    // Wipe the decrypted chat history
+    for message in history.lock().await.iter_mut() {
+        message.message.zeroize();
     }
-}
 
-fn get_timestamp() -> String {
-    let now = Utc::now();
-    now.format("%Y-%m-%dT%H:%M:%SZ").to_string()
+    // Clean up
+    execute!(
+        io::stdout(),
+        Clear(ClearType::All),
+        cursor::MoveTo(0, 0),
+        SetBackgroundColor(Color::Reset),
+        SetForegroundColor(Color::Reset)
+    )?;
+
+    result
 }
 
-fn format_timestamp(timestamp_str: &str) -> String {
-    // Parse ISO8601/RFC3339 UTC timestamp
-    if let Ok(utc_time) = DateTime::parse_from_rfc3339(timestamp_str) {
-        // Convert UTC to local time
-        let local_time: DateTime<Local> = DateTime::from(utc_time);
-        let today = Local::now().date_naive();
+/// Checks the key a message was signed with against the pins in `store`.
+/// Returns whether the sender is verified, and a warning if their key changed
+/// or a sender with a pinned key sent an unsigned message.
+fn check_identity(
+    store: &mut TrustStore,
+    channel: &str,
+    message: &ChatMessage,
+    signer: Option<PublicKey>,
+) -> io::Result<(bool, Option<String>)> {
+    let Some(sender) = message.sender() else {
+        return Ok((false, None));
+    };
+    let Some(signer) = signer else {
+        let warning = store.fingerprint(channel, sender).map(|_| {
+            format!(
+                "WARNING: {} sent unsigned messages although their identity key is pinned",
+                sender
+            )
+        });
+        return Ok((false, warning));
+    };
+    // A join may only announce the key it is signed with
+    if message
+        .public_key()
+        .is_some_and(|announced| announced != signer)
+    {
+        return Ok((false, None));
+    }
+    match store.check(channel, sender, &signer) {
+        TrustStatus::Known => Ok((true, None)),
+        TrustStatus::New => {
+            store.save()?;
+            Ok((true, None))
+        }
+        TrustStatus::Changed { previous } => {
+            let warning = format!(
+                "WARNING: identity key of {} changed from {} to {}. Their messages stay \
+                 unverified until you compare safety numbers with /verify {} and accept \
+                 the new key with /trust {}.",
+                sender,
+                previous,
+                identity::fingerprint(&signer),
+                sender,
+                sender
+            );
+            Ok((false, Some(warning)))
+        }
+    }
+}
 
-        if local_time.date_naive() == today {
-            // If message is from today, only show local time
-            local_time.format("%H:%M").to_string()
-        } else {
-            // If message is from another day, show local date and time
-            local_time.format("%Y-%m-%d %H:%M").to_string()
+/// Passes a file offer or chunk from `sender` to `downloads`, returning a
+/// notice for the UI when a download starts, finishes or fails
+fn download_notice(
+    downloads: &mut Downloads,
+    sender: &str,
+    content_type: ContentType,
+    payload: &[u8],
+) -> Option<UiMessage> {
+    let notice = match content_type {
+        ContentType::FileOffer => {
+            let offer = FileOffer::decode(payload)?;
+            let (name, size) = (offer.name.clone(), offer.size);
+            match downloads.offer(sender, offer) {
+                Ok(true) => format!("{} is sending {} ({} bytes)", sender, name, size),
+                Ok(false) => return None,
+                Err(e) => format!("Cannot receive {} from {}: {}", name, sender, e),
+            }
         }
+        ContentType::FileChunk => match downloads.chunk(sender, payload) {
+            Ok(Some(Download::Saved { sender, name, path })) => format!(
+                "Received {} from {}, saved to {}",
+                name,
+                sender,
+                path.display()
+            ),
+            Ok(Some(Download::Corrupt { sender, name })) => {
+                return Some(UiMessage::alert(&format!(
+                    "{} from {} failed verification and was discarded",
+                    name, sender
+                )));
+            }
+            Ok(None) => return None,
+            Err(e) => format!("Download failed: {}", e),
+        },
+        _ => return None,
+    };
+    Some(ChatMessage::system(&notice).into())
+}
+
+/// Builds the reply to `/verify <uid>`, for their changed key if they have
+/// one that is not trusted yet
+fn verify_notice(
+    store: &TrustStore,
+    channel: &str,
+    own_fingerprint: Option<&str>,
+    other: &str,
+) -> UiMessage {
+    let changed = store.changed(channel, other);
+    let theirs = changed.or_else(|| store.fingerprint(channel, other));
+    let body = match (own_fingerprint, theirs) {
+        (None, _) => "No local identity, restart without --no-identity to verify".to_string(),
+        (_, None) => format!("No identity key known for {}", other),
+        (Some(ours), Some(theirs)) if changed.is_some() => format!(
+            "Safety number with the new key of {}: {} (if it matches theirs, /trust {})",
+            other,
+            identity::safety_number(ours, theirs),
+            other
+        ),
+        (Some(ours), Some(theirs)) => format!(
+            "Safety number with {}: {} (compare it with {} in person or over another channel)",
+            other,
+            identity::safety_number(ours, theirs),
+            other
+        ),
+    };
+    ChatMessage::system(&body).into()
+}
+
+fn format_timestamp(utc_time: DateTime<Utc>) -> String {
+    // Convert UTC to local time
+    let local_time: DateTime<Local> = DateTime::from(utc_time);
+    let today = Local::now().date_naive();
+
+    if local_time.date_naive() == today {
+        // If message is from today, only show local time
+        local_time.format("%H:%M").to_string()
     } else {
-        // If parsing fails, return original timestamp
-        timestamp_str.to_string()
+        // If message is from another day, show local date and time
+        local_time.format("%Y-%m-%d %H:%M").to_string()
     }
 }
 
@@ -369,7 +1206,11 @@ fn assign_color(colors: &mut HashMap<String, Color>, uid: &str) {
     }
 }
 
-fn print_ui(messages: &Vec<String>, colors: &HashMap<String, Color>, own_uid: &str) {
+fn print_ui(
+    messages: &[UiMessage],
+    colors: &HashMap<String, Color>,
+    own_uid: &str,
+) -> io::Result<()> {
     let (_cols, rows) = size().unwrap_or((80, 24));
     let message_area = rows as usize - 2;
 
@@ -379,8 +1220,7 @@ fn print_ui(messages: &Vec<String>, colors: &HashMap<String, Color>, own_uid: &s
         cursor::MoveTo(0, 0),
         SetBackgroundColor(Color::Black),
         SetForegroundColor(Color::White)
-    )
-    .unwrap();
+    )?;
 
     let start_index = if messages.len() > message_area {
         messages.len() - message_area
@@ -388,56 +1228,62 @@ fn print_ui(messages: &Vec<String>, colors: &HashMap<String, Color>, own_uid: &s
         0
     };
 
-    for msg in &messages[start_index..] {
-        if let Some((timestamp_str, rest)) = msg.split_once(' ') {
-            let timestamp = format_timestamp(timestamp_str);
-
-            if rest.contains(':') {
-                if let Some((sender, message)) = rest.split_once(':') {
-                    let sender = sender.trim();
-                    let message = message.trim();
-
-                    if !sender.is_empty() {
-                        // Get color for sender (including own messages)
-                        let color = if sender == own_uid {
-                            colors.get(sender).unwrap_or(&Color::White)
-                        } else {
-                            colors.get(sender).unwrap_or(&Color::Grey)
-                        };
-
-                        // Print timestamp in neutral color
-                        execute!(io::stdout(), SetForegroundColor(Color::Grey)).unwrap();
-                        print!("{} ", timestamp);
-
-                        // Print sender in their color
-                        execute!(io::stdout(), SetForegroundColor(*color)).unwrap();
-                        print!("{}: ", sender);
-
-                        // Print message in default color
-                        execute!(io::stdout(), SetForegroundColor(Color::White)).unwrap();
-                        println!("{}", message);
-                    }
+    for UiMessage {
+        message: msg,
+        verified,
+        alert,
+    } in &messages[start_index..]
+    {
+        // Print timestamp in neutral color
+        execute!(io::stdout(), SetForegroundColor(Color::Grey))?;
+        print!("{} ", format_timestamp(msg.timestamp()));
+
+        match msg {
+            ChatMessage::Text { sender, body, .. } => {
+                // Get color for sender (including own messages)
+                let color = if sender == own_uid {
+                    colors.get(sender).unwrap_or(&Color::White)
+                } else {
+                    colors.get(sender).unwrap_or(&Color::Grey)
+                };
+
+                // Print sender in their color, marked by signature status
+                execute!(io::stdout(), SetForegroundColor(*color))?;
+                print!("{}", sender);
+                if *verified {
+                    execute!(io::stdout(), SetForegroundColor(Color::Green))?;
+                    print!(" ✓");
+                } else {
+                    execute!(io::stdout(), SetForegroundColor(Color::DarkGrey))?;
+                    print!(" ?");
                 }
-            } else {
-                // System messages (like join notifications)
-                if rest.contains("joined.") {
-                    if let Some(join_uid) = rest.split_whitespace().next() {
-                        if let Some(color) = colors.get(join_uid) {
-                            execute!(io::stdout(), SetForegroundColor(Color::Grey)).unwrap();
-                            print!("{} ", timestamp);
-                            execute!(io::stdout(), SetForegroundColor(*color)).unwrap();
-                            println!("{} joined.", join_uid);
-                            continue;
-                        }
-                    }
+                execute!(io::stdout(), SetForegroundColor(*color))?;
+                print!(": ");
+
+                // Print message in default color
+                execute!(io::stdout(), SetForegroundColor(Color::White))?;
+                println!("{}", body);
+            }
+            ChatMessage::Join { sender, .. } | ChatMessage::Leave { sender, .. } => {
+                let color = colors.get(sender).unwrap_or(&Color::Grey);
+                let action = if matches!(msg, ChatMessage::Join { .. }) {
+                    "joined"
+                } else {
+                    "left"
+                };
+                execute!(io::stdout(), SetForegroundColor(*color))?;
+                println!("{} {}.", sender, action);
+            }
+            ChatMessage::System { body, .. } => {
+                // System messages stay in neutral color unless they are warnings
+                if *alert {
+                    execute!(io::stdout(), SetForegroundColor(Color::Red))?;
                 }
-                // Default system message format
-                execute!(io::stdout(), SetForegroundColor(Color::Grey)).unwrap();
-                println!("{} {}", timestamp, rest);
+                println!("{}", body);
             }
         }
         // Reset color after each message
-        execute!(io::stdout(), SetForegroundColor(Color::White)).unwrap();
+        execute!(io::stdout(), SetForegroundColor(Color::White))?;
     }
 
     // Reset for input line
@@ -445,8 +1291,7 @@ fn print_ui(messages: &Vec<String>, colors: &HashMap<String, Color>, own_uid: &s
         io::stdout(),
         cursor::MoveTo(0, rows - 1),
         SetForegroundColor(Color::White)
-    )
-    .unwrap();
+    )?;
     print!("\r> ");
-    io::stdout().flush().unwrap();
+    io::stdout().flush()
 }
@@ -1,52 +1,712 @@
-use base64::{Engine as _, engine::general_purpose::STANDARD_NO_PAD};
-use blake2::{Blake2b512, Digest};
-use chacha20poly1305::{KeyInit, XChaCha20Poly1305, aead::Aead};
-use rand::{RngCore, rngs::OsRng};
-use scrypt::{
-    Scrypt,
-    password_hash::{PasswordHasher, SaltString},
+use crate::envelope::{
+    ChainPosition, ContentType, Envelope, FLAG_EPOCH, FLAG_PADDED, FLAG_RATCHET, FLAG_SIGNED,
+    FLAG_TYPED, Header, MAX_SENDER_LEN, NONCE_LEN,
+};
+use crate::error::{CryptoError, Result};
+use crate::identity::{Identity, PUBLIC_KEY_LENGTH, PublicKey, SIGNATURE_LENGTH};
+use crate::kdf::{ChannelKey, KdfParams};
+use crate::padding::{self, Padding};
+use crate::secret::SecretKey;
+use blake2::digest::Mac;
+use blake2::{Blake2b512, Blake2bMac512, Digest};
+use chacha20poly1305::{
+    KeyInit, XChaCha20Poly1305,
+    aead::{Aead, Payload},
 };
+use chrono::{DateTime, Utc};
+use ed25519_dalek::{Signature, VerifyingKey};
+use rand::{RngCore, rngs::OsRng};
+use zeroize::Zeroizing;
 
-// Derive a 256-bit encryption key from a password
-pub fn derive_key(password: &str, channel: &str) -> [u8; 32] {
+// Derive a 256-bit encryption key from a password with the default KDF,
+// see crate::kdf for configurable ones
+pub fn derive_key(password: &str, channel: &str) -> Result<SecretKey> {
     let mut hasher = Blake2b512::new();
     hasher.update(channel.as_bytes());
     let hash = hasher.finalize();
     let salt_bytes = &hash[..16];
 
-    let salt = SaltString::from_b64(&STANDARD_NO_PAD.encode(salt_bytes)).unwrap();
-    let password_hash = Scrypt
-        .hash_password(password.as_bytes(), salt.as_salt())
-        .unwrap();
+    // Recommended scrypt parameters, as earlier clients hashed with
+    let mut key = SecretKey::zeroed();
+    scrypt::scrypt(
+        password.as_bytes(),
+        salt_bytes,
+        &scrypt::Params::recommended(),
+        key.as_mut_bytes(),
+    )
+    .expect("32-byte output is valid for scrypt");
+    Ok(key)
+}
+
+// Derive the channel name sent to the server in place of `channel`, so that
+// only those holding the channel key can tell which channel it is
+pub fn opaque_channel(key: &ChannelKey, channel: &str) -> String {
+    keyed_id(key, b"mles channel id", channel)
+}
+
+// Derive the uid sent to the server and bound into envelopes in place of
+// `uid`. Chat messages still carry `uid`, which receivers check against the
+// pseudonym.
+pub fn pseudonym(key: &ChannelKey, uid: &str) -> String {
+    keyed_id(key, b"mles uid", uid)
+}
+
+// Derive a fingerprint of the channel key, for checking out of band that
+// two members derived the same key without revealing it.
+pub fn key_fingerprint(key: &ChannelKey) -> String {
+    keyed_id(key, b"mles key fingerprint", "")
+}
+
+// Hex of the first 16 bytes of Blake2bMac512(key, label | 0 | value)
+fn keyed_id(key: &ChannelKey, label: &[u8], value: &str) -> String {
+    let mut mac = <Blake2bMac512 as KeyInit>::new_from_slice(key.as_bytes())
+        .expect("32-byte keys are valid for Blake2b");
+    mac.update(label);
+    mac.update(&[0]);
+    mac.update(value.as_bytes());
+    mac.finalize().into_bytes()[..16]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Decrypted message with the sender uid authenticated by the envelope.
+/// Chat messages carry a `String` plaintext, other payloads raw bytes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Decrypted<P = String> {
+    /// Sender bound into the AEAD, None for unbound legacy messages
+    pub sender: Option<String>,
+    /// Identity key that signed the message, None for unsigned messages
+    pub signer: Option<PublicKey>,
+    /// Sender chain position for messages encrypted with a chain key
+    pub chain: Option<ChainPosition>,
+    /// Nonce the message was encrypted with
+    pub nonce: Vec<u8>,
+    /// Time a typed payload was sent, None for chat messages
+    pub timestamp: Option<DateTime<Utc>>,
+    pub content_type: ContentType,
+    pub plaintext: P,
+}
+
+impl Decrypted<Vec<u8>> {
+    /// Converts a decrypted chat message to text, returning None for other
+    /// content types or invalid UTF-8
+    pub fn into_text(self) -> Option<Decrypted> {
+        if self.content_type != ContentType::Chat {
+            return None;
+        }
+        Some(Decrypted {
+            sender: self.sender,
+            signer: self.signer,
+            chain: self.chain,
+            nonce: self.nonce,
+            timestamp: self.timestamp,
+            content_type: self.content_type,
+            plaintext: String::from_utf8(self.plaintext).ok()?,
+        })
+    }
+}
+
+// Encrypt a chat message using XChaCha20-Poly1305 into a versioned envelope,
+// binding the channel, sender, KDF parameters and key epoch as associated data.
+// The plaintext is padded inside the AEAD according to `padding`.
+pub fn encrypt_message(
+    key: &ChannelKey,
+    channel: &str,
+    sender: &str,
+    padding: Padding,
+    plaintext: &str,
+) -> Result<Vec<u8>> {
+    encrypt_bytes(
+        key,
+        channel,
+        sender,
+        None,
+        padding,
+        ContentType::Chat,
+        plaintext.as_bytes(),
+    )
+}
+
+// Encrypt a chat message like encrypt_message, additionally signing it with
+// the sender's identity inside the AEAD
+pub fn encrypt_signed_message(
+    key: &ChannelKey,
+    channel: &str,
+    sender: &str,
+    identity: &Identity,
+    padding: Padding,
+    plaintext: &str,
+) -> Result<Vec<u8>> {
+    encrypt_bytes(
+        key,
+        channel,
+        sender,
+        Some(identity),
+        padding,
+        ContentType::Chat,
+        plaintext.as_bytes(),
+    )
+}
 
-    let mut key = [0u8; 32];
-    key.copy_from_slice(password_hash.hash.unwrap().as_bytes());
-    key
+// Encrypt an arbitrary payload tagged with its content type, which is
+// encrypted along with it. Signed if an identity is given.
+pub fn encrypt_bytes(
+    key: &ChannelKey,
+    channel: &str,
+    sender: &str,
+    identity: Option<&Identity>,
+    padding: Padding,
+    content_type: ContentType,
+    payload: &[u8],
+) -> Result<Vec<u8>> {
+    let signed = if identity.is_some() { FLAG_SIGNED } else { 0 };
+    let header = Header {
+        flags: signed,
+        ..Header::current()
+    };
+    let envelope = unsealed(header, *key.kdf(), key.epoch(), sender, None)?;
+    seal(
+        key.as_bytes(),
+        envelope,
+        identity,
+        padding,
+        content_type,
+        channel,
+        payload,
+    )
 }
 
-// Encrypt a message using XChaCha20-Poly1305
-pub fn encrypt_message(key: &[u8; 32], plaintext: &str) -> Vec<u8> {
-    let cipher = XChaCha20Poly1305::new_from_slice(key).unwrap();
-    let mut nonce = [0u8; 24]; // 24 bytes for XChaCha20
+// Encrypt a message with the key at `chain` in the sender's key chain (see
+// crate::ratchet), signing it if an identity is given
+pub fn encrypt_chain_message(
+    message_key: &[u8; 32],
+    channel: &str,
+    sender: &str,
+    chain: ChainPosition,
+    identity: Option<&Identity>,
+    padding: Padding,
+    plaintext: &str,
+) -> Result<Vec<u8>> {
+    let signed = if identity.is_some() { FLAG_SIGNED } else { 0 };
+    let header = Header {
+        flags: FLAG_RATCHET | signed,
+        ..Header::current()
+    };
+    // Chain keys come from announcements rather than a KDF
+    let envelope = unsealed(header, KdfParams::ChannelSalt, 0, sender, Some(chain))?;
+    seal(
+        message_key,
+        envelope,
+        identity,
+        padding,
+        ContentType::Chat,
+        channel,
+        plaintext.as_bytes(),
+    )
+}
+
+// Encrypt a message into the headerless format understood by earlier clients
+pub fn encrypt_message_legacy(key: &[u8; 32], plaintext: &str) -> Result<Vec<u8>> {
+    let envelope = Envelope {
+        header: Header::legacy(),
+        kdf: KdfParams::ChannelSalt,
+        sender: None,
+        epoch: 0,
+        chain: None,
+        nonce: &[],
+        ciphertext: &[],
+    };
+    seal(
+        key,
+        envelope,
+        None,
+        Padding::None,
+        ContentType::Chat,
+        "",
+        plaintext.as_bytes(),
+    )
+}
+
+// Build a bound envelope with everything but the nonce and ciphertext
+fn unsealed<'a>(
+    header: Header,
+    kdf: KdfParams,
+    epoch: u32,
+    sender: &'a str,
+    chain: Option<ChainPosition>,
+) -> Result<Envelope<'a>> {
+    if sender.len() > MAX_SENDER_LEN {
+        return Err(CryptoError::InvalidSender.into());
+    }
+    let epoch_flag = if epoch != 0 { FLAG_EPOCH } else { 0 };
+    Ok(Envelope {
+        header: Header {
+            kdf: kdf.id(),
+            flags: header.flags | epoch_flag,
+            ..header
+        },
+        kdf,
+        sender: Some(sender),
+        epoch,
+        chain,
+        nonce: &[],
+        ciphertext: &[],
+    })
+}
+
+#[cfg(test)]
+thread_local! {
+    static FIXED_NONCE: std::cell::Cell<Option<[u8; NONCE_LEN]>> = const { std::cell::Cell::new(None) };
+}
+
+// Run `f` with every message encrypted under `nonce` instead of a random
+// one, to reproduce the published test vectors
+#[cfg(test)]
+pub(crate) fn with_fixed_nonce<T>(nonce: [u8; NONCE_LEN], f: impl FnOnce() -> T) -> T {
+    FIXED_NONCE.set(Some(nonce));
+    let result = f();
+    FIXED_NONCE.set(None);
+    result
+}
+
+// Random nonce for the next message, 24 bytes for XChaCha20
+fn fresh_nonce() -> [u8; NONCE_LEN] {
+    #[cfg(test)]
+    if let Some(nonce) = FIXED_NONCE.get() {
+        return nonce;
+    }
+    let mut nonce = [0u8; NONCE_LEN];
     OsRng.fill_bytes(&mut nonce);
+    nonce
+}
+
+// Encrypt `payload` into `envelope` under a fresh nonce
+fn seal(
+    key: &[u8; 32],
+    envelope: Envelope,
+    identity: Option<&Identity>,
+    padding: Padding,
+    content_type: ContentType,
+    channel: &str,
+    payload: &[u8],
+) -> Result<Vec<u8>> {
+    let cipher = XChaCha20Poly1305::new(key.into());
+    let nonce = fresh_nonce();
 
+    let mut envelope = Envelope {
+        nonce: &nonce,
+        ..envelope
+    };
+    if padding != Padding::None {
+        envelope.header.flags |= FLAG_PADDED;
+    }
+    let typed;
+    let mut message = payload;
+    if content_type != ContentType::Chat {
+        envelope.header.flags |= FLAG_TYPED;
+        let timestamp = Utc::now().timestamp().to_be_bytes();
+        typed = Zeroizing::new([&[content_type as u8], &timestamp[..], payload].concat());
+        message = &typed;
+    }
+    let aad = envelope.associated_data(channel);
+    // Plaintext copies are wiped as soon as the ciphertext exists
+    let msg = Zeroizing::new(match identity {
+        Some(identity) => {
+            let signature = identity.sign(&Zeroizing::new([&aad, message].concat()));
+            [
+                &identity.public_key()[..],
+                &signature.to_bytes()[..],
+                message,
+            ]
+            .concat()
+        }
+        None => message.to_vec(),
+    });
+    let msg = Zeroizing::new(padding.pad(&msg));
+    let payload = Payload {
+        msg: &msg,
+        aad: &aad,
+    };
     // Just pass &nonce directly - no XNonce creation needed!
-    let ciphertext = cipher.encrypt(&nonce.into(), plaintext.as_bytes()).unwrap();
+    let ciphertext = cipher
+        .encrypt(&nonce.into(), payload)
+        .map_err(|_| CryptoError::Encryption)?;
+    envelope.ciphertext = &ciphertext;
+
+    Ok(envelope.to_bytes())
+}
+
+// Decrypt a received chat message in either the versioned or the legacy
+// format. Bound messages only decrypt if they were sent on `channel` with a
+// key derived like `key` in the same epoch, and signed messages only if the
+// signature verifies.
+pub fn decrypt_message(key: &ChannelKey, channel: &str, encrypted: &[u8]) -> Option<Decrypted> {
+    decrypt_bytes(key, channel, encrypted)?.into_text()
+}
+
+// Decrypt a received chat message like decrypt_message, asking `key_for` for
+// the key of each candidate envelope. Chain messages need the key at their
+// position in the sender's chain.
+pub fn decrypt_message_with(
+    channel: &str,
+    encrypted: &[u8],
+    key_for: impl FnMut(&Envelope) -> Option<Zeroizing<[u8; 32]>>,
+) -> Option<Decrypted> {
+    decrypt_bytes_with(channel, encrypted, key_for)?.into_text()
+}
 
-    [nonce.to_vec(), ciphertext].concat()
+// Decrypt a received message of any content type, see decrypt_message
+pub fn decrypt_bytes(
+    key: &ChannelKey,
+    channel: &str,
+    encrypted: &[u8],
+) -> Option<Decrypted<Vec<u8>>> {
+    decrypt_bytes_with(channel, encrypted, |envelope| key.key_for(envelope))
 }
 
-// Decrypt a received message
-pub fn decrypt_message(key: &[u8; 32], encrypted: &[u8]) -> Option<String> {
-    if encrypted.len() < 24 {
-        return None;
+// Decrypt a received message of any content type, see decrypt_message_with
+pub fn decrypt_bytes_with(
+    channel: &str,
+    encrypted: &[u8],
+    mut key_for: impl FnMut(&Envelope) -> Option<Zeroizing<[u8; 32]>>,
+) -> Option<Decrypted<Vec<u8>>> {
+    Envelope::candidates(encrypted).find_map(|envelope| {
+        let cipher = XChaCha20Poly1305::new((&*key_for(&envelope)?).into());
+        let aad = envelope.associated_data(channel);
+        let payload = Payload {
+            msg: envelope.ciphertext,
+            aad: &aad,
+        };
+        // Just pass nonce directly with .into() - no XNonce creation needed!
+        // Only the returned plaintext outlives this closure unwiped
+        let decrypted = Zeroizing::new(cipher.decrypt(envelope.nonce.into(), payload).ok()?);
+        let mut bytes = &decrypted[..];
+        if envelope.header.is_padded() {
+            bytes = padding::unpad(bytes)?;
+        }
+        let mut signer = None;
+        if envelope.header.is_signed() {
+            let (public_key, rest) = bytes.split_first_chunk::<PUBLIC_KEY_LENGTH>()?;
+            let (signature, message) = rest.split_first_chunk::<SIGNATURE_LENGTH>()?;
+            VerifyingKey::from_bytes(public_key)
+                .ok()?
+                .verify_strict(
+                    &Zeroizing::new([&aad, message].concat()),
+                    &Signature::from_bytes(signature),
+                )
+                .ok()?;
+            signer = Some(*public_key);
+            bytes = message;
+        }
+        let mut content_type = ContentType::Chat;
+        let mut timestamp = None;
+        if envelope.header.is_typed() {
+            let (tag, rest) = bytes.split_first()?;
+            let (secs, rest) = rest.split_first_chunk::<8>()?;
+            content_type = ContentType::from_u8(*tag)?;
+            timestamp = Some(DateTime::from_timestamp(i64::from_be_bytes(*secs), 0)?);
+            bytes = rest;
+        }
+        Some(Decrypted {
+            sender: envelope.sender.map(str::to_string),
+            signer,
+            chain: envelope.chain,
+            nonce: envelope.nonce.to_vec(),
+            timestamp,
+            content_type,
+            plaintext: bytes.to_vec(),
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::envelope::HEADER_LEN;
+
+    #[test]
+    fn test_roundtrip_versioned_and_legacy() {
+        let key = ChannelKey::from(SecretKey::from([3u8; 32]));
+        let versioned = encrypt_message(&key, "ops", "alice", Padding::None, "hello").unwrap();
+        assert!(versioned.starts_with(&Header::current().to_bytes()));
+        let decrypted = decrypt_message(&key, "ops", &versioned).unwrap();
+        assert_eq!(decrypted.sender.as_deref(), Some("alice"));
+        assert_eq!(decrypted.plaintext, "hello");
+
+        let legacy = encrypt_message_legacy(key.as_bytes(), "hello").unwrap();
+        assert_eq!(legacy.len(), versioned.len() - HEADER_LEN - 1 - 5);
+        let decrypted = decrypt_message(&key, "ops", &legacy).unwrap();
+        assert_eq!(decrypted.sender, None);
+        assert_eq!(decrypted.plaintext, "hello");
+
+        assert_eq!(
+            decrypt_message(
+                &ChannelKey::from(SecretKey::from([4u8; 32])),
+                "ops",
+                &versioned
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn test_associated_data_mismatch_rejected() {
+        let key = ChannelKey::from(SecretKey::from([3u8; 32]));
+        let encrypted = encrypt_message(&key, "ops", "alice", Padding::None, "hello").unwrap();
+
+        // Replayed into another channel sharing the passphrase
+        assert_eq!(decrypt_message(&key, "dev", &encrypted), None);
+
+        // Sender rewritten in the envelope
+        let mut forged = encrypted.clone();
+        let sender_at = HEADER_LEN + 1;
+        forged[sender_at..sender_at + 5].copy_from_slice(b"mallo");
+        assert_eq!(decrypt_message(&key, "ops", &forged), None);
+
+        // Version downgraded to the unbound format
+        let mut downgraded = encrypted.clone();
+        downgraded[3] = crate::envelope::VERSION_UNBOUND;
+        assert_eq!(decrypt_message(&key, "ops", &downgraded), None);
+    }
+
+    #[test]
+    fn test_signed_roundtrip() {
+        let key = ChannelKey::from(SecretKey::from([3u8; 32]));
+        let identity = Identity::from_bytes(&[5u8; 32]);
+        let signed =
+            encrypt_signed_message(&key, "ops", "alice", &identity, Padding::None, "hello")
+                .unwrap();
+        let decrypted = decrypt_message(&key, "ops", &signed).unwrap();
+        assert_eq!(decrypted.signer, Some(identity.public_key()));
+        assert_eq!(decrypted.plaintext, "hello");
+
+        let unsigned = encrypt_message(&key, "ops", "alice", Padding::None, "hello").unwrap();
+        assert_eq!(
+            decrypt_message(&key, "ops", &unsigned).unwrap().signer,
+            None
+        );
+
+        // Someone holding the channel key re-encrypts a signed payload under
+        // another sender; the signature no longer covers the envelope
+        let envelope = Envelope::parse(&signed).unwrap();
+        let cipher = XChaCha20Poly1305::new(key.as_bytes().into());
+        let aad = envelope.associated_data("ops");
+        let inner = cipher
+            .decrypt(
+                envelope.nonce.into(),
+                Payload {
+                    msg: envelope.ciphertext,
+                    aad: &aad,
+                },
+            )
+            .unwrap();
+        let forged = Envelope {
+            sender: Some("mallory"),
+            ..envelope
+        };
+        let forged_aad = forged.associated_data("ops");
+        let ciphertext = cipher
+            .encrypt(
+                envelope.nonce.into(),
+                Payload {
+                    msg: &inner,
+                    aad: &forged_aad,
+                },
+            )
+            .unwrap();
+        let forged = Envelope {
+            ciphertext: &ciphertext,
+            ..forged
+        };
+        assert_eq!(decrypt_message(&key, "ops", &forged.to_bytes()), None);
     }
-    let (nonce, ciphertext) = encrypted.split_at(24);
-    let cipher = XChaCha20Poly1305::new_from_slice(key).unwrap();
 
-    // Just pass nonce directly with .into() - no XNonce creation needed!
-    cipher
-        .decrypt(nonce.into(), ciphertext)
-        .ok()
-        .and_then(|bytes| String::from_utf8(bytes).ok())
+    #[test]
+    fn test_bytes_roundtrip() {
+        let key = ChannelKey::from(SecretKey::from([3u8; 32]));
+        let identity = Identity::from_bytes(&[5u8; 32]);
+        let blob = [0xa1, 0x61, 0x74, 0xf9, 0x3c, 0x00, 0xff];
+        let encrypted = encrypt_bytes(
+            &key,
+            "sensors",
+            "probe",
+            Some(&identity),
+            Padding::Padme,
+            ContentType::Cbor,
+            &blob,
+        )
+        .unwrap();
+        let decrypted = decrypt_bytes(&key, "sensors", &encrypted).unwrap();
+        assert_eq!(decrypted.content_type, ContentType::Cbor);
+        assert_eq!(decrypted.plaintext, blob);
+        assert!(decrypted.timestamp.unwrap() <= Utc::now());
+        assert_eq!(decrypted.signer, Some(identity.public_key()));
+
+        // Only chat messages decrypt as text
+        assert_eq!(decrypt_message(&key, "sensors", &encrypted), None);
+        let chat = encrypt_message(&key, "sensors", "probe", Padding::None, "hi").unwrap();
+        let decrypted = decrypt_bytes(&key, "sensors", &chat).unwrap();
+        assert_eq!(decrypted.content_type, ContentType::Chat);
+        assert_eq!(decrypted.plaintext, b"hi");
+        assert_eq!(decrypted.timestamp, None);
+    }
+
+    #[test]
+    fn test_padding_hides_length() {
+        let key = ChannelKey::from(SecretKey::from([3u8; 32]));
+        let identity = Identity::from_bytes(&[5u8; 32]);
+        let short =
+            encrypt_signed_message(&key, "ops", "alice", &identity, Padding::PowerOfTwo, "hi")
+                .unwrap();
+        let long = encrypt_signed_message(
+            &key,
+            "ops",
+            "alice",
+            &identity,
+            Padding::PowerOfTwo,
+            "hello",
+        )
+        .unwrap();
+        assert_eq!(short.len(), long.len());
+        assert!(Envelope::parse(&short).unwrap().header.is_padded());
+
+        let decrypted = decrypt_message(&key, "ops", &short).unwrap();
+        assert_eq!(decrypted.plaintext, "hi");
+        assert_eq!(decrypted.signer, Some(identity.public_key()));
+
+        // Clearing the flag breaks the associated data
+        let mut stripped = short.clone();
+        stripped[6] &= !FLAG_PADDED;
+        assert_eq!(decrypt_message(&key, "ops", &stripped), None);
+    }
+
+    #[test]
+    fn test_kdf_params_must_match() {
+        let params = "scrypt:log_n=4,r=1,p=1,salt=AAAAAAAAAAAAAAAAAAAAAA"
+            .parse()
+            .unwrap();
+        let key = ChannelKey::derive("secret", "ops", params).unwrap();
+        let encrypted = encrypt_message(&key, "ops", "alice", Padding::None, "hello").unwrap();
+        assert_eq!(
+            decrypt_message(&key, "ops", &encrypted).unwrap().plaintext,
+            "hello"
+        );
+
+        // The same key bytes claimed for other parameters are not tried
+        let relabeled = ChannelKey::from(SecretKey::from(*key.as_bytes()));
+        assert_eq!(decrypt_message(&relabeled, "ops", &encrypted), None);
+    }
+
+    #[test]
+    fn test_chain_message_needs_chain_key() {
+        let (channel_key, message_key) = (ChannelKey::from(SecretKey::from([3u8; 32])), [6u8; 32]);
+        let chain = ChainPosition {
+            chain_id: [1u8; 16],
+            index: 7,
+        };
+        let encrypted = encrypt_chain_message(
+            &message_key,
+            "ops",
+            "alice",
+            chain,
+            None,
+            Padding::None,
+            "hello",
+        )
+        .unwrap();
+
+        // Not readable with the channel key alone
+        assert_eq!(decrypt_message(&channel_key, "ops", &encrypted), None);
+
+        let decrypted = decrypt_message_with("ops", &encrypted, |envelope| {
+            assert_eq!(envelope.chain, Some(chain));
+            Some(Zeroizing::new(message_key))
+        })
+        .unwrap();
+        assert_eq!(decrypted.chain, Some(chain));
+        assert_eq!(decrypted.plaintext, "hello");
+    }
+
+    #[test]
+    fn test_keyed_ids() {
+        let key = ChannelKey::from(SecretKey::from([3u8; 32]));
+        let id = opaque_channel(&key, "ops");
+        assert_eq!(id.len(), 32);
+        assert_eq!(id, opaque_channel(&key, "ops"));
+        assert_ne!(id, opaque_channel(&key, "dev"));
+        assert_ne!(id, pseudonym(&key, "ops"));
+        assert_ne!(key_fingerprint(&key), opaque_channel(&key, ""));
+        let other = ChannelKey::from(SecretKey::from([4u8; 32]));
+        assert_ne!(id, opaque_channel(&other, "ops"));
+    }
+
+    #[derive(serde::Deserialize)]
+    struct Vectors {
+        encryption: Vec<EncryptionVector>,
+    }
+
+    #[derive(serde::Deserialize)]
+    struct EncryptionVector {
+        description: String,
+        passphrase: String,
+        channel: String,
+        kdf: String,
+        epoch: u32,
+        key: String,
+        format: String,
+        sender: Option<String>,
+        padding: String,
+        nonce: String,
+        plaintext: String,
+        ciphertext: String,
+    }
+
+    fn unhex(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_published_vectors() {
+        let vectors: Vectors =
+            serde_json::from_str(include_str!("../test-vectors/mles.json")).unwrap();
+        for vector in vectors.encryption {
+            let kdf: KdfParams = vector.kdf.parse().unwrap();
+            let key = ChannelKey::derive(&vector.passphrase, &vector.channel, kdf)
+                .unwrap()
+                .with_epoch(vector.epoch);
+            assert_eq!(
+                key.as_bytes()[..],
+                unhex(&vector.key),
+                "{}",
+                vector.description
+            );
+
+            let nonce = unhex(&vector.nonce).try_into().unwrap();
+            let encrypted = with_fixed_nonce(nonce, || match vector.format.as_str() {
+                "legacy" => encrypt_message_legacy(key.as_bytes(), &vector.plaintext),
+                _ => encrypt_message(
+                    &key,
+                    &vector.channel,
+                    vector.sender.as_deref().unwrap(),
+                    vector.padding.parse().unwrap(),
+                    &vector.plaintext,
+                ),
+            })
+            .unwrap();
+            assert_eq!(
+                encrypted,
+                unhex(&vector.ciphertext),
+                "{}",
+                vector.description
+            );
+
+            let decrypted = decrypt_message(&key, &vector.channel, &encrypted).unwrap();
+            assert_eq!(decrypted.plaintext, vector.plaintext);
+            assert_eq!(decrypted.sender, vector.sender);
+        }
+    }
 }
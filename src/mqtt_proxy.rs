@@ -1,64 +1,46 @@
-use crate::dupdet::{MessageTracker, hash_binary_message};
+use crate::client::connect_channel;
+use crate::dupdet::MessageTracker;
+use crate::error::{Error, MqttError, Result};
+use crate::handshake::AuthFrame;
+use crate::proxy::{restore_tracker, save_tracker, snapshot_tracker};
+use crate::transport::Frame;
 use futures_util::{SinkExt, StreamExt};
 use rumqttc::{AsyncClient, Event, MqttOptions, Packet, QoS};
-use serde_json::json;
-use siphasher::sip::SipHasher;
-use std::env;
-use std::hash::Hasher;
 use std::io::Write;
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::time::Duration;
 use tokio::sync::Mutex;
-use tokio_tungstenite::{
-    connect_async, tungstenite::client::IntoClientRequest, tungstenite::protocol::Message,
-};
 use url::Url;
 
-use std::error::Error as StdError;
-use std::fmt;
-
-#[derive(Debug)]
-struct ProxyError(String);
-
-impl fmt::Display for ProxyError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.0)
-    }
-}
-
-impl StdError for ProxyError {}
-
 pub async fn run_mqtt_proxy(
     server: String,
     mqtt_server: String,
-    channel: String,
-    uid: String,
-) -> Result<(), Box<dyn std::error::Error>> {
+    auth: AuthFrame,
+    mut message_tracker: MessageTracker,
+    state: Option<PathBuf>,
+) -> Result<()> {
     let messages_mles_to_mqtt = Arc::new(AtomicU64::new(0));
     let messages_mqtt_to_mles = Arc::new(AtomicU64::new(0));
-    let message_tracker = Arc::new(Mutex::new(MessageTracker::new()));
+    if let Some(path) = &state {
+        restore_tracker(&mut message_tracker, path);
+    }
+    let message_tracker = Arc::new(Mutex::new(message_tracker));
 
     // Create clones for the stats task
     let messages_mles_to_mqtt_stats = Arc::clone(&messages_mles_to_mqtt);
     let messages_mqtt_to_mles_stats = Arc::clone(&messages_mqtt_to_mles);
     let server_stats = server.clone();
 
-    // Connect to Mles server
-    let mut request = server.clone().into_client_request()?;
-    request
-        .headers_mut()
-        .insert("Sec-WebSocket-Protocol", "mles-websocket".parse().unwrap());
-    let (ws_stream, _) = connect_async(request).await?;
-    let (write, mut read) = ws_stream.split();
+    // Connect and authenticate to Mles server
+    let (write, mut read) = connect_channel(&server, &auth).await?.split();
     let write = Arc::new(Mutex::new(write));
 
     // Setup MQTT connection
     println!("Connecting to MQTT broker {}...", mqtt_server);
-    let mqtt_url = Url::parse(&mqtt_server)?;
-    let host = mqtt_url
-        .host_str()
-        .ok_or_else(|| ProxyError("No host in MQTT URL".to_string()))?;
+    let mqtt_url = Url::parse(&mqtt_server).map_err(MqttError::Url)?;
+    let host = mqtt_url.host_str().ok_or(MqttError::MissingHost)?;
     let port = mqtt_url.port().unwrap_or(1883);
     println!("Resolved MQTT broker address: {}:{}", host, port);
 
@@ -90,48 +72,27 @@ pub async fn run_mqtt_proxy(
                     tokio::time::sleep(Duration::from_secs(2)).await;
                     continue;
                 } else {
-                    return Err(Box::new(ProxyError(
-                        "Failed to establish MQTT connection".to_string(),
-                    )));
+                    return Err(MqttError::Connection(Box::new(e)).into());
                 }
             }
         }
     }
 
+    let channel = auth.channel.clone();
     println!("Subscribing to MQTT topic '{}'", channel);
     match mqtt_client.subscribe(&channel, QoS::AtLeastOnce).await {
         Ok(_) => println!("Successfully subscribed to topic"),
         Err(e) => {
             println!("Failed to subscribe: {}", e);
-            return Err(Box::new(ProxyError(format!(
-                "MQTT subscription failed: {}",
-                e
-            ))));
+            return Err(MqttError::Client(e).into());
         }
     }
 
-    // Prepare authentication message
-    let auth_message = {
-        let mut hasher = SipHasher::new();
-        hasher.write(uid.as_bytes());
-        hasher.write(channel.as_bytes());
-        if let Ok(mles_key) = env::var("MLES_KEY") {
-            hasher.write(mles_key.as_bytes());
-        }
-        let hash = hasher.finish();
-        json!({
-            "uid": uid,
-            "channel": channel,
-            "auth": format!("{:016x}", hash)
-        })
-        .to_string()
-    };
-
-    write
-        .lock()
-        .await
-        .send(Message::Text(auth_message.into()))
-        .await?;
+    // Save the state periodically only once both sides are connected, so
+    // that a failed start leaves no task behind
+    let snapshot_task = state
+        .clone()
+        .map(|path| tokio::spawn(snapshot_tracker(Arc::clone(&message_tracker), path)));
 
     let write_clone = Arc::clone(&write);
     println!(
@@ -152,7 +113,7 @@ pub async fn run_mqtt_proxy(
                 server_stats,
                 messages_mqtt_to_mles_stats.load(Ordering::Relaxed),
             );
-            std::io::stdout().flush().unwrap();
+            let _ = std::io::stdout().flush();
             tokio::time::sleep(Duration::from_secs(5)).await;
         }
     });
@@ -162,42 +123,47 @@ pub async fn run_mqtt_proxy(
     let channel_clone = channel.clone();
     let message_tracker_clone1 = Arc::clone(&message_tracker);
     let mles_to_mqtt = tokio::spawn(async move {
-        while let Some(Ok(msg)) = read.next().await {
-            if let Message::Binary(data) = msg {
-                let msg_hash = hash_binary_message(&data);
-                let mut tracker = message_tracker_clone1.lock().await;
-                if !tracker.is_duplicate(msg_hash) {
+        while let Some(msg) = read.next().await {
+            if let Frame::Binary(data) = msg? {
+                let is_duplicate = message_tracker_clone1.lock().await.is_duplicate(&data);
+                if !is_duplicate {
                     mqtt_client_clone
                         .publish(&channel_clone, QoS::AtLeastOnce, false, data)
                         .await
-                        .map_err(|e| ProxyError(e.to_string()))?;
+                        .map_err(MqttError::Client)?;
                     messages_mles_to_mqtt_clone.fetch_add(1, Ordering::Relaxed);
                 }
             }
         }
         println!("\nMles to MQTT forwarding ended");
-        Ok::<(), ProxyError>(())
+        Ok::<(), Error>(())
     });
 
     let write_clone2 = Arc::clone(&write_clone);
     let messages_mqtt_to_mles_clone = Arc::clone(&messages_mqtt_to_mles);
     let message_tracker_clone2 = Arc::clone(&message_tracker);
     let mqtt_to_mles = tokio::spawn(async move {
-        let result: Result<(), ProxyError> = async {
+        let result: Result<()> = async {
             loop {
                 match eventloop.poll().await {
                     Ok(notification) => {
                         match notification {
                             Event::Incoming(Packet::Publish(msg)) => {
-                                let msg_hash = hash_binary_message(&msg.payload);
-                                let mut tracker = message_tracker_clone2.lock().await;
-                                if !tracker.is_duplicate(msg_hash) {
+                                let is_duplicate = message_tracker_clone2
+                                    .lock()
+                                    .await
+                                    .is_duplicate(&msg.payload);
+                                if !is_duplicate {
                                     let mut write = write_clone2.lock().await;
-                                    write
-                                        .send(Message::Binary(msg.payload))
-                                        .await
-                                        .map_err(|e| ProxyError(e.to_string()))?;
-                                    messages_mqtt_to_mles_clone.fetch_add(1, Ordering::Relaxed);
+                                    // A frame the server fails to take is
+                                    // dropped, as in crate::proxy::forward
+                                    match write.send(Frame::Binary(msg.payload)).await {
+                                        Ok(()) => {
+                                            messages_mqtt_to_mles_clone
+                                                .fetch_add(1, Ordering::Relaxed);
+                                        }
+                                        Err(e) => println!("\nFailed to forward message: {}", e),
+                                    }
                                 }
                             }
                             Event::Incoming(Packet::Disconnect) => {
@@ -246,25 +212,46 @@ pub async fn run_mqtt_proxy(
         }
     });
 
-    tokio::select! {
-        result = mles_to_mqtt => {
-            if let Err(e) = result {
-                println!("\nMles to MQTT error: {:?}", e);
-            } else {
+    let result = tokio::select! {
+        result = mles_to_mqtt => match result.unwrap_or_else(|e| Err(Error::Task(e))) {
+            Err(e) => {
+                println!("\nMles to MQTT error: {}", e);
+                Err(e)
+            }
+            Ok(()) => {
                 println!("\nMles to MQTT connection closed");
+                Ok(())
             }
         },
-        result = mqtt_to_mles => {
-            if let Err(e) = result {
-                println!("\nMQTT to Mles error: {:?}", e);
-            } else {
+        result = mqtt_to_mles => match result.unwrap_or_else(|e| Err(Error::Task(e))) {
+            Err(e) => {
+                println!("\nMQTT to Mles error: {}", e);
+                Err(e)
+            }
+            Ok(()) => {
                 println!("\nMQTT to Mles connection closed");
+                Ok(())
             }
         },
-        _ = ping_task => println!("\nPing task ended"),
-        _ = stats_task => println!("\nStats task ended"),
-        _ = tokio::signal::ctrl_c() => println!("\nReceived Ctrl+C"),
-    }
+        _ = ping_task => {
+            println!("\nPing task ended");
+            Ok(())
+        },
+        _ = stats_task => {
+            println!("\nStats task ended");
+            Ok(())
+        },
+        _ = tokio::signal::ctrl_c() => {
+            println!("\nReceived Ctrl+C");
+            Ok(())
+        },
+    };
 
-    Ok(())
+    if let Some(path) = &state {
+        save_tracker(&message_tracker, path).await;
+    }
+    if let Some(task) = snapshot_task {
+        task.abort();
+    }
+    result
 }
@@ -0,0 +1,124 @@
+//! Length-hiding padding applied to plaintexts inside the AEAD.
+//!
+//! A padded plaintext carries its real length in front and is filled up with
+//! zeros to the size picked by the [`Padding`] scheme:
+//!
+//! ```text
+//! length (4, big endian) | message | zeros
+//! ```
+//!
+//! Padded messages are marked with [`crate::envelope::FLAG_PADDED`], so
+//! receivers strip the padding whichever scheme the sender chose.
+
+use std::fmt;
+use std::str::FromStr;
+
+/// Length of the prefix holding the unpadded length
+pub const LENGTH_LEN: usize = 4;
+
+/// Scheme deciding how far plaintexts are padded
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Padding {
+    /// Plaintexts are sent as they are
+    #[default]
+    None,
+    /// Padmé: at most 12% overhead, leaking O(log log n) bits of the length
+    Padme,
+    /// Next power of two: at most 100% overhead, leaking O(log n) bits
+    PowerOfTwo,
+}
+
+impl Padding {
+    /// Returns the size `len` bytes are padded to
+    pub fn padded_len(&self, len: usize) -> usize {
+        match self {
+            Padding::None => len,
+            Padding::Padme => padme(len),
+            Padding::PowerOfTwo => len.next_power_of_two(),
+        }
+    }
+
+    /// Pads `msg`, or returns it unchanged for [`Padding::None`]
+    pub fn pad(&self, msg: &[u8]) -> Vec<u8> {
+        if *self == Padding::None {
+            return msg.to_vec();
+        }
+        let padded_len = self.padded_len(LENGTH_LEN + msg.len());
+        let mut padded = Vec::with_capacity(padded_len);
+        padded.extend_from_slice(&(msg.len() as u32).to_be_bytes());
+        padded.extend_from_slice(msg);
+        padded.resize(padded_len, 0);
+        padded
+    }
+}
+
+/// Strips the padding from a padded plaintext, returning None if the
+/// length prefix does not fit
+pub fn unpad(padded: &[u8]) -> Option<&[u8]> {
+    let (len, rest) = padded.split_first_chunk::<LENGTH_LEN>()?;
+    rest.get(..u32::from_be_bytes(*len) as usize)
+}
+
+// Round `len` up so that only the top O(log log len) bits are significant
+fn padme(len: usize) -> usize {
+    if len < 2 {
+        return len;
+    }
+    let exponent = len.ilog2();
+    let bits = exponent.ilog2() + 1;
+    let mask = (1usize << (exponent - bits)) - 1;
+    (len + mask) & !mask
+}
+
+impl fmt::Display for Padding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Padding::None => write!(f, "none"),
+            Padding::Padme => write!(f, "padme"),
+            Padding::PowerOfTwo => write!(f, "pow2"),
+        }
+    }
+}
+
+impl FromStr for Padding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Padding::None),
+            "padme" => Ok(Padding::Padme),
+            "pow2" => Ok(Padding::PowerOfTwo),
+            _ => Err(format!(
+                "unknown padding '{}', expected none, padme or pow2",
+                s
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_padded_lengths() {
+        assert_eq!(Padding::Padme.padded_len(100), 104);
+        assert_eq!(Padding::Padme.padded_len(1000), 1024);
+        assert_eq!(Padding::Padme.padded_len(1025), 1088);
+        assert_eq!(Padding::PowerOfTwo.padded_len(1025), 2048);
+        assert_eq!(Padding::None.padded_len(1025), 1025);
+
+        // Similar lengths end up in the same bucket
+        let short = Padding::PowerOfTwo.pad(b"{\"type\":\"join\"}");
+        let long = Padding::PowerOfTwo.pad(b"{\"type\":\"text\",\"x\":1}");
+        assert_eq!(short.len(), long.len());
+        assert_eq!(unpad(&short), Some(&b"{\"type\":\"join\"}"[..]));
+    }
+
+    #[test]
+    fn test_unpad_rejects_bad_length() {
+        assert_eq!(unpad(&[0, 0, 0, 9, 1, 2]), None);
+        assert_eq!(unpad(&[0, 0]), None);
+        assert_eq!(unpad(&[0, 0, 0, 0]), Some(&[][..]));
+    }
+}
@@ -1,114 +1,62 @@
-use crate::dupdet::{MessageTracker, hash_binary_message};
-use futures_util::{SinkExt, StreamExt};
-use serde_json::json;
-use siphasher::sip::SipHasher;
-use std::env;
-use std::hash::Hasher;
-use std::io::Write;
+use crate::client::connect_channel;
+use crate::dupdet::MessageTracker;
+use crate::error::{Error, Result};
+use crate::fsutil;
+use crate::handshake::AuthFrame;
+use crate::transport::Frame;
+use futures_util::{Sink, SinkExt, Stream, StreamExt};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::time::Duration;
 use tokio::sync::Mutex;
-use tokio_tungstenite::{
-    connect_async, tungstenite::client::IntoClientRequest, tungstenite::protocol::Message,
-};
+
+/// Interval the deduplication state is saved in
+const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(60);
 
 pub async fn run_proxy(
     server1: String,
     server2: String,
-    channel: String,
-    uid: String,
-) -> Result<(), Box<dyn std::error::Error>> {
+    auth: AuthFrame,
+    mut message_tracker: MessageTracker,
+    state: Option<PathBuf>,
+) -> Result<()> {
     // Add counters for messages and message tracker
     let messages_s1_to_s2 = Arc::new(AtomicU64::new(0));
     let messages_s2_to_s1 = Arc::new(AtomicU64::new(0));
-    let message_tracker = Arc::new(Mutex::new(MessageTracker::new()));
-
-    // Connect to first server
-    let mut request1 = server1.clone().into_client_request()?;
-    request1
-        .headers_mut()
-        .insert("Sec-WebSocket-Protocol", "mles-websocket".parse().unwrap());
-    let (ws_stream1, _) = connect_async(request1).await?;
-    let (write1, mut read1) = ws_stream1.split();
-    let write1 = Arc::new(Mutex::new(write1));
-
-    // Connect to second server
-    let mut request2 = server2.clone().into_client_request()?;
-    request2
-        .headers_mut()
-        .insert("Sec-WebSocket-Protocol", "mles-websocket".parse().unwrap());
-    let (ws_stream2, _) = connect_async(request2).await?;
-    let (write2, mut read2) = ws_stream2.split();
-    let write2 = Arc::new(Mutex::new(write2));
-
-    // Prepare authentication messages
-    let auth_message = {
-        let mut hasher = SipHasher::new();
-        hasher.write(uid.as_bytes());
-        hasher.write(channel.as_bytes());
-        if let Ok(mles_key) = env::var("MLES_KEY") {
-            hasher.write(mles_key.as_bytes());
-        }
-        let hash = hasher.finish();
-        json!({
-            "uid": uid,
-            "channel": channel,
-            "auth": format!("{:016x}", hash)
-        })
-        .to_string()
-    };
+    if let Some(path) = &state {
+        restore_tracker(&mut message_tracker, path);
+    }
+    let message_tracker = Arc::new(Mutex::new(message_tracker));
 
-    // Send auth messages to both servers
-    write1
-        .lock()
-        .await
-        .send(Message::Text(auth_message.clone().into()))
-        .await?;
-    write2
-        .lock()
-        .await
-        .send(Message::Text(auth_message.into()))
-        .await?;
+    // Connect and authenticate to both servers
+    let (write1, read1) = connect_channel(&server1, &auth).await?.split();
+    let (write2, read2) = connect_channel(&server2, &auth).await?.split();
 
-    let write1_clone = Arc::clone(&write1);
-    let write2_clone = Arc::clone(&write2);
+    // Save the state periodically only once both sides are connected, so
+    // that a failed start leaves no task behind
+    let snapshot_task = state
+        .clone()
+        .map(|path| tokio::spawn(snapshot_tracker(Arc::clone(&message_tracker), path)));
 
     println!("Proxy established between {} and {}", server1, server2);
 
-    let messages_s1_to_s2_clone = Arc::clone(&messages_s1_to_s2);
-    let message_tracker_clone1 = Arc::clone(&message_tracker);
     // Forward messages from server1 to server2
-    let task1 = tokio::spawn(async move {
-        while let Some(Ok(msg)) = read1.next().await {
-            if let Message::Binary(data) = msg {
-                let msg_hash = hash_binary_message(&data);
-                let mut tracker = message_tracker_clone1.lock().await;
-                if !tracker.is_duplicate(msg_hash) {
-                    let mut write2 = write2_clone.lock().await;
-                    messages_s1_to_s2_clone.fetch_add(1, Ordering::Relaxed);
-                    let _ = write2.send(Message::Binary(data)).await;
-                }
-            }
-        }
-    });
+    let task1 = tokio::spawn(forward(
+        read1,
+        write2,
+        Arc::clone(&message_tracker),
+        Arc::clone(&messages_s1_to_s2),
+    ));
 
-    let messages_s2_to_s1_clone = Arc::clone(&messages_s2_to_s1);
-    let message_tracker_clone2 = Arc::clone(&message_tracker);
     // Forward messages from server2 to server1
-    let task2 = tokio::spawn(async move {
-        while let Some(Ok(msg)) = read2.next().await {
-            if let Message::Binary(data) = msg {
-                let msg_hash = hash_binary_message(&data);
-                let mut tracker = message_tracker_clone2.lock().await;
-                if !tracker.is_duplicate(msg_hash) {
-                    let mut write1 = write1_clone.lock().await;
-                    messages_s2_to_s1_clone.fetch_add(1, Ordering::Relaxed);
-                    let _ = write1.send(Message::Binary(data)).await;
-                }
-            }
-        }
-    });
+    let task2 = tokio::spawn(forward(
+        read2,
+        write1,
+        Arc::clone(&message_tracker),
+        Arc::clone(&messages_s2_to_s1),
+    ));
 
     // Start statistics display task
     let stats_task = tokio::spawn(async move {
@@ -122,18 +70,155 @@ pub async fn run_proxy(
                 server1,
                 messages_s2_to_s1.load(Ordering::Relaxed),
             );
-            std::io::stdout().flush().unwrap();
+            let _ = std::io::stdout().flush();
             tokio::time::sleep(Duration::from_secs(5)).await;
         }
     });
 
     // Wait for either task to complete or Ctrl+C
-    tokio::select! {
-        _ = task1 => println!("Connection to server1 closed"),
-        _ = task2 => println!("Connection to server2 closed"),
-        _ = stats_task => println!("\nStats task ended"),
-        _ = tokio::signal::ctrl_c() => println!("Received Ctrl+C"),
+    let result = tokio::select! {
+        result = task1 => {
+            println!("\nConnection to server1 closed");
+            result.unwrap_or_else(|e| Err(Error::Task(e)))
+        },
+        result = task2 => {
+            println!("\nConnection to server2 closed");
+            result.unwrap_or_else(|e| Err(Error::Task(e)))
+        },
+        _ = stats_task => {
+            println!("\nStats task ended");
+            Ok(())
+        },
+        _ = tokio::signal::ctrl_c() => {
+            println!("Received Ctrl+C");
+            Ok(())
+        },
+    };
+
+    if let Some(path) = &state {
+        save_tracker(&message_tracker, path).await;
+    }
+    if let Some(task) = snapshot_task {
+        task.abort();
     }
+    result
+}
 
+/// Restores the tracker from the snapshot at `path`. A missing file starts
+/// empty, an unreadable one is reported and ignored.
+pub(crate) fn restore_tracker(message_tracker: &mut MessageTracker, path: &Path) {
+    match message_tracker.load(path) {
+        Ok(count) => println!("Restored {} seen messages from {}", count, path.display()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => println!("Ignoring deduplication state {}: {}", path.display(), e),
+    }
+}
+
+/// Saves the tracker to `path` every [`SNAPSHOT_INTERVAL`]
+pub(crate) async fn snapshot_tracker(message_tracker: Arc<Mutex<MessageTracker>>, path: PathBuf) {
+    loop {
+        tokio::time::sleep(SNAPSHOT_INTERVAL).await;
+        save_tracker(&message_tracker, &path).await;
+    }
+}
+
+/// Saves the tracker to `path`, reporting failures. Only encoding the
+/// snapshot holds the lock; the file is written on the blocking pool.
+pub(crate) async fn save_tracker(message_tracker: &Mutex<MessageTracker>, path: &Path) {
+    let snapshot = message_tracker.lock().await.snapshot();
+    let target = path.to_path_buf();
+    let written = tokio::task::spawn_blocking(move || fsutil::write_private(&target, &snapshot))
+        .await
+        .unwrap_or_else(|e| Err(io::Error::other(e)));
+    if let Err(e) = written {
+        println!(
+            "\nCannot save deduplication state {}: {}",
+            path.display(),
+            e
+        );
+    }
+}
+
+/// Forwards binary frames from `source` to `sink`, skipping frames already
+/// seen in either direction, until `source` closes. Frames the sink fails to
+/// take are reported and dropped.
+pub async fn forward<S, K>(
+    mut source: S,
+    mut sink: K,
+    message_tracker: Arc<Mutex<MessageTracker>>,
+    counter: Arc<AtomicU64>,
+) -> Result<()>
+where
+    S: Stream<Item = Result<Frame>> + Unpin,
+    K: Sink<Frame, Error = Error> + Unpin,
+{
+    while let Some(frame) = source.next().await {
+        if let Frame::Binary(data) = frame? {
+            let is_duplicate = message_tracker.lock().await.is_duplicate(&data);
+            if is_duplicate {
+                continue;
+            }
+            match sink.send(Frame::Binary(data)).await {
+                Ok(()) => {
+                    counter.fetch_add(1, Ordering::Relaxed);
+                }
+                Err(e) => println!("\nFailed to forward message: {}", e),
+            }
+        }
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::MemoryTransport;
+    use tokio_tungstenite::tungstenite::Bytes;
+
+    #[tokio::test]
+    async fn test_forward_skips_duplicates_and_text() {
+        let (mut server1, proxy1) = MemoryTransport::pair();
+        let (proxy2, mut server2) = MemoryTransport::pair();
+        let tracker = Arc::new(Mutex::new(MessageTracker::new()));
+        let counter = Arc::new(AtomicU64::new(0));
+
+        let (_, read1) = proxy1.split();
+        let (write2, _) = proxy2.split();
+        let task = tokio::spawn(forward(read1, write2, tracker, Arc::clone(&counter)));
+
+        let first = Frame::Binary(Bytes::from_static(b"first"));
+        let second = Frame::Binary(Bytes::from_static(b"second"));
+        server1.send(first.clone()).await.unwrap();
+        server1.send(Frame::Text("auth".to_string())).await.unwrap();
+        server1.send(first.clone()).await.unwrap();
+        server1.send(second.clone()).await.unwrap();
+        server1.close().await.unwrap();
+
+        task.await.unwrap().unwrap();
+        assert_eq!(server2.next().await.unwrap().unwrap(), first);
+        assert_eq!(server2.next().await.unwrap().unwrap(), second);
+        assert_eq!(counter.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn test_forward_survives_send_errors() {
+        let (mut server1, proxy1) = MemoryTransport::pair();
+        let (proxy2, server2) = MemoryTransport::pair();
+        drop(server2);
+        let tracker = Arc::new(Mutex::new(MessageTracker::new()));
+        let counter = Arc::new(AtomicU64::new(0));
+
+        let (_, read1) = proxy1.split();
+        let (write2, _) = proxy2.split();
+        let task = tokio::spawn(forward(read1, write2, tracker, Arc::clone(&counter)));
+
+        server1
+            .send(Frame::Binary(Bytes::from_static(b"lost")))
+            .await
+            .unwrap();
+        server1.close().await.unwrap();
+
+        task.await.unwrap().unwrap();
+        assert_eq!(counter.load(Ordering::Relaxed), 0);
+    }
+}
@@ -0,0 +1,440 @@
+//! Sender-key ratchet giving chat messages forward secrecy.
+//!
+//! Each participant sending in ratchet mode picks a random chain key and
+//! announces it, together with a random chain id, in a [`ChainAnnouncement`]
+//! encrypted under the channel key. Every message advances the chain one step:
+//!
+//! ```text
+//! Blake2bMac512(key = chain key, "mles chain step") = next chain key (32) | message key (32)
+//! ```
+//!
+//! Chain keys are overwritten as the chain advances and message keys are
+//! deleted once used, so a client compromised later cannot decrypt messages
+//! it already processed. Announcements are only as safe as the channel key:
+//! someone holding the passphrase and a recording of an announcement can
+//! still follow that chain. Chains are therefore replaced regularly, see
+//! [`CHAIN_LENGTH`]. A replaced chain of another sender is kept for
+//! [`RETIRED_CHAIN_LIFETIME`] so that its messages arriving late still
+//! decrypt, up to [`MAX_RETIRED_CHAINS`] per sender.
+
+use crate::envelope::{CHAIN_ID_LEN, ChainPosition};
+use base64::{Engine as _, engine::general_purpose::STANDARD};
+use blake2::Blake2bMac512;
+use blake2::digest::{KeyInit, Mac};
+use chrono::{DateTime, Duration, SubsecRound, Utc};
+use rand::{RngCore, rngs::OsRng};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use zeroize::{Zeroize, Zeroizing};
+
+/// Number of messages sent on a chain before it is replaced by a new one
+pub const CHAIN_LENGTH: u32 = 1000;
+
+/// Maximum number of message keys skipped over and kept for late messages
+pub const MAX_SKIP: u32 = 1000;
+
+/// Time a replaced receiving chain is kept for late messages
+pub const RETIRED_CHAIN_LIFETIME: Duration = Duration::hours(1);
+
+/// Maximum number of replaced receiving chains kept per sender
+pub const MAX_RETIRED_CHAINS: usize = 4;
+
+/// Key zeroized when dropped or overwritten by the next one
+type Key = Zeroizing<[u8; 32]>;
+
+/// Advances `chain_key` by one step, returning the next chain key and the
+/// message key for the current position
+fn step(chain_key: &[u8; 32]) -> (Key, Key) {
+    let mut mac = <Blake2bMac512 as KeyInit>::new_from_slice(chain_key)
+        .expect("32-byte keys are valid for Blake2b");
+    mac.update(b"mles chain step");
+    let mut output = mac.finalize().into_bytes();
+    let mut next = Key::default();
+    let mut message_key = Key::default();
+    next.copy_from_slice(&output[..32]);
+    message_key.copy_from_slice(&output[32..]);
+    output.as_mut_slice().zeroize();
+    (next, message_key)
+}
+
+/// Announcement of a sender chain, sent encrypted under the channel key.
+///
+/// Encoded like chat messages, e.g.
+/// `{"type":"senderkey","sender":"alice","timestamp":...,"chain_id":...,"index":0,"chain_key":...}`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename = "senderkey")]
+pub struct ChainAnnouncement {
+    pub sender: String,
+    pub timestamp: DateTime<Utc>,
+    /// Base64 chain id
+    pub chain_id: String,
+    /// Chain index the announced key is at
+    pub index: u32,
+    /// Base64 chain key
+    pub chain_key: Zeroizing<String>,
+}
+
+impl ChainAnnouncement {
+    /// Encodes the announcement into the plaintext that gets encrypted
+    pub fn encode(&self) -> String {
+        serde_json::to_string(self).expect("ChainAnnouncement serialization cannot fail")
+    }
+
+    /// Decodes a decrypted plaintext, returning None if it is not an announcement
+    pub fn decode(plaintext: &str) -> Option<Self> {
+        serde_json::from_str(plaintext).ok()
+    }
+
+    fn chain(&self) -> Option<([u8; CHAIN_ID_LEN], Key)> {
+        let chain_id = STANDARD.decode(&self.chain_id).ok()?.try_into().ok()?;
+        let decoded = Zeroizing::new(STANDARD.decode(&self.chain_key).ok()?);
+        let mut chain_key = Key::default();
+        *chain_key = decoded[..].try_into().ok()?;
+        Some((chain_id, chain_key))
+    }
+}
+
+/// Chain this client sends on
+struct SendingChain {
+    chain_id: [u8; CHAIN_ID_LEN],
+    index: u32,
+    chain_key: Key,
+}
+
+impl SendingChain {
+    fn generate() -> Self {
+        let mut chain_id = [0u8; CHAIN_ID_LEN];
+        let mut chain_key = Key::default();
+        OsRng.fill_bytes(&mut chain_id);
+        OsRng.fill_bytes(&mut *chain_key);
+        Self {
+            chain_id,
+            index: 0,
+            chain_key,
+        }
+    }
+
+    fn announcement(&self, sender: &str) -> ChainAnnouncement {
+        ChainAnnouncement {
+            sender: sender.to_string(),
+            timestamp: Utc::now().trunc_subsecs(0),
+            chain_id: STANDARD.encode(self.chain_id),
+            index: self.index,
+            chain_key: Zeroizing::new(STANDARD.encode(*self.chain_key)),
+        }
+    }
+
+    fn next_key(&mut self) -> (ChainPosition, Key) {
+        let position = ChainPosition {
+            chain_id: self.chain_id,
+            index: self.index,
+        };
+        let (next, message_key) = step(&self.chain_key);
+        self.chain_key = next;
+        self.index += 1;
+        (position, message_key)
+    }
+}
+
+/// Chain announced by another participant
+struct ReceivingChain {
+    chain_id: [u8; CHAIN_ID_LEN],
+    announced_at: DateTime<Utc>,
+    /// Index of the next unused chain key
+    index: u32,
+    chain_key: Key,
+    /// Message keys skipped over, kept for messages arriving late
+    skipped: BTreeMap<u32, Key>,
+}
+
+impl ReceivingChain {
+    /// Returns the message key for `index` without advancing the chain
+    fn message_key(&self, index: u32) -> Option<Key> {
+        if index < self.index {
+            return self.skipped.get(&index).cloned();
+        }
+        if index - self.index >= MAX_SKIP {
+            return None;
+        }
+        let mut chain_key = self.chain_key.clone();
+        for _ in self.index..index {
+            chain_key = step(&chain_key).0;
+        }
+        Some(step(&chain_key).1)
+    }
+
+    /// Deletes the message key for `index` once its message was decrypted,
+    /// advancing the chain past it
+    fn commit(&mut self, index: u32) {
+        if index < self.index {
+            self.skipped.remove(&index);
+            return;
+        }
+        while self.index <= index {
+            let (next, message_key) = step(&self.chain_key);
+            if self.index < index {
+                self.skipped.insert(self.index, message_key);
+            }
+            self.chain_key = next;
+            self.index += 1;
+        }
+        // Forget the oldest skipped keys
+        while self.skipped.len() > MAX_SKIP as usize {
+            self.skipped.pop_first();
+        }
+    }
+}
+
+/// Receiving chain replaced by a newer one of the same sender
+struct RetiredChain {
+    sender: String,
+    retired_at: DateTime<Utc>,
+    chain: ReceivingChain,
+}
+
+/// Sending chain of this client and the receiving chains of other senders
+#[derive(Default)]
+pub struct Ratchet {
+    sending: Option<SendingChain>,
+    announce_pending: bool,
+    receiving: HashMap<String, ReceivingChain>,
+    retired: Vec<RetiredChain>,
+}
+
+impl Ratchet {
+    /// Starts sending in ratchet mode on a fresh chain
+    pub fn enable(&mut self) {
+        self.sending = Some(SendingChain::generate());
+        self.announce_pending = true;
+    }
+
+    /// Returns true if messages are sent in ratchet mode
+    pub fn is_enabled(&self) -> bool {
+        self.sending.is_some()
+    }
+
+    /// Asks for the sending chain to be announced again before the next
+    /// message, e.g. because a new participant joined
+    pub fn request_announcement(&mut self) {
+        self.announce_pending = true;
+    }
+
+    /// Returns the position and key for the next message from `sender`, with
+    /// an announcement to send first if needed. Returns None unless enabled.
+    pub fn next_send(
+        &mut self,
+        sender: &str,
+    ) -> Option<(Option<ChainAnnouncement>, ChainPosition, Key)> {
+        let chain = self.sending.as_mut()?;
+        if chain.index >= CHAIN_LENGTH {
+            *chain = SendingChain::generate();
+            self.announce_pending = true;
+        }
+        let announcement =
+            std::mem::take(&mut self.announce_pending).then(|| chain.announcement(sender));
+        let (position, message_key) = chain.next_key();
+        Some((announcement, position, message_key))
+    }
+
+    /// Installs a chain announced by another participant. Returns false if
+    /// the announcement is malformed, repeats the current chain, or is older
+    /// than it. The oldest retired chains of the sender beyond
+    /// [`MAX_RETIRED_CHAINS`] are deleted.
+    pub fn install(&mut self, announcement: &ChainAnnouncement) -> bool {
+        let Some((chain_id, chain_key)) = announcement.chain() else {
+            return false;
+        };
+        if let Some(current) = self.receiving.get(&announcement.sender)
+            && (current.chain_id == chain_id || current.announced_at > announcement.timestamp)
+        {
+            return false;
+        }
+        let replaced = self.receiving.insert(
+            announcement.sender.clone(),
+            ReceivingChain {
+                chain_id,
+                announced_at: announcement.timestamp,
+                index: announcement.index,
+                chain_key,
+                skipped: BTreeMap::new(),
+            },
+        );
+        if let Some(chain) = replaced {
+            self.retired.push(RetiredChain {
+                sender: announcement.sender.clone(),
+                retired_at: Utc::now(),
+                chain,
+            });
+            let mut kept = self
+                .retired
+                .iter()
+                .filter(|retired| retired.sender == announcement.sender)
+                .count();
+            // Retired chains are in the order they were replaced
+            self.retired.retain(|retired| {
+                if kept > MAX_RETIRED_CHAINS && retired.sender == announcement.sender {
+                    kept -= 1;
+                    return false;
+                }
+                true
+            });
+        }
+        true
+    }
+
+    /// Deletes replaced chains older than [`RETIRED_CHAIN_LIFETIME`]
+    pub fn expire(&mut self) {
+        self.expire_at(Utc::now());
+    }
+
+    fn expire_at(&mut self, now: DateTime<Utc>) {
+        self.retired
+            .retain(|retired| now - retired.retired_at <= RETIRED_CHAIN_LIFETIME);
+    }
+
+    /// Returns the current or retired chain of `sender` with `chain_id`
+    fn chain(&self, sender: &str, chain_id: &[u8; CHAIN_ID_LEN]) -> Option<&ReceivingChain> {
+        self.receiving
+            .get(sender)
+            .into_iter()
+            .chain(
+                self.retired
+                    .iter()
+                    .filter(|retired| retired.sender == sender)
+                    .map(|retired| &retired.chain),
+            )
+            .find(|chain| chain.chain_id == *chain_id)
+    }
+
+    /// Returns the key for a message from `sender` at `position`, without
+    /// deleting it; call [`Ratchet::commit`] once the message decrypted
+    pub fn message_key(
+        &self,
+        sender: &str,
+        position: &ChainPosition,
+    ) -> Option<Zeroizing<[u8; 32]>> {
+        self.chain(sender, &position.chain_id)?
+            .message_key(position.index)
+    }
+
+    /// Deletes the key for a decrypted message from `sender` at `position`
+    pub fn commit(&mut self, sender: &str, position: &ChainPosition) {
+        let current = self.receiving.get_mut(sender).into_iter();
+        let retired = self
+            .retired
+            .iter_mut()
+            .filter(|retired| retired.sender == sender)
+            .map(|retired| &mut retired.chain);
+        if let Some(chain) = current
+            .chain(retired)
+            .find(|chain| chain.chain_id == position.chain_id)
+        {
+            chain.commit(position.index);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn connected() -> (Ratchet, Ratchet) {
+        let mut alice = Ratchet::default();
+        alice.enable();
+        (alice, Ratchet::default())
+    }
+
+    #[test]
+    fn test_keys_follow_announced_chain() {
+        let (mut alice, mut bob) = connected();
+
+        let (announcement, first, first_key) = alice.next_send("alice").unwrap();
+        let announcement = ChainAnnouncement::decode(&announcement.unwrap().encode()).unwrap();
+        assert!(bob.install(&announcement));
+        assert_eq!(bob.message_key("alice", &first), Some(first_key.clone()));
+
+        let (announcement, second, second_key) = alice.next_send("alice").unwrap();
+        assert!(announcement.is_none());
+        assert_ne!(first_key, second_key);
+        bob.commit("alice", &first);
+        assert_eq!(bob.message_key("alice", &second), Some(second_key));
+
+        // Chat messages are not announcements
+        let text = crate::chat::ChatMessage::text("alice", "hi").encode();
+        assert_eq!(ChainAnnouncement::decode(&text), None);
+
+        // Used keys are gone
+        assert_eq!(bob.message_key("alice", &first), None);
+        assert_eq!(bob.message_key("bob", &first), None);
+    }
+
+    #[test]
+    fn test_out_of_order_and_skipped() {
+        let (mut alice, mut bob) = connected();
+        let sent: Vec<_> = (0..4).map(|_| alice.next_send("alice").unwrap()).collect();
+        assert!(bob.install(sent[0].0.as_ref().unwrap()));
+
+        // Message 3 arrives first, keys for 0..3 are kept
+        bob.commit("alice", &sent[3].1);
+        assert_eq!(bob.message_key("alice", &sent[3].1), None);
+        for (_, position, key) in &sent[..3] {
+            assert_eq!(bob.message_key("alice", position).as_ref(), Some(key));
+            bob.commit("alice", position);
+            assert_eq!(bob.message_key("alice", position), None);
+        }
+
+        // Positions too far ahead are refused
+        let far = ChainPosition {
+            index: 4 + MAX_SKIP,
+            ..sent[0].1
+        };
+        assert_eq!(bob.message_key("alice", &far), None);
+    }
+
+    #[test]
+    fn test_rotation_and_stale_announcements() {
+        let (mut alice, mut bob) = connected();
+        let (first, ..) = alice.next_send("alice").unwrap();
+        let mut first = first.unwrap();
+        first.timestamp -= Duration::minutes(1);
+        assert!(bob.install(&first));
+        assert!(!bob.install(&first));
+
+        alice.sending.as_mut().unwrap().index = CHAIN_LENGTH;
+        let (second, position, key) = alice.next_send("alice").unwrap();
+        let second = second.unwrap();
+        assert_ne!(second.chain_id, first.chain_id);
+        assert!(bob.install(&second));
+        assert_eq!(bob.message_key("alice", &position), Some(key.clone()));
+
+        // A replayed announcement of the old chain does not displace the new one
+        assert!(!bob.install(&first));
+
+        // Messages of the old chain arriving late still decrypt until it expires
+        let late = ChainPosition {
+            chain_id: STANDARD
+                .decode(&first.chain_id)
+                .unwrap()
+                .try_into()
+                .unwrap(),
+            index: 5,
+        };
+        assert!(bob.message_key("alice", &late).is_some());
+        bob.commit("alice", &late);
+        assert!(bob.message_key("alice", &late).is_none());
+        let skipped = ChainPosition { index: 3, ..late };
+        assert!(bob.message_key("alice", &skipped).is_some());
+        bob.expire_at(Utc::now() + RETIRED_CHAIN_LIFETIME + Duration::seconds(1));
+        assert!(bob.message_key("alice", &skipped).is_none());
+        assert_eq!(bob.message_key("alice", &position), Some(key));
+
+        // A sender announcing chain after chain only keeps a few retired
+        for _ in 0..2 * MAX_RETIRED_CHAINS {
+            alice.sending.as_mut().unwrap().index = CHAIN_LENGTH;
+            let (announcement, ..) = alice.next_send("alice").unwrap();
+            assert!(bob.install(&announcement.unwrap()));
+        }
+        assert_eq!(bob.retired.len(), MAX_RETIRED_CHAINS);
+        assert!(bob.message_key("alice", &position).is_none());
+    }
+}
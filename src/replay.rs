@@ -0,0 +1,185 @@
+use chrono::{DateTime, Duration, Utc};
+use std::collections::HashMap;
+use std::fmt;
+
+/// Default age after which messages are rejected as stale
+pub const DEFAULT_WINDOW: Duration = Duration::hours(24);
+
+/// Default tolerance for sender clocks running ahead of ours
+pub const DEFAULT_MAX_SKEW: Duration = Duration::minutes(5);
+
+/// Interval in which nonces of all senders are pruned, including those
+/// that went quiet
+const PRUNE_INTERVAL: Duration = Duration::minutes(1);
+
+/// Reason a message was rejected by the [`ReplayGuard`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplayError {
+    /// Timestamp is older than the acceptance window
+    Stale,
+    /// Timestamp is further in the future than the allowed clock skew
+    FromFuture,
+    /// Nonce was already seen from this sender
+    RepeatedNonce,
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReplayError::Stale => write!(f, "message is too old"),
+            ReplayError::FromFuture => write!(f, "message is from the future"),
+            ReplayError::RepeatedNonce => write!(f, "message was already received"),
+        }
+    }
+}
+
+/// Tracks per-sender (timestamp, nonce) pairs to reject replayed messages.
+///
+/// Only nonces inside the acceptance window are remembered: anything older
+/// is rejected as stale before the nonce lookup, so memory stays bounded by
+/// the message rate within the window.
+pub struct ReplayGuard {
+    window: Duration,
+    max_skew: Duration,
+    seen: HashMap<String, HashMap<Vec<u8>, DateTime<Utc>>>,
+    last_pruned: Option<DateTime<Utc>>,
+}
+
+impl ReplayGuard {
+    /// Creates a guard accepting messages up to `window` old and up to
+    /// `max_skew` ahead of the local clock
+    pub fn new(window: Duration, max_skew: Duration) -> Self {
+        Self {
+            window,
+            max_skew,
+            seen: HashMap::new(),
+            last_pruned: None,
+        }
+    }
+
+    /// Checks a message against the guard at the current time and records it
+    /// if accepted
+    pub fn check(
+        &mut self,
+        sender: &str,
+        timestamp: DateTime<Utc>,
+        nonce: &[u8],
+    ) -> Result<(), ReplayError> {
+        self.check_at(sender, timestamp, nonce, Utc::now())
+    }
+
+    /// Checks a message against the guard at time `now` and records it if accepted
+    pub fn check_at(
+        &mut self,
+        sender: &str,
+        timestamp: DateTime<Utc>,
+        nonce: &[u8],
+        now: DateTime<Utc>,
+    ) -> Result<(), ReplayError> {
+        let oldest = now - self.window;
+        if timestamp < oldest {
+            return Err(ReplayError::Stale);
+        }
+        if timestamp > now + self.max_skew {
+            return Err(ReplayError::FromFuture);
+        }
+
+        if self
+            .last_pruned
+            .is_none_or(|pruned| now - pruned >= PRUNE_INTERVAL)
+        {
+            self.prune(oldest);
+            self.last_pruned = Some(now);
+        }
+        let nonces = self.seen.entry(sender.to_string()).or_default();
+        // Forget nonces that have aged out of the window
+        nonces.retain(|_, seen_at| *seen_at >= oldest);
+        if nonces.contains_key(nonce) {
+            return Err(ReplayError::RepeatedNonce);
+        }
+        nonces.insert(nonce.to_vec(), timestamp);
+        Ok(())
+    }
+
+    /// Forgets nonces older than `oldest` for all senders, and senders
+    /// left without any
+    fn prune(&mut self, oldest: DateTime<Utc>) {
+        self.seen.retain(|_, nonces| {
+            nonces.retain(|_, seen_at| *seen_at >= oldest);
+            !nonces.is_empty()
+        });
+    }
+
+    /// Returns the number of remembered nonces across all senders
+    pub fn tracked_count(&self) -> usize {
+        self.seen.values().map(HashMap::len).sum()
+    }
+}
+
+impl Default for ReplayGuard {
+    fn default() -> Self {
+        Self::new(DEFAULT_WINDOW, DEFAULT_MAX_SKEW)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ts(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().to_utc()
+    }
+
+    #[test]
+    fn test_repeated_nonce_rejected_per_sender() {
+        let mut guard = ReplayGuard::default();
+        let now = ts("2025-01-01T12:00:00Z");
+
+        assert_eq!(guard.check_at("alice", now, b"n1", now), Ok(()));
+        assert_eq!(
+            guard.check_at("alice", now, b"n1", now),
+            Err(ReplayError::RepeatedNonce)
+        );
+        // Same nonce from another sender is tracked separately
+        assert_eq!(guard.check_at("bob", now, b"n1", now), Ok(()));
+    }
+
+    #[test]
+    fn test_window_and_skew() {
+        let mut guard = ReplayGuard::new(Duration::minutes(10), Duration::seconds(30));
+        let now = ts("2025-01-01T12:00:00Z");
+
+        assert_eq!(
+            guard.check_at("alice", now - Duration::minutes(11), b"old", now),
+            Err(ReplayError::Stale)
+        );
+        assert_eq!(
+            guard.check_at("alice", now + Duration::seconds(31), b"new", now),
+            Err(ReplayError::FromFuture)
+        );
+        assert_eq!(
+            guard.check_at("alice", now + Duration::seconds(29), b"skewed", now),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_aged_out_nonces_are_forgotten() {
+        let mut guard = ReplayGuard::new(Duration::minutes(10), Duration::seconds(30));
+        let start = ts("2025-01-01T12:00:00Z");
+
+        guard.check_at("alice", start, b"n1", start).unwrap();
+        guard.check_at("bob", start, b"n1", start).unwrap();
+        let later = start + Duration::minutes(20);
+        guard.check_at("alice", later, b"n2", later).unwrap();
+        // Bob went quiet, but his nonces are pruned all the same
+        assert_eq!(guard.tracked_count(), 1);
+        assert_eq!(guard.seen.len(), 1);
+
+        // The replay of n1 is still rejected, now as stale
+        assert_eq!(
+            guard.check_at("alice", start, b"n1", later),
+            Err(ReplayError::Stale)
+        );
+    }
+}
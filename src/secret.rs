@@ -0,0 +1,189 @@
+//! Key material that is wiped from memory once it is no longer needed.
+//!
+//! [`SecretKey`] keeps a 256-bit key in its own heap allocation, so moving it
+//! around does not leave copies behind, and overwrites it with zeros when it
+//! is dropped. It is deliberately neither `Clone` nor `Copy`, and its `Debug`
+//! output does not show the key.
+//!
+//! Keys are derived directly into a [`SecretKey`], and Argon2id wipes its
+//! working memory; scrypt offers no way to do so.
+//!
+//! With the `mlock` feature on Unix the pages holding keys are additionally
+//! locked into RAM, so they are never written to swap. Locking is best
+//! effort: it fails silently once the `RLIMIT_MEMLOCK` limit is reached.
+//! Pages are unlocked again once the last key on them is dropped.
+
+use std::fmt;
+use zeroize::Zeroize;
+
+/// Length of a secret key in bytes
+pub const KEY_LEN: usize = 32;
+
+/// 256-bit key zeroized on drop
+pub struct SecretKey {
+    bytes: Box<[u8; KEY_LEN]>,
+}
+
+impl SecretKey {
+    /// Creates an all-zero key to be filled in place
+    pub(crate) fn zeroed() -> Self {
+        let bytes = Box::new([0u8; KEY_LEN]);
+        lock(&bytes);
+        Self { bytes }
+    }
+
+    /// Moves the key out of `bytes`, leaving zeros behind
+    pub fn take(bytes: &mut [u8; KEY_LEN]) -> Self {
+        let mut key = Self::zeroed();
+        key.bytes.copy_from_slice(bytes);
+        bytes.zeroize();
+        key
+    }
+
+    /// Returns the raw key
+    pub fn as_bytes(&self) -> &[u8; KEY_LEN] {
+        &self.bytes
+    }
+
+    /// Returns the raw key for filling it in place
+    pub(crate) fn as_mut_bytes(&mut self) -> &mut [u8; KEY_LEN] {
+        &mut self.bytes
+    }
+
+    /// Returns a second copy of the key, for the rare places that need to
+    /// hold on to the same key twice
+    pub fn duplicate(&self) -> Self {
+        let mut key = Self::zeroed();
+        key.bytes.copy_from_slice(&self.bytes[..]);
+        key
+    }
+}
+
+impl Drop for SecretKey {
+    fn drop(&mut self) {
+        self.bytes.zeroize();
+        unlock(&self.bytes);
+    }
+}
+
+impl fmt::Debug for SecretKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("SecretKey(..)")
+    }
+}
+
+/// Takes over a key. Arrays are `Copy`, so the caller's copy is left as it
+/// is; use [`SecretKey::take`] to wipe it.
+impl From<[u8; KEY_LEN]> for SecretKey {
+    fn from(mut bytes: [u8; KEY_LEN]) -> Self {
+        Self::take(&mut bytes)
+    }
+}
+
+/// Copies a key out of a slice, failing unless it is exactly [`KEY_LEN`] long
+impl TryFrom<&[u8]> for SecretKey {
+    type Error = std::array::TryFromSliceError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        let bytes: &[u8; KEY_LEN] = bytes.try_into()?;
+        let mut key = Self::zeroed();
+        key.bytes.copy_from_slice(bytes);
+        Ok(key)
+    }
+}
+
+/// Number of live keys on each locked page, as one `munlock` unlocks a page
+/// for all of them
+#[cfg(all(unix, feature = "mlock"))]
+static LOCKED_PAGES: std::sync::Mutex<std::collections::BTreeMap<usize, usize>> =
+    std::sync::Mutex::new(std::collections::BTreeMap::new());
+
+/// Returns the start addresses of the pages `bytes` lies on and the page size
+#[cfg(all(unix, feature = "mlock"))]
+fn pages(bytes: &[u8; KEY_LEN]) -> (impl Iterator<Item = usize>, usize) {
+    // SAFETY: sysconf has no preconditions
+    let page_size = usize::try_from(unsafe { libc::sysconf(libc::_SC_PAGESIZE) })
+        .unwrap_or(4096)
+        .max(1);
+    let start = bytes.as_ptr() as usize / page_size * page_size;
+    let end = bytes.as_ptr() as usize + bytes.len();
+    ((start..end).step_by(page_size), page_size)
+}
+
+#[cfg(all(unix, feature = "mlock"))]
+fn lock(bytes: &[u8; KEY_LEN]) {
+    let mut locked = LOCKED_PAGES.lock().unwrap_or_else(|e| e.into_inner());
+    let (pages, page_size) = pages(bytes);
+    for page in pages {
+        let count = locked.entry(page).or_default();
+        if *count == 0 {
+            // SAFETY: the page holds part of a live allocation
+            unsafe {
+                libc::mlock(page as *const libc::c_void, page_size);
+            }
+        }
+        *count += 1;
+    }
+}
+
+#[cfg(all(unix, feature = "mlock"))]
+fn unlock(bytes: &[u8; KEY_LEN]) {
+    let mut locked = LOCKED_PAGES.lock().unwrap_or_else(|e| e.into_inner());
+    let (pages, page_size) = pages(bytes);
+    for page in pages {
+        let Some(count) = locked.get_mut(&page) else {
+            continue;
+        };
+        *count -= 1;
+        if *count == 0 {
+            locked.remove(&page);
+            // SAFETY: the page holds part of a live allocation
+            unsafe {
+                libc::munlock(page as *const libc::c_void, page_size);
+            }
+        }
+    }
+}
+
+#[cfg(not(all(unix, feature = "mlock")))]
+fn lock(_bytes: &[u8; KEY_LEN]) {}
+
+#[cfg(not(all(unix, feature = "mlock")))]
+fn unlock(_bytes: &[u8; KEY_LEN]) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_secret_key_hides_and_moves_bytes() {
+        let mut bytes = [7u8; KEY_LEN];
+        let key = SecretKey::take(&mut bytes);
+        assert_eq!(bytes, [0u8; KEY_LEN]);
+        assert_eq!(key.as_bytes(), &[7u8; KEY_LEN]);
+        assert_eq!(key.duplicate().as_bytes(), key.as_bytes());
+        assert_eq!(format!("{:?}", key), "SecretKey(..)");
+
+        assert!(SecretKey::try_from(&[1u8; 31][..]).is_err());
+        let key = SecretKey::try_from(&[1u8; KEY_LEN][..]).unwrap();
+        assert_eq!(key.as_bytes(), &[1u8; KEY_LEN]);
+    }
+
+    #[cfg(all(unix, feature = "mlock"))]
+    #[test]
+    fn test_pages_unlocked_with_last_key() {
+        // Aligned beyond any page size, so no other key shares the page
+        #[repr(align(65536))]
+        struct Page([u8; KEY_LEN]);
+        let page = Box::new(Page([0u8; KEY_LEN]));
+        let start = page.0.as_ptr() as usize;
+        let count = || LOCKED_PAGES.lock().unwrap().get(&start).copied();
+
+        lock(&page.0);
+        lock(&page.0);
+        unlock(&page.0);
+        assert_eq!(count(), Some(1));
+        unlock(&page.0);
+        assert_eq!(count(), None);
+    }
+}
@@ -0,0 +1,560 @@
+//! Chunked file transfer over a channel.
+//!
+//! A sender announces a file with a [`FileOffer`] carrying its name, size,
+//! Blake2b hash and a random file key, then sends the file in chunks of
+//! [`CHUNK_SIZE`] bytes. Both travel as channel messages, see
+//! [`crate::envelope::ContentType`]. Chunks are additionally encrypted with
+//! the file key using the XChaCha20-Poly1305 STREAM construction, so they
+//! cannot be reordered, truncated or moved to another file:
+//!
+//! ```text
+//! file id (16) | chunk index (4, big endian) | STREAM ciphertext
+//! ```
+//!
+//! Receivers write chunks into a partial file as they arrive, in any order,
+//! and move it into the download directory once the hash matches. At most
+//! [`MAX_INCOMING`] files are received at once, and partial files that saw no
+//! chunk for [`INCOMING_TIMEOUT`] are deleted.
+
+use base64::{Engine as _, engine::general_purpose::STANDARD};
+use blake2::{Blake2b512, Digest};
+use chacha20poly1305::XChaCha20Poly1305;
+use chacha20poly1305::aead::stream::{NewStream, StreamBE32, StreamPrimitive};
+use chacha20poly1305::{Key, KeyInit};
+use chrono::{DateTime, Duration, SubsecRound, Utc};
+use rand::{RngCore, rngs::OsRng};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// Plaintext size of all chunks but the last
+pub const CHUNK_SIZE: usize = 32 * 1024;
+
+/// Largest file accepted for download
+pub const MAX_FILE_SIZE: u64 = 256 * 1024 * 1024;
+
+/// Largest number of files received at once
+pub const MAX_INCOMING: usize = 8;
+
+/// Time without new chunks after which a download is abandoned
+pub const INCOMING_TIMEOUT: Duration = Duration::minutes(5);
+
+/// Length of a file id
+pub const FILE_ID_LEN: usize = 16;
+
+/// Length of the STREAM nonce, the XChaCha20 nonce minus the STREAM counter
+pub const STREAM_NONCE_LEN: usize = 19;
+
+/// Length of the chunk header preceding the ciphertext
+const CHUNK_HEADER_LEN: usize = FILE_ID_LEN + 4;
+
+type Stream = StreamBE32<XChaCha20Poly1305>;
+
+fn stream(key: &[u8; 32], nonce: &[u8; STREAM_NONCE_LEN]) -> Stream {
+    Stream::from_aead(
+        XChaCha20Poly1305::new(Key::from_slice(key)),
+        nonce.as_slice().into(),
+    )
+}
+
+/// Announcement of a file, sent before its chunks.
+///
+/// Encoded like chat messages, e.g.
+/// `{"type":"file","sender":"alice","timestamp":...,"id":...,"name":"notes.txt","size":1234,...}`.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename = "file")]
+pub struct FileOffer {
+    pub sender: String,
+    pub timestamp: DateTime<Utc>,
+    /// Base64 file id
+    pub id: String,
+    /// File name without directories
+    pub name: String,
+    pub size: u64,
+    pub chunks: u32,
+    /// Base64 Blake2b-512 hash of the file
+    pub hash: String,
+    /// Base64 file key
+    pub key: String,
+    /// Base64 STREAM nonce
+    pub nonce: String,
+}
+
+impl FileOffer {
+    /// Encodes the offer into the payload that gets encrypted
+    pub fn encode(&self) -> String {
+        serde_json::to_string(self).expect("FileOffer serialization cannot fail")
+    }
+
+    /// Decodes a decrypted payload, returning None if it is not an offer
+    pub fn decode(payload: &[u8]) -> Option<Self> {
+        serde_json::from_slice(payload).ok()
+    }
+}
+
+impl fmt::Debug for FileOffer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("FileOffer")
+            .field("sender", &self.sender)
+            .field("timestamp", &self.timestamp)
+            .field("id", &self.id)
+            .field("name", &self.name)
+            .field("size", &self.size)
+            .field("chunks", &self.chunks)
+            .field("hash", &self.hash)
+            .field("key", &"..")
+            .field("nonce", &self.nonce)
+            .finish()
+    }
+}
+
+/// File being sent chunk by chunk
+pub struct OutgoingFile {
+    offer: FileOffer,
+    id: [u8; FILE_ID_LEN],
+    stream: Stream,
+    file: File,
+    index: u32,
+}
+
+impl OutgoingFile {
+    /// Opens the file at `path` to be sent by `sender`, hashing it for the offer
+    pub fn open(path: &Path, sender: &str) -> io::Result<Self> {
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not a file name"))?
+            .to_string();
+        let mut file = File::open(path)?;
+        let size = file.metadata()?.len();
+        if size > MAX_FILE_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is larger than {} bytes", name, MAX_FILE_SIZE),
+            ));
+        }
+        let hash = hash_file(&mut file)?;
+        file.rewind()?;
+
+        let mut id = [0u8; FILE_ID_LEN];
+        let mut key = [0u8; 32];
+        let mut nonce = [0u8; STREAM_NONCE_LEN];
+        OsRng.fill_bytes(&mut id);
+        OsRng.fill_bytes(&mut key);
+        OsRng.fill_bytes(&mut nonce);
+        let chunks = size.div_ceil(CHUNK_SIZE as u64).max(1) as u32;
+        Ok(Self {
+            offer: FileOffer {
+                sender: sender.to_string(),
+                timestamp: Utc::now().trunc_subsecs(0),
+                id: STANDARD.encode(id),
+                name,
+                size,
+                chunks,
+                hash: STANDARD.encode(hash),
+                key: STANDARD.encode(key),
+                nonce: STANDARD.encode(nonce),
+            },
+            id,
+            stream: stream(&key, &nonce),
+            file,
+            index: 0,
+        })
+    }
+
+    /// Returns the offer to send before the chunks
+    pub fn offer(&self) -> &FileOffer {
+        &self.offer
+    }
+
+    /// Reads and encrypts the next chunk, returning None after the last one
+    pub fn next_chunk(&mut self) -> io::Result<Option<Vec<u8>>> {
+        if self.index == self.offer.chunks {
+            return Ok(None);
+        }
+        let last = self.index + 1 == self.offer.chunks;
+        let mut plaintext = Vec::with_capacity(CHUNK_SIZE);
+        (&mut self.file)
+            .take(CHUNK_SIZE as u64)
+            .read_to_end(&mut plaintext)?;
+        let ciphertext = self
+            .stream
+            .encrypt(self.index, last, plaintext.as_slice())
+            .map_err(|_| io::Error::other("chunk encryption failed"))?;
+        let chunk = [&self.id[..], &self.index.to_be_bytes(), &ciphertext].concat();
+        self.index += 1;
+        Ok(Some(chunk))
+    }
+}
+
+/// Outcome of a finished download
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Download {
+    /// File matched its hash and was saved to `path`
+    Saved {
+        sender: String,
+        name: String,
+        path: PathBuf,
+    },
+    /// A chunk failed to decrypt or the file did not match its hash
+    Corrupt { sender: String, name: String },
+}
+
+/// File being received
+struct IncomingFile {
+    offer: FileOffer,
+    hash: Vec<u8>,
+    stream: Stream,
+    part: PathBuf,
+    file: File,
+    received: Vec<bool>,
+    remaining: u32,
+    last_chunk: DateTime<Utc>,
+}
+
+impl IncomingFile {
+    /// Plaintext size of chunk `index`
+    fn chunk_len(&self, index: u32) -> usize {
+        if index + 1 < self.offer.chunks {
+            CHUNK_SIZE
+        } else {
+            (self.offer.size - u64::from(index) * CHUNK_SIZE as u64) as usize
+        }
+    }
+}
+
+/// Files being received into a download directory
+pub struct Downloads {
+    dir: PathBuf,
+    incoming: HashMap<[u8; FILE_ID_LEN], IncomingFile>,
+}
+
+impl Downloads {
+    /// Receives files into `dir`, created on the first offer
+    pub fn new(dir: PathBuf) -> Self {
+        Self {
+            dir,
+            incoming: HashMap::new(),
+        }
+    }
+
+    /// Default download directory, the user's download directory if any
+    pub fn default_dir() -> PathBuf {
+        dirs::download_dir().unwrap_or_else(|| PathBuf::from("."))
+    }
+
+    /// Starts receiving the file described by `offer` from `sender`, the
+    /// sender authenticated by the envelope. Returns false if the offer is
+    /// malformed, too large, claims another sender, was already seen or
+    /// [`MAX_INCOMING`] files are being received.
+    pub fn offer(&mut self, sender: &str, offer: FileOffer) -> io::Result<bool> {
+        self.expire_at(Utc::now())?;
+        if self.incoming.len() >= MAX_INCOMING {
+            return Ok(false);
+        }
+        let decode = |value: &str| STANDARD.decode(value).ok();
+        let (Some(id), Some(key), Some(nonce), Some(hash)) = (
+            decode(&offer.id).and_then(|id| <[u8; FILE_ID_LEN]>::try_from(id).ok()),
+            decode(&offer.key).and_then(|key| <[u8; 32]>::try_from(key).ok()),
+            decode(&offer.nonce).and_then(|nonce| <[u8; STREAM_NONCE_LEN]>::try_from(nonce).ok()),
+            decode(&offer.hash),
+        ) else {
+            return Ok(false);
+        };
+        let expected_chunks = offer.size.div_ceil(CHUNK_SIZE as u64).max(1);
+        if offer.sender != sender
+            || offer.size > MAX_FILE_SIZE
+            || u64::from(offer.chunks) != expected_chunks
+            || file_name(&offer.name).is_none()
+            || self.incoming.contains_key(&id)
+        {
+            return Ok(false);
+        }
+
+        fs::create_dir_all(&self.dir)?;
+        let part = self.dir.join(format!(".{}.part", hex(&id)));
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&part)?;
+        file.set_len(offer.size)?;
+        self.incoming.insert(
+            id,
+            IncomingFile {
+                hash,
+                stream: stream(&key, &nonce),
+                part,
+                file,
+                received: vec![false; offer.chunks as usize],
+                remaining: offer.chunks,
+                last_chunk: Utc::now(),
+                offer,
+            },
+        );
+        Ok(true)
+    }
+
+    /// Writes a chunk received from `sender`, the sender authenticated by the
+    /// envelope. Returns the outcome once the last missing chunk of a file
+    /// arrived or a chunk turned out corrupt. Chunks of unknown files, from
+    /// anyone but the offering sender, or already written are ignored.
+    pub fn chunk(&mut self, sender: &str, chunk: &[u8]) -> io::Result<Option<Download>> {
+        self.expire_at(Utc::now())?;
+        if chunk.len() < CHUNK_HEADER_LEN {
+            return Ok(None);
+        }
+        let (id, rest) = chunk.split_at(FILE_ID_LEN);
+        let (index, ciphertext) = rest.split_at(4);
+        let id: [u8; FILE_ID_LEN] = id.try_into().expect("split at FILE_ID_LEN");
+        let index = u32::from_be_bytes(index.try_into().expect("split at 4"));
+        let Some(incoming) = self.incoming.get_mut(&id) else {
+            return Ok(None);
+        };
+        if incoming.offer.sender != sender
+            || index >= incoming.offer.chunks
+            || incoming.received[index as usize]
+        {
+            return Ok(None);
+        }
+
+        let last = index + 1 == incoming.offer.chunks;
+        let plaintext = match incoming.stream.decrypt(index, last, ciphertext) {
+            Ok(plaintext) if plaintext.len() == incoming.chunk_len(index) => plaintext,
+            _ => return self.finish(id, false).map(Some),
+        };
+        incoming
+            .file
+            .seek(SeekFrom::Start(u64::from(index) * CHUNK_SIZE as u64))?;
+        incoming.file.write_all(&plaintext)?;
+        incoming.received[index as usize] = true;
+        incoming.remaining -= 1;
+        incoming.last_chunk = Utc::now();
+        if incoming.remaining > 0 {
+            return Ok(None);
+        }
+
+        incoming.file.rewind()?;
+        let valid = hash_file(&mut incoming.file)? == incoming.hash;
+        self.finish(id, valid).map(Some)
+    }
+
+    /// Abandons downloads that saw no chunk for [`INCOMING_TIMEOUT`] before
+    /// `now`, deleting their partial files. Returns the first error deleting
+    /// one once all are abandoned.
+    fn expire_at(&mut self, now: DateTime<Utc>) -> io::Result<()> {
+        let stale: Vec<_> = self
+            .incoming
+            .iter()
+            .filter(|(_, incoming)| now - incoming.last_chunk >= INCOMING_TIMEOUT)
+            .map(|(id, _)| *id)
+            .collect();
+        let mut result = Ok(());
+        for id in stale {
+            if let Some(incoming) = self.incoming.remove(&id)
+                && let Err(e) = fs::remove_file(&incoming.part)
+                && e.kind() != io::ErrorKind::NotFound
+                && result.is_ok()
+            {
+                result = Err(e);
+            }
+        }
+        result
+    }
+
+    /// Moves a finished file into place, or deletes it if it is not valid
+    fn finish(&mut self, id: [u8; FILE_ID_LEN], valid: bool) -> io::Result<Download> {
+        let incoming = self
+            .incoming
+            .remove(&id)
+            .expect("finished file is incoming");
+        let IncomingFile { offer, part, .. } = incoming;
+        if !valid {
+            fs::remove_file(&part)?;
+            return Ok(Download::Corrupt {
+                sender: offer.sender,
+                name: offer.name,
+            });
+        }
+        let path = unique_path(&self.dir, file_name(&offer.name).expect("checked on offer"));
+        fs::rename(&part, &path)?;
+        Ok(Download::Saved {
+            sender: offer.sender,
+            name: offer.name,
+            path,
+        })
+    }
+}
+
+impl Drop for Downloads {
+    fn drop(&mut self) {
+        // Unfinished downloads cannot be resumed
+        for incoming in self.incoming.values() {
+            let _ = fs::remove_file(&incoming.part);
+        }
+    }
+}
+
+/// Returns the Blake2b-512 hash of the rest of `file`
+fn hash_file(file: &mut File) -> io::Result<Vec<u8>> {
+    let mut hasher = Blake2b512::new();
+    io::copy(file, &mut hasher)?;
+    Ok(hasher.finalize().to_vec())
+}
+
+/// Returns `name` if it is a plain file name that is safe to create
+fn file_name(name: &str) -> Option<&str> {
+    let plain = Path::new(name).file_name()? == name;
+    (plain && !name.starts_with('.')).then_some(name)
+}
+
+/// Returns a path for `name` in `dir` that does not exist yet
+fn unique_path(dir: &Path, name: &str) -> PathBuf {
+    let path = Path::new(name);
+    let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or(name);
+    let extension = path.extension().and_then(|s| s.to_str());
+    let mut candidate = dir.join(name);
+    let mut n = 1;
+    while candidate.exists() {
+        let numbered = match extension {
+            Some(extension) => format!("{} ({}).{}", stem, n, extension),
+            None => format!("{} ({})", stem, n),
+        };
+        candidate = dir.join(numbered);
+        n += 1;
+    }
+    candidate
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("mles-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_transfer_out_of_order_with_duplicates() {
+        let dir = temp_dir("transfer");
+        let source = dir.join("report.bin");
+        let content: Vec<u8> = (0..CHUNK_SIZE * 2 + 100).map(|i| i as u8).collect();
+        fs::write(&source, &content).unwrap();
+
+        let mut outgoing = OutgoingFile::open(&source, "alice").unwrap();
+        let offer = FileOffer::decode(outgoing.offer().encode().as_bytes()).unwrap();
+        assert_eq!(offer.chunks, 3);
+        let mut chunks = Vec::new();
+        while let Some(chunk) = outgoing.next_chunk().unwrap() {
+            chunks.push(chunk);
+        }
+
+        let mut downloads = Downloads::new(dir.join("downloads"));
+        assert!(!downloads.offer("mallory", offer.clone()).unwrap());
+        assert!(downloads.offer("alice", offer.clone()).unwrap());
+        // Chunks injected by anyone but the offering sender are ignored
+        assert_eq!(downloads.chunk("mallory", &chunks[2]).unwrap(), None);
+        assert!(!downloads.incoming.values().next().unwrap().received[2]);
+        assert_eq!(downloads.chunk("alice", &chunks[2]).unwrap(), None);
+        assert_eq!(downloads.chunk("alice", &chunks[2]).unwrap(), None);
+        assert_eq!(downloads.chunk("alice", &chunks[0]).unwrap(), None);
+        let Some(Download::Saved { path, .. }) = downloads.chunk("alice", &chunks[1]).unwrap()
+        else {
+            panic!("download not saved");
+        };
+        assert_eq!(path, dir.join("downloads").join("report.bin"));
+        assert_eq!(fs::read(&path).unwrap(), content);
+
+        // The same name again gets a numbered copy
+        let mut again = OutgoingFile::open(&source, "alice").unwrap();
+        assert!(downloads.offer("alice", again.offer().clone()).unwrap());
+        let mut outcome = None;
+        while let Some(chunk) = again.next_chunk().unwrap() {
+            outcome = downloads.chunk("alice", &chunk).unwrap();
+        }
+        assert!(matches!(outcome, Some(Download::Saved { path, .. })
+            if path.ends_with("report (1).bin")));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_truncated_and_tampered_transfers_rejected() {
+        let dir = temp_dir("transfer-bad");
+        let source = dir.join("notes.txt");
+        fs::write(&source, vec![b'x'; CHUNK_SIZE + 1]).unwrap();
+        let mut downloads = Downloads::new(dir.join("downloads"));
+
+        // A chunk moved to the last position does not decrypt
+        let mut outgoing = OutgoingFile::open(&source, "alice").unwrap();
+        let mut offer = outgoing.offer().clone();
+        let first = outgoing.next_chunk().unwrap().unwrap();
+        offer.size = CHUNK_SIZE as u64;
+        offer.chunks = 1;
+        assert!(downloads.offer("alice", offer).unwrap());
+        assert!(matches!(
+            downloads.chunk("alice", &first).unwrap(),
+            Some(Download::Corrupt { .. })
+        ));
+
+        // Names with directories are refused
+        let mut offer = outgoing.offer().clone();
+        offer.name = "../notes.txt".to_string();
+        assert!(!downloads.offer("alice", offer).unwrap());
+        assert_eq!(fs::read_dir(dir.join("downloads")).unwrap().count(), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_incoming_capped_and_expired() {
+        let dir = temp_dir("transfer-stale");
+        let source = dir.join("data.bin");
+        fs::write(&source, [1u8; 10]).unwrap();
+        let downloads_dir = dir.join("downloads");
+        let mut downloads = Downloads::new(downloads_dir.clone());
+
+        for _ in 0..MAX_INCOMING {
+            let offer = OutgoingFile::open(&source, "alice")
+                .unwrap()
+                .offer()
+                .clone();
+            assert!(downloads.offer("alice", offer).unwrap());
+        }
+        let offer = OutgoingFile::open(&source, "alice")
+            .unwrap()
+            .offer()
+            .clone();
+        assert!(!downloads.offer("alice", offer.clone()).unwrap());
+        assert!(!format!("{:?}", offer).contains(&offer.key));
+
+        // Stalled downloads are abandoned on the next chunk, even if a
+        // partial file is already gone, and leave no partial files
+        for incoming in downloads.incoming.values_mut() {
+            incoming.last_chunk -= INCOMING_TIMEOUT;
+        }
+        let part = downloads.incoming.values().next().unwrap().part.clone();
+        fs::remove_file(part).unwrap();
+        assert!(
+            downloads
+                .chunk("alice", &[0; CHUNK_HEADER_LEN])
+                .unwrap()
+                .is_none()
+        );
+        assert!(downloads.incoming.is_empty());
+        assert!(downloads.offer("alice", offer).unwrap());
+        assert_eq!(fs::read_dir(&downloads_dir).unwrap().count(), 1);
+        drop(downloads);
+        assert_eq!(fs::read_dir(&downloads_dir).unwrap().count(), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,229 @@
+use crate::error::{Error, Result};
+use crate::handshake;
+use futures_util::{Sink, Stream, ready};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio_tungstenite::tungstenite::Bytes;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::{
+    MaybeTlsStream, WebSocketStream, connect_async,
+    tungstenite::{self, Message},
+};
+
+/// Frame exchanged with a Mles server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Frame {
+    /// Text frame, used for the channel join and for key requests and grants
+    Text(String),
+    /// Binary frame carrying channel data
+    Binary(Bytes),
+}
+
+/// Bidirectional carrier for Mles frames.
+///
+/// Frames are received through [`Stream`] and sent or the transport closed
+/// through [`Sink`], so `StreamExt::next`, `SinkExt::send`, `SinkExt::close`
+/// and `StreamExt::split` all work on any transport. The stream ends when the
+/// peer closes the connection.
+pub trait Transport:
+    Stream<Item = Result<Frame>> + Sink<Frame, Error = Error> + Send + Unpin + 'static
+{
+}
+
+impl<T> Transport for T where
+    T: Stream<Item = Result<Frame>> + Sink<Frame, Error = Error> + Send + Unpin + 'static
+{
+}
+
+/// WebSocket stream type used for Mles server connections
+pub type WsStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// WebSocket transport, the default carrier for Mles
+pub struct WsTransport {
+    inner: WsStream,
+    close_sent: bool,
+}
+
+impl WsTransport {
+    /// Opens a WebSocket connection speaking the Mles subprotocol to `server`
+    pub async fn connect(server: &str) -> Result<Self> {
+        let request = handshake::client_request(server)?;
+        let (inner, _) = connect_async(request).await.map_err(|e| match e {
+            tungstenite::Error::Url(_) => Error::url(e),
+            e => Error::connect(e),
+        })?;
+        Ok(Self {
+            inner,
+            close_sent: false,
+        })
+    }
+}
+
+impl Stream for WsTransport {
+    type Item = Result<Frame>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            match Pin::new(&mut self.inner).poll_next(cx) {
+                Poll::Ready(Some(Ok(Message::Binary(data)))) => {
+                    return Poll::Ready(Some(Ok(Frame::Binary(data))));
+                }
+                Poll::Ready(Some(Ok(Message::Text(text)))) => {
+                    return Poll::Ready(Some(Ok(Frame::Text(text.to_string()))));
+                }
+                Poll::Ready(Some(Ok(Message::Close(_)))) | Poll::Ready(None) => {
+                    return Poll::Ready(None);
+                }
+                // Ping, pong and raw frames are handled by tungstenite
+                Poll::Ready(Some(Ok(_))) => {}
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Some(Err(Error::protocol(e)))),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl Sink<Frame> for WsTransport {
+    type Error = Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.inner)
+            .poll_ready(cx)
+            .map_err(Error::protocol)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, frame: Frame) -> Result<()> {
+        let message = match frame {
+            Frame::Text(text) => Message::Text(text.into()),
+            Frame::Binary(data) => Message::Binary(data),
+        };
+        Pin::new(&mut self.inner)
+            .start_send(message)
+            .map_err(Error::protocol)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.inner)
+            .poll_flush(cx)
+            .map_err(Error::protocol)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        // Send a normal close frame once before finishing the close handshake
+        if !self.close_sent {
+            ready!(Pin::new(&mut self.inner).poll_ready(cx)).map_err(Error::protocol)?;
+            Pin::new(&mut self.inner)
+                .start_send(Message::Close(Some(CloseFrame {
+                    code: CloseCode::Normal,
+                    reason: "Client shutdown".into(),
+                })))
+                .map_err(Error::protocol)?;
+            self.close_sent = true;
+        }
+        Pin::new(&mut self.inner)
+            .poll_close(cx)
+            .map_err(Error::protocol)
+    }
+}
+
+/// In-memory transport, one end of a pair created with [`MemoryTransport::pair`].
+///
+/// Frames sent on one end are received on the other. Useful for testing
+/// forwarding and receive logic without a network.
+pub struct MemoryTransport {
+    tx: Option<UnboundedSender<Frame>>,
+    rx: UnboundedReceiver<Frame>,
+}
+
+impl MemoryTransport {
+    /// Creates two connected transports
+    pub fn pair() -> (Self, Self) {
+        let (tx1, rx1) = mpsc::unbounded_channel();
+        let (tx2, rx2) = mpsc::unbounded_channel();
+        (
+            Self {
+                tx: Some(tx1),
+                rx: rx2,
+            },
+            Self {
+                tx: Some(tx2),
+                rx: rx1,
+            },
+        )
+    }
+}
+
+impl Stream for MemoryTransport {
+    type Item = Result<Frame>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.rx.poll_recv(cx).map(|frame| frame.map(Ok))
+    }
+}
+
+impl Sink<Frame> for MemoryTransport {
+    type Error = Error;
+
+    fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, frame: Frame) -> Result<()> {
+        let sent = match &self.tx {
+            Some(tx) => tx.send(frame).is_ok(),
+            None => false,
+        };
+        if !sent {
+            self.tx = None;
+            return Err(Error::protocol(
+                tokio_tungstenite::tungstenite::Error::AlreadyClosed,
+            ));
+        }
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<()>> {
+        // Dropping the sender ends the peer's stream
+        self.tx = None;
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::{SinkExt, StreamExt};
+
+    #[tokio::test]
+    async fn test_memory_pair_roundtrip() {
+        let (mut a, mut b) = MemoryTransport::pair();
+        a.send(Frame::Text("hello".to_string())).await.unwrap();
+        a.send(Frame::Binary(Bytes::from_static(b"\x00\x01")))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            b.next().await.unwrap().unwrap(),
+            Frame::Text("hello".to_string())
+        );
+        assert_eq!(
+            b.next().await.unwrap().unwrap(),
+            Frame::Binary(Bytes::from_static(b"\x00\x01"))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_memory_close_ends_peer_stream() {
+        let (mut a, mut b) = MemoryTransport::pair();
+        a.close().await.unwrap();
+        assert!(b.next().await.is_none());
+        assert!(a.send(Frame::Text("late".to_string())).await.is_err());
+    }
+}
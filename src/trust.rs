@@ -0,0 +1,194 @@
+//! Trust-on-first-use store of the identity keys seen for each user.
+//!
+//! The first key seen for a uid on a channel is pinned by its fingerprint.
+//! A later key with a different fingerprint is reported as
+//! [`TrustStatus::Changed`] for as long as the user has not accepted it with
+//! [`TrustStore::accept`]; until then the original pin stays in place.
+
+use crate::fsutil;
+use crate::identity::{PublicKey, fingerprint};
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Outcome of checking a key against the store
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TrustStatus {
+    /// First key seen for this uid, now pinned
+    New,
+    /// Key matches the pinned fingerprint
+    Known,
+    /// Key differs from the pinned fingerprint, which stays pinned
+    Changed { previous: String },
+}
+
+/// Per-channel mapping of uid to pinned key fingerprint
+#[derive(Debug, Default)]
+pub struct TrustStore {
+    path: Option<PathBuf>,
+    channels: HashMap<String, HashMap<String, String>>,
+    /// Latest unaccepted fingerprint seen per (channel, uid), not persisted
+    changed: HashMap<(String, String), String>,
+}
+
+impl TrustStore {
+    /// Default location of the store in the user's config directory
+    pub fn default_path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("mles-client").join("known_identities.json"))
+    }
+
+    /// Creates an empty store that is not persisted
+    pub fn in_memory() -> Self {
+        Self::default()
+    }
+
+    /// Loads the store at `path`, starting empty if the file does not exist.
+    /// Changes are saved back to `path`. A corrupted file is an error rather
+    /// than silently dropping the pins it holds.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let channels = match fs::read(path) {
+            Ok(bytes) => serde_json::from_slice(&bytes).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "corrupted trust store {}: {}; move it aside to start over",
+                        path.display(),
+                        e
+                    ),
+                )
+            })?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Self {
+            path: Some(path.to_path_buf()),
+            channels,
+            changed: HashMap::new(),
+        })
+    }
+
+    /// Writes the store to its file, if it has one, replacing it atomically
+    pub fn save(&self) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fsutil::write_private(path, &serde_json::to_vec_pretty(&self.channels)?)
+    }
+
+    /// Checks `public_key` against the fingerprint pinned for `uid` on
+    /// `channel`, pinning it if it is new. A changed key is remembered for
+    /// [`TrustStore::accept`].
+    pub fn check(&mut self, channel: &str, uid: &str, public_key: &PublicKey) -> TrustStatus {
+        let seen = fingerprint(public_key);
+        let pinned = self
+            .channels
+            .entry(channel.to_string())
+            .or_default()
+            .entry(uid.to_string());
+        match pinned {
+            Entry::Vacant(entry) => {
+                entry.insert(seen);
+                TrustStatus::New
+            }
+            Entry::Occupied(entry) if *entry.get() == seen => TrustStatus::Known,
+            Entry::Occupied(entry) => {
+                let previous = entry.get().clone();
+                self.changed
+                    .insert((channel.to_string(), uid.to_string()), seen);
+                TrustStatus::Changed { previous }
+            }
+        }
+    }
+
+    /// Pins the changed key last seen for `uid` on `channel` in place of the
+    /// previous one, returning its fingerprint, or None if there is none
+    pub fn accept(&mut self, channel: &str, uid: &str) -> Option<String> {
+        let seen = self
+            .changed
+            .remove(&(channel.to_string(), uid.to_string()))?;
+        self.channels
+            .entry(channel.to_string())
+            .or_default()
+            .insert(uid.to_string(), seen.clone());
+        Some(seen)
+    }
+
+    /// Returns the changed fingerprint last seen for `uid` on `channel`
+    /// that has not been accepted
+    pub fn changed(&self, channel: &str, uid: &str) -> Option<&str> {
+        self.changed
+            .get(&(channel.to_string(), uid.to_string()))
+            .map(String::as_str)
+    }
+
+    /// Returns the fingerprint pinned for `uid` on `channel`
+    pub fn fingerprint(&self, channel: &str, uid: &str) -> Option<&str> {
+        self.channels.get(channel)?.get(uid).map(String::as_str)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_first_use_then_change() {
+        let mut store = TrustStore::in_memory();
+        let (first, second) = ([1u8; 32], [2u8; 32]);
+
+        assert_eq!(store.check("ops", "alice", &first), TrustStatus::New);
+        assert_eq!(store.check("ops", "alice", &first), TrustStatus::Known);
+        // Pins are per channel
+        assert_eq!(store.check("dev", "alice", &second), TrustStatus::New);
+
+        // A changed key is reported until it is accepted, and the original
+        // pin stays in place
+        for _ in 0..2 {
+            assert_eq!(
+                store.check("ops", "alice", &second),
+                TrustStatus::Changed {
+                    previous: fingerprint(&first)
+                }
+            );
+        }
+        assert_eq!(
+            store.fingerprint("ops", "alice"),
+            Some(fingerprint(&first).as_str())
+        );
+        assert_eq!(
+            store.changed("ops", "alice"),
+            Some(fingerprint(&second).as_str())
+        );
+        assert_eq!(store.check("ops", "alice", &first), TrustStatus::Known);
+
+        assert_eq!(store.accept("ops", "bob"), None);
+        assert_eq!(store.accept("ops", "alice"), Some(fingerprint(&second)));
+        assert_eq!(store.check("ops", "alice", &second), TrustStatus::Known);
+        assert_eq!(store.changed("ops", "alice"), None);
+    }
+
+    #[test]
+    fn test_persisted_across_loads() {
+        let path =
+            std::env::temp_dir().join(format!("mles-known-identities-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let mut store = TrustStore::load(&path).unwrap();
+        store.check("ops", "alice", &[1u8; 32]);
+        store.save().unwrap();
+
+        let mut store = TrustStore::load(&path).unwrap();
+        assert_eq!(store.check("ops", "alice", &[1u8; 32]), TrustStatus::Known);
+
+        // A truncated file is reported instead of losing the pins
+        fs::write(&path, b"{\"ops\":").unwrap();
+        let error = TrustStore::load(&path).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        fs::remove_file(&path).unwrap();
+    }
+}